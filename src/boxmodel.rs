@@ -14,10 +14,12 @@ pub struct Vertex {
     pub normal: [f32; 3],
     /// Face index between 0 and 5 this vertex belongs to.
     pub face: f32,
+    /// Mask of the face within the face mask of an instance.
+    pub face_bit: f32,
     /// Texture coordinate.
     pub tex_coord: [f32; 2],
 }
-implement_vertex!(Vertex, position, normal, face, tex_coord);
+implement_vertex!(Vertex, position, normal, face, face_bit, tex_coord);
 /// Type of the vertex buffer.
 pub type VertexBuffer = glium::VertexBuffer<Vertex>;
 
@@ -28,11 +30,25 @@ pub struct Instance {
     pub box_pos: [f32; 3],
     /// The type of the box.
     pub box_type: f32,
+    /// The side length of the box, which is larger than 1 for coarse boxes.
+    pub box_size: f32,
+    /// Mask of the faces, which are drawn (see boxtree::ALL_FACES_MASK).
+    pub face_mask: f32,
 }
-implement_vertex!(Instance, box_pos, box_type);
+implement_vertex!(Instance, box_pos, box_type, box_size, face_mask);
 /// Type of the instance buffer.
 pub type InstanceBuffer = glium::VertexBuffer<Instance>;
 
+/// Masks of the faces of the model within a face mask of the tree.
+const FACE_MASKS: [u8; 6] = [
+    boxtree::FACE_NEG_Z_MASK,
+    boxtree::FACE_POS_X_MASK,
+    boxtree::FACE_POS_Z_MASK,
+    boxtree::FACE_NEG_X_MASK,
+    boxtree::FACE_POS_Y_MASK,
+    boxtree::FACE_NEG_Y_MASK,
+];
+
 /// Texture which stores indices of a tile for each box type and face.
 pub type BoxTypeFaceTileMapTex = glium::texture::Texture1d;
 pub fn box_type_face_tile_map_tex_from_array<F: glium::backend::Facade>(facade: &F, box_type_face_tile_map: &[u16]) -> BoxTypeFaceTileMapTex {
@@ -91,10 +107,13 @@ impl Model {
 
                 attribute vec3 box_pos;
                 attribute float box_type;
+                attribute float box_size;
+                attribute float face_mask;
 
                 attribute vec3 position;
                 attribute vec3 normal;
                 attribute float face;
+                attribute float face_bit;
                 attribute vec2 tex_coord;
 
                 //varying vec3 v_normal;
@@ -113,7 +132,11 @@ impl Model {
                     v_tex_coord = vec2(tex_coord/*, value.r * 65536*/);
                     v_color = vec3(value.r, 0.0, 0.0);
                     //v_normal = transpose(inverse(mat3(matrix))) * normal;
-                    gl_Position = matrix * vec4(position + box_pos, 1.0);
+                    gl_Position = matrix * vec4(position * box_size + box_pos, 1.0);
+                    // Hidden faces collapse into a single point outside of the view.
+                    if (mod(floor(face_mask / face_bit), 2.0) < 0.5) {
+                        gl_Position = vec4(0.0, 0.0, 2.0, 1.0);
+                    }
                     v_position = gl_Position.xyz / gl_Position.w;
                 }
             "#;
@@ -166,6 +189,7 @@ impl Model {
                     position: pos,
                     normal: pos,
                     face: 0 as f32,
+                    face_bit: 0 as f32,
                     tex_coord: [0.0, 0.0],
                 }
             }).collect::<Vec<Vertex>>();
@@ -184,6 +208,7 @@ impl Model {
                 }
                 for v in 0..6 {
                     vertices[f*6 + v].face = f as f32;
+                    vertices[f*6 + v].face_bit = FACE_MASKS[f] as f32;
                     vertices[f*6 + v].tex_coord = [tex_coords[v].0 as f32, tex_coords[v].1 as f32];
                 }
             }
@@ -2,7 +2,18 @@
 use nalgebra as na;
 use nalgebra::Norm;
 use std::ops::{Deref, DerefMut};
-use std::mem::transmute;
+use std::mem::{transmute, size_of};
+use std::error::Error;
+use std::fmt;
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::panic;
 
 /// Maximal depth of the tree.
 /// The maximal dimension of the world is (CHUNK_SIDE_LEN^MAX_DEPTH)^3=(16^8)^3=(2^32)^3 boxes.
@@ -32,6 +43,10 @@ pub const NODE_BIT_MASK: u16 = 1 << NODE_BIT;
 pub const NODE_INDEX_BITS: u8 = 15;
 /// Mask of bits in a child value, which specify the index to an other node.
 pub const NODE_INDEX_BIT_MASK: u16 = (1 << NODE_INDEX_BITS) - 1;
+/// Index to an other node within a child value, which marks the node as far node. The index of a
+/// far node doesn't fit into NODE_INDEX_BITS, so it is looked up by the index of the parent node
+/// and the index of the child within the chunk of the parent instead.
+pub const FAR_NODE_INDEX: u16 = NODE_INDEX_BIT_MASK;
 
 /// Index of a bit in a nodes child value, which specifies whether the child is part of the surface
 /// (not surrounded by solid boxes). Only used if the child is a leaf.
@@ -52,21 +67,40 @@ pub const BOX_SPEC_BITS: u8 = 13;
 /// Mask of bits in a child value, which specify the box.
 pub const BOX_SPEC_BIT_MASK: u16 = (1 << BOX_SPEC_BITS) - 1;
 
-/*
-/// Index of a bit in a nodes child value which specifies whether the node is compressed or static.
-pub const COMPRESSED_BIT: u8 = 12;
-/// Mask of a bit in a nodes child value which specifies whether the node is compressed or static.
-pub const COMPRESSED_BIT_MASK: u16 = 1 << COMPRESSED_BIT;
-*/
+/// Child value of the marker of a sub-cube, whose nodes have been moved out of the tree. The
+/// surface bit is never set for void boxes, so the value is not used by any other leaf.
+pub const PAGED_VALUE: u16 = SURFACE_BIT_MASK;
+
+/// Mask of the face of a box in negative x direction within a face mask. The face in negative
+/// direction of an axis is followed by the one in positive direction and the axes are ordered x,
+/// y, z.
+pub const FACE_NEG_X_MASK: u8 = 1 << 0;
+/// Mask of the face of a box in positive x direction within a face mask.
+pub const FACE_POS_X_MASK: u8 = 1 << 1;
+/// Mask of the face of a box in negative y direction within a face mask.
+pub const FACE_NEG_Y_MASK: u8 = 1 << 2;
+/// Mask of the face of a box in positive y direction within a face mask.
+pub const FACE_POS_Y_MASK: u8 = 1 << 3;
+/// Mask of the face of a box in negative z direction within a face mask.
+pub const FACE_NEG_Z_MASK: u8 = 1 << 4;
+/// Mask of the face of a box in positive z direction within a face mask.
+pub const FACE_POS_Z_MASK: u8 = 1 << 5;
+/// Mask of all faces of a box within a face mask.
+pub const ALL_FACES_MASK: u8 = (1 << 6) - 1;
 
 /// Child of node.
 #[derive(Copy, Clone)]
+#[repr(transparent)]
 pub struct Child {
-    pub value: u16,
+    value_: u16,
 }
 impl Child {
     pub fn new(value: u16) -> Self {
-        Child { value: value }
+        Child { value_: value }
+    }
+    /// Raw bits of the child.
+    pub fn value(&self) -> u16 {
+        self.value_
     }
     pub fn node_from_index(index: u16) -> Self {
         Child::new(
@@ -90,100 +124,136 @@ impl Child {
     pub fn void() -> Self {
         Child::leaf_from_surface_ext_spec(false, 0)
     }
+    /// Marker of a sub-cube, whose nodes have been moved out of the tree, e.g. to a file.
+    pub fn paged() -> Self {
+        Child::new(PAGED_VALUE)
+    }
     pub fn is_void(&self) -> bool {
-        self.value == 0
+        self.value_ == 0
     }
     pub fn is_node(&self) -> bool {
-        (self.value & NODE_BIT_MASK) != 0
+        (self.value_ & NODE_BIT_MASK) != 0
     }
     pub fn set_node(&mut self, node: bool) {
         if node {
-            self.value |= NODE_BIT_MASK;
+            self.value_ |= NODE_BIT_MASK;
         } else {
-            self.value &= !NODE_BIT_MASK;
+            self.value_ &= !NODE_BIT_MASK;
         }
     }
     pub fn is_surface(&self) -> bool {
-        (self.value & SURFACE_BIT_MASK) != 0
+        (self.value_ & SURFACE_BIT_MASK) != 0
     }
     pub fn set_surface(&mut self, surface: bool) {
         if surface {
-            self.value |= SURFACE_BIT_MASK;
+            self.value_ |= SURFACE_BIT_MASK;
         } else {
-            self.value &= !SURFACE_BIT_MASK;
+            self.value_ &= !SURFACE_BIT_MASK;
         }
     }
     pub fn is_solid(&self) -> bool {
-        (self.value & SOLID_BIT_MASK) != 0
+        (self.value_ & SOLID_BIT_MASK) != 0
     }
     pub fn set_solid(&mut self, solid: bool) {
         if solid {
-            self.value |= SOLID_BIT_MASK;
+            self.value_ |= SOLID_BIT_MASK;
         } else {
-            self.value &= !SOLID_BIT_MASK;
+            self.value_ &= !SOLID_BIT_MASK;
         }
     }
     pub fn box_spec(&self) -> u16 {
-        self.value & BOX_SPEC_BIT_MASK
+        self.value_ & BOX_SPEC_BIT_MASK
     }
-    pub fn set_box_spec_unmasked(&mut self, box_spec: u16) {
-        self.value = (self.value & !BOX_SPEC_BIT_MASK) | box_spec;
+    pub(crate) fn set_box_spec_unmasked(&mut self, box_spec: u16) {
+        self.value_ = (self.value_ & !BOX_SPEC_BIT_MASK) | box_spec;
     }
     pub fn set_box_spec(&mut self, box_spec: u16) {
-        unsafe { self.set_box_spec_unmasked(box_spec & BOX_SPEC_BIT_MASK); }
+        self.set_box_spec_unmasked(box_spec & BOX_SPEC_BIT_MASK);
     }
     pub fn ext_spec(&self) -> u16 {
-        self.value & (BOX_SPEC_BIT_MASK | SOLID_BIT_MASK)
+        self.value_ & (BOX_SPEC_BIT_MASK | SOLID_BIT_MASK)
     }
-    pub fn set_ext_spec_unmasked(&mut self, ext_spec: u16) {
-        self.value = (self.value & !(BOX_SPEC_BIT_MASK | SOLID_BIT_MASK)) | ext_spec;
+    pub(crate) fn set_ext_spec_unmasked(&mut self, ext_spec: u16) {
+        self.value_ = (self.value_ & !(BOX_SPEC_BIT_MASK | SOLID_BIT_MASK)) | ext_spec;
     }
     pub fn set_ext_spec(&mut self, ext_spec: u16) {
-        unsafe { self.set_ext_spec_unmasked(ext_spec & (BOX_SPEC_BIT_MASK | SOLID_BIT_MASK)); }
+        self.set_ext_spec_unmasked(ext_spec & (BOX_SPEC_BIT_MASK | SOLID_BIT_MASK));
+    }
+    /// Whether a leaf above depth 0 is the marker of a sub-cube, whose nodes have been moved out of
+    /// the tree. Boxes at depth 0 are never paged.
+    pub fn is_paged(&self) -> bool {
+        self.value_ == PAGED_VALUE
+    }
+    /// Whether the child hides the faces of its neighbors. This is the case for solid leafs and
+    /// for paged leafs, whose boxes are unknown and are treated as if they were solid.
+    pub fn is_opaque(&self) -> bool {
+        self.is_solid() || self.is_paged()
     }
     pub fn node_index(&self) -> u16 {
-        self.value & NODE_INDEX_BIT_MASK
+        self.value_ & NODE_INDEX_BIT_MASK
     }
-    pub fn set_node_index_unmasked(&mut self, node_index: u16) {
-        self.value = (self.value & !NODE_INDEX_BIT_MASK) | node_index;
+    pub(crate) fn set_node_index_unmasked(&mut self, node_index: u16) {
+        self.value_ = (self.value_ & !NODE_INDEX_BIT_MASK) | node_index;
     }
     pub fn set_node_index(&mut self, node_index: u16) {
-        unsafe { self.set_node_index_unmasked(node_index & NODE_INDEX_BIT_MASK); }
+        self.set_node_index_unmasked(node_index & NODE_INDEX_BIT_MASK);
+    }
+    /// Whether the child may be viewed as a hidden leaf, which is a solid leaf without surface
+    /// flag.
+    pub fn is_hidden(&self) -> bool {
+        !self.is_node() && self.is_solid() && !self.is_surface()
+    }
+    /// Get the child as leaf or None, if it is a node or a paged leaf.
+    pub fn as_leaf(&self) -> Option<&Leaf> {
+        if self.is_node() || self.is_paged() { None } else { Some(unsafe { self.as_leaf_unchecked() }) }
+    }
+    /// Get the child as leaf or None, if it is a node or a paged leaf.
+    pub fn as_leaf_mut(&mut self) -> Option<&mut Leaf> {
+        if self.is_node() || self.is_paged() { None } else { Some(unsafe { self.as_leaf_mut_unchecked() }) }
     }
-    pub unsafe fn as_leaf(&self) -> &Leaf {
+    /// Get the child as hidden leaf or None, if it is not hidden.
+    pub fn as_hidden(&self) -> Option<&HiddenLeaf> {
+        if !self.is_hidden() { None } else { Some(unsafe { self.as_hidden_unchecked() }) }
+    }
+    /// Get the child as hidden leaf or None, if it is not hidden.
+    pub fn as_hidden_mut(&mut self) -> Option<&mut HiddenLeaf> {
+        if !self.is_hidden() { None } else { Some(unsafe { self.as_hidden_mut_unchecked() }) }
+    }
+    pub(crate) unsafe fn as_leaf_unchecked(&self) -> &Leaf {
         transmute::<&Self, &Leaf>(self)
     }
-    pub unsafe fn as_leaf_mut(&mut self) -> &mut Leaf {
+    pub(crate) unsafe fn as_leaf_mut_unchecked(&mut self) -> &mut Leaf {
         transmute::<&mut Self, &mut Leaf>(self)
     }
-    pub unsafe fn as_hidden(&self) -> &HiddenLeaf {
+    pub(crate) unsafe fn as_hidden_unchecked(&self) -> &HiddenLeaf {
         transmute::<&Self, &HiddenLeaf>(self)
     }
-    pub unsafe fn as_hidden_mut(&mut self) -> &mut HiddenLeaf {
+    pub(crate) unsafe fn as_hidden_mut_unchecked(&mut self) -> &mut HiddenLeaf {
         transmute::<&mut Self, &mut HiddenLeaf>(self)
     }
 }
 impl Deref for Child {
     type Target = u16;
     fn deref(&self) -> &Self::Target {
-        &self.value
-    }
-}
-impl DerefMut for Child {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.value
+        &self.value_
     }
 }
 
 /// Leaf wrapper of a child of a node.
 #[derive(Copy, Clone)]
+#[repr(transparent)]
 pub struct Leaf {
     child_: Child,
 }
 impl Leaf {
-    pub unsafe fn new(child: Child) -> Self {
+    pub(crate) unsafe fn new(child: Child) -> Self {
         Leaf { child_: child }
     }
+    /// Get a child as leaf or None, if it is a node or a paged leaf. Only the tree itself places
+    /// the markers of paged sub-cubes.
+    pub fn from_child(child: Child) -> Option<Self> {
+        if child.is_node() || child.is_paged() { None } else { Some(unsafe { Leaf::new(child) }) }
+    }
     pub fn void() -> Self {
         unsafe { Leaf::new(Child::void()) }
     }
@@ -205,9 +275,6 @@ impl Leaf {
     pub fn as_child(&self) -> &Child {
         &self.child_
     }
-    pub unsafe fn as_child_mut(&mut self) -> &mut Child {
-        &mut self.child_
-    }
 }
 impl Deref for Leaf {
     type Target = Child;
@@ -216,14 +283,20 @@ impl Deref for Leaf {
     }
 }
 
+/// Leaf wrapper of a child of a node, which is solid and not part of the surface.
 #[derive(Copy, Clone)]
+#[repr(transparent)]
 pub struct HiddenLeaf {
     child_: Child,
 }
 impl HiddenLeaf {
-    pub unsafe fn new(child: Child) -> Self {
+    pub(crate) unsafe fn new(child: Child) -> Self {
         HiddenLeaf { child_: child }
     }
+    /// Get a child as hidden leaf or None, if it is not hidden.
+    pub fn from_child(child: Child) -> Option<Self> {
+        if !child.is_hidden() { None } else { Some(unsafe { HiddenLeaf::new(child) }) }
+    }
     pub fn from_box_spec(spec: u16) -> Self {
         unsafe { HiddenLeaf::new(Child::leaf_from_surface_solid_box_spec(false, true, spec)) }
     }
@@ -233,8 +306,8 @@ impl HiddenLeaf {
     pub fn as_child(&self) -> &Child {
         &self.child_
     }
-    pub unsafe fn as_child_mut(&mut self) -> &mut Child {
-        &mut self.child_
+    pub fn as_leaf(&self) -> &Leaf {
+        unsafe { self.child_.as_leaf_unchecked() }
     }
 }
 impl Deref for HiddenLeaf {
@@ -253,22 +326,40 @@ impl Deref for HiddenLeaf {
 /// is not partial transparent and a 13-Bit user defined box specifier. Zero is the void box
 /// specifier. When the space a node would represent in the world is only made of the same boxes
 /// (including void), no chunks and infos will be allocated for this node and its children. Instead
-/// the parent node will only contain a leaf child value for this node.
+/// the parent node will only contain a leaf child value for this node. Indices of nodes, which
+/// don't fit into 15 Bits, are stored outside of the chunk (see FAR_NODE_INDEX).
 #[derive(Copy)]
+#[repr(transparent)]
 pub struct Chunk<C: Copy> {
     pub children: [C; CHUNK_LEN as usize],
 }
 impl Chunk<Child> {
-    pub unsafe fn as_leaf(&self) -> &Chunk<Leaf> {
+    /// Get the chunk as chunk of leafs or None, if any child is a node.
+    pub fn as_leaf(&self) -> Option<&Chunk<Leaf>> {
+        if self.iter().any(|child| child.is_node()) { None } else { Some(unsafe { self.as_leaf_unchecked() }) }
+    }
+    /// Get the chunk as chunk of leafs or None, if any child is a node.
+    pub fn as_leaf_mut(&mut self) -> Option<&mut Chunk<Leaf>> {
+        if self.iter().any(|child| child.is_node()) { None } else { Some(unsafe { self.as_leaf_mut_unchecked() }) }
+    }
+    /// Get the chunk as chunk of hidden leafs or None, if any child is not hidden.
+    pub fn as_hidden_leaf(&self) -> Option<&Chunk<HiddenLeaf>> {
+        if !self.iter().all(|child| child.is_hidden()) { None } else { Some(unsafe { self.as_hidden_leaf_unchecked() }) }
+    }
+    /// Get the chunk as chunk of hidden leafs or None, if any child is not hidden.
+    pub fn as_hidden_leaf_mut(&mut self) -> Option<&mut Chunk<HiddenLeaf>> {
+        if !self.iter().all(|child| child.is_hidden()) { None } else { Some(unsafe { self.as_hidden_leaf_mut_unchecked() }) }
+    }
+    pub(crate) unsafe fn as_leaf_unchecked(&self) -> &Chunk<Leaf> {
         transmute::<&Self, &Chunk<Leaf>>(self)
     }
-    pub unsafe fn as_leaf_mut(&mut self) -> &mut Chunk<Leaf> {
+    pub(crate) unsafe fn as_leaf_mut_unchecked(&mut self) -> &mut Chunk<Leaf> {
         transmute::<&mut Self, &mut Chunk<Leaf>>(self)
     }
-    pub unsafe fn as_hidden_leaf(&self) -> &Chunk<HiddenLeaf> {
+    pub(crate) unsafe fn as_hidden_leaf_unchecked(&self) -> &Chunk<HiddenLeaf> {
         transmute::<&Self, &Chunk<HiddenLeaf>>(self)
     }
-    pub unsafe fn as_hidden_leaf_mut(&mut self) -> &mut Chunk<HiddenLeaf> {
+    pub(crate) unsafe fn as_hidden_leaf_mut_unchecked(&mut self) -> &mut Chunk<HiddenLeaf> {
         transmute::<&mut Self, &mut Chunk<HiddenLeaf>>(self)
     }
 }
@@ -276,16 +367,13 @@ impl Chunk<Leaf> {
     pub fn as_child(&self) -> &Chunk<Child> {
         unsafe { transmute::<&Self, &Chunk<Child>>(self) }
     }
-    pub unsafe fn as_child_mut(&mut self) -> &Chunk<Child> {
-        transmute::<&mut Self, &mut Chunk<Child>>(self)
-    }
 }
 impl Chunk<HiddenLeaf> {
-    pub fn as_child(&self) -> &Chunk<Leaf> {
-        unsafe { transmute::<&Self, &Chunk<Leaf>>(self) }
+    pub fn as_child(&self) -> &Chunk<Child> {
+        unsafe { transmute::<&Self, &Chunk<Child>>(self) }
     }
-    pub unsafe fn as_child_mut(&mut self) -> &mut Chunk<Child> {
-        transmute::<&mut Self, &mut Chunk<Child>>(self)
+    pub fn as_leaf(&self) -> &Chunk<Leaf> {
+        unsafe { transmute::<&Self, &Chunk<Leaf>>(self) }
     }
 }
 impl<C: Copy> Clone for Chunk<C> {
@@ -302,11 +390,896 @@ impl<C: Copy> DerefMut for Chunk<C> {
 }
 
 /// Chunks where all children are hidden may be compressed and decompressed.
+/// The position is the position of the first box within the sub-cube of the node and the depth is
+/// the depth of the leaf, which replaces the node in its parent. Only nodes at depth 1, whose
+/// children are single boxes, are compressed. The box specifier of the leaf returned by compress
+/// is the id of the compressed node. The tree keeps the id apart from the leaf and passes it to
+/// peek and decompress as box specifier of a hidden leaf. The leaf within the chunk of the parent
+/// is the first box of the compressed chunk instead, so that lookups, which don't look into
+/// compressed leafs, see an actual box.
 pub trait Compression {
     /// Compress a chunk to a leaf.
     fn compress(&mut self, pos: na::Vector3<u32>, depth: u8, chunk: &Chunk<HiddenLeaf>) -> Option<HiddenLeaf>;
-    /// Decompress a leaf into a chunk.
+    /// Decompress a leaf into a chunk. The leaf is not used anymore afterwards.
     fn decompress(&mut self, pos: na::Vector3<u32>, depth: u8, leaf: HiddenLeaf, chunk: &mut Chunk<HiddenLeaf>);
+    /// Get a single child of a compressed leaf without decompressing it or None, if the
+    /// compression can't do that. Boxes within compressed leafs, which can't be looked into, are
+    /// reported by the tree as the first box of their chunk.
+    #[allow(unused_variables)]
+    fn peek(&self, pos: na::Vector3<u32>, depth: u8, leaf: HiddenLeaf, index: u16) -> Option<HiddenLeaf> {
+        None
+    }
+}
+
+/// Compression, which never compresses a chunk.
+pub struct NoCompression;
+impl Compression for NoCompression {
+    fn compress(&mut self, _: na::Vector3<u32>, _: u8, _: &Chunk<HiddenLeaf>) -> Option<HiddenLeaf> {
+        None
+    }
+    fn decompress(&mut self, _: na::Vector3<u32>, _: u8, _: HiddenLeaf, _: &mut Chunk<HiddenLeaf>) {
+        panic!("nothing has been compressed");
+    }
+}
+
+/// Entry of a dictionary compression.
+struct DictionaryEntry {
+    chunk: Box<Chunk<HiddenLeaf>>,
+    hash: u64,
+    num_refs: u32,
+}
+
+/// Compression, which stores the chunks of hidden nodes in a dictionary and uses the index within
+/// the dictionary as id of the compressed node. Identical chunks, like the ones deep below a
+/// terrain made of the same boxes, are stored only once.
+pub struct DictionaryCompression {
+    entries_: Vec<Option<DictionaryEntry>>,
+    free_ids_: Vec<u16>,
+    ids_by_hash_: HashMap<u64, Vec<u16>>,
+}
+impl DictionaryCompression {
+    /// Creates a new empty dictionary.
+    pub fn new() -> Self {
+        DictionaryCompression {
+            entries_: Vec::new(),
+            free_ids_: Vec::new(),
+            ids_by_hash_: HashMap::new(),
+        }
+    }
+    /// Number of distinct chunks stored in the dictionary.
+    pub fn num_chunks(&self) -> usize {
+        self.entries_.len() - self.free_ids_.len()
+    }
+    /// Number of compressed leafs referencing the chunks stored in the dictionary.
+    pub fn num_refs(&self) -> usize {
+        self.entries_.iter().map(|entry| match *entry {
+            Some(ref entry) => entry.num_refs as usize,
+            None => 0,
+        }).sum()
+    }
+    fn hash_chunk(chunk: &Chunk<HiddenLeaf>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for leaf in chunk.iter() {
+            hasher.write_u16(leaf.value_);
+        }
+        hasher.finish()
+    }
+    fn entry(&self, leaf: HiddenLeaf) -> &DictionaryEntry {
+        match self.entries_.get(leaf.box_spec() as usize) {
+            Some(&Some(ref entry)) => entry,
+            _ => panic!("invalid compressed leaf {}", leaf.box_spec()),
+        }
+    }
+}
+impl Compression for DictionaryCompression {
+    fn compress(&mut self, _: na::Vector3<u32>, _: u8, chunk: &Chunk<HiddenLeaf>) -> Option<HiddenLeaf> {
+        let hash = DictionaryCompression::hash_chunk(chunk);
+        if let Some(ids) = self.ids_by_hash_.get(&hash) {
+            for &id in ids.iter() {
+                if let Some(ref mut entry) = self.entries_[id as usize] {
+                    if entry.chunk.iter().zip(chunk.iter()).all(|(a, b)| a.value_ == b.value_) {
+                        entry.num_refs += 1;
+                        return Some(HiddenLeaf::from_box_spec(id));
+                    }
+                }
+            }
+        }
+        let id = match self.free_ids_.pop() {
+            Some(id) => id,
+            None => {
+                if self.entries_.len() > (BOX_SPEC_BIT_MASK as usize) {
+                    return None;
+                }
+                self.entries_.push(None);
+                (self.entries_.len() - 1) as u16
+            },
+        };
+        self.entries_[id as usize] = Some(DictionaryEntry {
+            chunk: Box::new(*chunk),
+            hash: hash,
+            num_refs: 1,
+        });
+        self.ids_by_hash_.entry(hash).or_insert_with(Vec::new).push(id);
+        Some(HiddenLeaf::from_box_spec(id))
+    }
+    fn decompress(&mut self, _: na::Vector3<u32>, _: u8, leaf: HiddenLeaf, chunk: &mut Chunk<HiddenLeaf>) {
+        let id = leaf.box_spec();
+        let hash = {
+            let entry = match self.entries_.get_mut(id as usize) {
+                Some(&mut Some(ref mut entry)) => entry,
+                _ => panic!("invalid compressed leaf {}", id),
+            };
+            *chunk = *entry.chunk;
+            entry.num_refs -= 1;
+            if entry.num_refs != 0 {
+                return;
+            }
+            entry.hash
+        };
+        self.entries_[id as usize] = None;
+        self.free_ids_.push(id);
+        let remove_hash = {
+            let ids = self.ids_by_hash_.get_mut(&hash).unwrap();
+            ids.retain(|&i| i != id);
+            ids.is_empty()
+        };
+        if remove_hash {
+            self.ids_by_hash_.remove(&hash);
+        }
+    }
+    fn peek(&self, _: na::Vector3<u32>, _: u8, leaf: HiddenLeaf, index: u16) -> Option<HiddenLeaf> {
+        Some(self.entry(leaf).chunk[index as usize])
+    }
+}
+
+/// Error of an edit, which needed more nodes than there are free nodes available. The tree is left
+/// unchanged.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutOfNodes;
+impl fmt::Display for OutOfNodes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "not enough free nodes available")
+    }
+}
+impl Error for OutOfNodes {
+    fn description(&self) -> &str {
+        "not enough free nodes available"
+    }
+}
+
+/// Inconsistency within the structure of a tree found by Tree::check. Nodes are identified by
+/// their index and children by the index of their node and their index within its chunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The number of infos differs from the number of chunks.
+    InfoCount { num_chunks: usize, num_infos: usize },
+    /// A child refers to the root node or to a node, which has not been allocated.
+    InvalidNodeIndex { node_index: u32, index: u16, child_node: u32 },
+    /// A child refers to a far node, which is not registered.
+    UnregisteredFarNode { node_index: u32, index: u16 },
+    /// A far node is registered for a child, which doesn't refer to a far node.
+    StaleFarNode { node_index: u32, index: u16 },
+    /// A node is referred to by more than one child.
+    SharedNode { node_index: u32 },
+    /// A child at depth 0 refers to a node.
+    NodeAtDepth0 { node_index: u32, index: u16 },
+    /// The parent index within the info of a node is not the node referring to it.
+    WrongParent { node_index: u32, parent_index: u32, expected: u32 },
+    /// The index within the chunk of the parent in the info of a node is not the index of the
+    /// child referring to it.
+    WrongIndexInParent { node_index: u32, index_in_parent: u16, expected: u16 },
+    /// A compressed leaf is registered for a child, which is no hidden leaf at depth 1.
+    StaleCompressedLeaf { node_index: u32, index: u16 },
+    /// The counters within the info of a node don't match its children.
+    WrongCounters { node_index: u32 },
+    /// The surface flag of a leaf doesn't match its neighbors. The position is the position of the
+    /// first box within the sub-cube of the leaf.
+    StaleSurface { pos: na::Vector3<u32>, depth: u8, surface: bool },
+    /// The free list refers to a node, which has not been allocated, is in use or is part of the
+    /// free list already.
+    InvalidFreeNode { node_index: u32 },
+    /// The number of free nodes doesn't match the free list and the nodes not allocated yet.
+    FreeNodeCount { num_free_nodes: u32, expected: u32 },
+    /// An allocated node, which is neither in use nor part of the free list.
+    LeakedNode { node_index: u32 },
+}
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::InfoCount { num_chunks, num_infos } =>
+                write!(f, "{} infos for {} chunks", num_infos, num_chunks),
+            Violation::InvalidNodeIndex { node_index, index, child_node } =>
+                write!(f, "child {} of node {} refers to invalid node {}", index, node_index, child_node),
+            Violation::UnregisteredFarNode { node_index, index } =>
+                write!(f, "child {} of node {} refers to an unregistered far node", index, node_index),
+            Violation::StaleFarNode { node_index, index } =>
+                write!(f, "far node registered for child {} of node {}, which is no far node", index, node_index),
+            Violation::SharedNode { node_index } =>
+                write!(f, "node {} is referred to more than once", node_index),
+            Violation::NodeAtDepth0 { node_index, index } =>
+                write!(f, "child {} of node {} at depth 0 refers to a node", index, node_index),
+            Violation::WrongParent { node_index, parent_index, expected } =>
+                write!(f, "node {} has parent index {} instead of {}", node_index, parent_index, expected),
+            Violation::WrongIndexInParent { node_index, index_in_parent, expected } =>
+                write!(f, "node {} has index {} in its parent instead of {}", node_index, index_in_parent, expected),
+            Violation::StaleCompressedLeaf { node_index, index } =>
+                write!(f, "compressed leaf registered for child {} of node {}, which is no hidden leaf at depth 1", index, node_index),
+            Violation::WrongCounters { node_index } =>
+                write!(f, "counters of node {} don't match its children", node_index),
+            Violation::StaleSurface { pos, depth, surface } =>
+                write!(f, "leaf at {:?} at depth {} has surface flag {}", pos, depth, surface),
+            Violation::InvalidFreeNode { node_index } =>
+                write!(f, "free list refers to invalid node {}", node_index),
+            Violation::FreeNodeCount { num_free_nodes, expected } =>
+                write!(f, "{} free nodes instead of {}", num_free_nodes, expected),
+            Violation::LeakedNode { node_index } =>
+                write!(f, "node {} is neither in use nor free", node_index),
+        }
+    }
+}
+
+/// Memory and occupancy statistics of a tree created by Tree::stats. Leafs are counted as
+/// children of nodes, regardless of the size of their sub-cubes.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// Number of nodes in use per depth. A node is at the depth of the leaf, which could replace
+    /// it in its parent, so the root node is at the depth of the tree and there are no nodes at
+    /// depth 0.
+    pub num_nodes_per_depth: Vec<u32>,
+    pub num_void_leafs: u64,
+    pub num_solid_leafs: u64,
+    /// Number of leafs, which are neither void nor solid.
+    pub num_transparent_leafs: u64,
+    pub num_surface_leafs: u64,
+    pub num_compressed_leafs: u64,
+    pub num_paged_leafs: u64,
+    /// Number of leafs per extended box specifier, excluding void, compressed and paged leafs.
+    pub ext_spec_histogram: BTreeMap<u16, u64>,
+    /// Bytes used by the chunks and infos of the nodes in use.
+    pub bytes_used: usize,
+    /// Bytes allocated for chunks and infos, including the free nodes and spare capacity.
+    pub bytes_allocated: usize,
+    /// Bytes the chunks and infos would take, if all max_nodes nodes were in use.
+    pub bytes_max: usize,
+    pub max_nodes: u32,
+    pub num_free_nodes: u32,
+    /// Number of nodes, which have been allocated and returned to the free list.
+    pub num_free_list_nodes: u32,
+    /// Number of runs of consecutive node indices within the free list. The more runs there are
+    /// for the same number of free nodes, the more fragmented the allocated nodes are.
+    pub num_free_runs: u32,
+}
+impl Stats {
+    /// Number of nodes in use.
+    pub fn num_nodes_used(&self) -> u32 {
+        self.num_nodes_per_depth.iter().sum()
+    }
+    /// Fraction of the allocated nodes, which are on the free list.
+    pub fn fragmentation(&self) -> f64 {
+        let num_allocated = self.num_nodes_used() + self.num_free_list_nodes;
+        if num_allocated == 0 {
+            0.0
+        } else {
+            self.num_free_list_nodes as f64 / num_allocated as f64
+        }
+    }
+}
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "nodes: {} used of {}, {} free", self.num_nodes_used(), self.max_nodes, self.num_free_nodes));
+        for (depth, num_nodes) in self.num_nodes_per_depth.iter().enumerate().skip(1).rev() {
+            try!(writeln!(f, "  depth {}: {} nodes", depth, num_nodes));
+        }
+        try!(writeln!(
+            f, "free list: {} nodes in {} runs, fragmentation {:.1}%",
+            self.num_free_list_nodes, self.num_free_runs, self.fragmentation() * 100.0
+        ));
+        try!(writeln!(
+            f, "memory: {} bytes used, {} bytes allocated, {} bytes max",
+            self.bytes_used, self.bytes_allocated, self.bytes_max
+        ));
+        try!(writeln!(
+            f, "leafs: {} void, {} solid, {} transparent, {} surface, {} compressed, {} paged",
+            self.num_void_leafs, self.num_solid_leafs, self.num_transparent_leafs,
+            self.num_surface_leafs, self.num_compressed_leafs, self.num_paged_leafs
+        ));
+        try!(write!(f, "box specs:"));
+        for (ext_spec, num_leafs) in self.ext_spec_histogram.iter() {
+            try!(write!(f, " {}:{}", ext_spec, num_leafs));
+        }
+        Ok(())
+    }
+}
+
+/// Magic bytes at the beginning of a saved tree.
+pub const FILE_MAGIC: [u8; 4] = *b"BXTR";
+/// Version of the format of a saved tree.
+pub const FILE_VERSION: u16 = 1;
+
+fn write_u16<W: Write>(writer: &mut W, value: u16) -> io::Result<()> {
+    writer.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    try!(reader.read_exact(&mut bytes));
+    Ok((bytes[0] as u16) | ((bytes[1] as u16) << 8))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    try!(reader.read_exact(&mut bytes));
+    Ok((bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 24))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Get the position of the first box within the sub-cube of the leaf at a specific depth, which
+/// contains the box at a position.
+pub fn sub_cube_pos(pos: na::Vector3<u32>, depth: u8) -> na::Vector3<u32> {
+    let sub_cube_mask = !(1u32.wrapping_shl((depth * CHUNK_SIDE_LEN_LOG2) as u32).wrapping_sub(1));
+    na::Vector3::new(
+        pos.x & sub_cube_mask,
+        pos.y & sub_cube_mask,
+        pos.z & sub_cube_mask,
+    )
+}
+
+/// Get the position of the first box within the sub-cube of a child at a specific depth, given
+/// the position of the first box within the sub-cube of its parent and its index within the chunk
+/// of the parent.
+pub fn child_pos(pos: na::Vector3<u32>, depth: u8, index: u16) -> na::Vector3<u32> {
+    let shift = (depth * CHUNK_SIDE_LEN_LOG2) as u32;
+    na::Vector3::new(
+        pos.x | ((((index >> (0 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK) << shift),
+        pos.y | ((((index >> (1 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK) << shift),
+        pos.z | ((((index >> (2 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK) << shift),
+    )
+}
+
+/// Get the range of the children of a node or a leaf above depth 0 per axis, which overlap an
+/// axis aligned box region from min to max inclusive, in units of their side length. The position
+/// is the position of the first box within the sub-cube of the parent, which must overlap the
+/// region, and the depth is the depth of the children.
+fn overlapping_children(
+    pos: na::Vector3<u32>, depth: u8, min: na::Vector3<u32>, max: na::Vector3<u32>
+) -> (na::Vector3<u32>, na::Vector3<u32>) {
+    let side_len = 1u64 << ((depth + 1) * CHUNK_SIDE_LEN_LOG2);
+    let shift = (depth * CHUNK_SIDE_LEN_LOG2) as u32;
+    let mut lo = na::Vector3::new(0u32, 0, 0);
+    let mut hi = na::Vector3::new(0u32, 0, 0);
+    for axis in 0..3 {
+        let last = (pos[axis] as u64 + side_len - 1).min(max[axis] as u64) as u32;
+        lo[axis] = (min[axis].max(pos[axis]) - pos[axis]) >> shift;
+        hi[axis] = (last - pos[axis]) >> shift;
+    }
+    (lo, hi)
+}
+
+/// Whether the sub-cube of a child at a specific depth is entirely within an axis aligned box
+/// region from min to max inclusive.
+fn is_within(pos: na::Vector3<u32>, depth: u8, min: na::Vector3<u32>, max: na::Vector3<u32>) -> bool {
+    let side_len = 1u64 << (depth * CHUNK_SIDE_LEN_LOG2);
+    (0..3).all(|axis| {
+        pos[axis] >= min[axis] && pos[axis] as u64 + side_len - 1 <= max[axis] as u64
+    })
+}
+
+/// Get the index of the child at a specific depth within the chunk of its parent, which contains
+/// the box at a position.
+fn child_index_at(pos: na::Vector3<u32>, depth: u8) -> u16 {
+    let shift = (depth * CHUNK_SIDE_LEN_LOG2) as u32;
+    (
+        (((pos.x >> shift) & CHUNK_SIDE_MASK) as u16) << (0 * CHUNK_SIDE_LEN_LOG2)
+        |
+        (((pos.y >> shift) & CHUNK_SIDE_MASK) as u16) << (1 * CHUNK_SIDE_LEN_LOG2)
+        |
+        (((pos.z >> shift) & CHUNK_SIDE_MASK) as u16) << (2 * CHUNK_SIDE_LEN_LOG2)
+    )
+}
+
+/// First box hit by a ray.
+#[derive(Copy, Clone)]
+pub struct RayHit {
+    /// Position of the box.
+    pub pos: na::Vector3<u32>,
+    /// Type of the box.
+    pub leaf: Leaf,
+    /// Normal of the face of the box, through which the ray entered it, or zero if the ray started
+    /// within the box.
+    pub normal: na::Vector3<i32>,
+    /// Distance from the origin of the ray to the point, where it entered the box.
+    pub distance: f64,
+}
+
+/// Node or leaf above depth 0, whose children are iterated by a region iterator.
+#[derive(Copy, Clone)]
+enum RegionParent {
+    Node(u32),
+    Leaf(Child),
+    /// Compressed leaf at depth 1 with its id.
+    Compressed(Child, u16),
+}
+
+/// Children of a node or a leaf above depth 0 overlapping the region of a region iterator.
+#[derive(Copy, Clone)]
+struct RegionFrame {
+    parent: RegionParent,
+    /// Position of the first box within the sub-cube of the parent.
+    pos: na::Vector3<u32>,
+    /// Depth of the children.
+    depth: u8,
+    /// Range of the overlapping children per axis in units of their side length.
+    lo: na::Vector3<u32>,
+    hi: na::Vector3<u32>,
+    /// Next child.
+    next: na::Vector3<u32>,
+}
+
+/// Iterator over the boxes within an axis aligned box region of a tree, see Tree::iter_region.
+pub struct RegionIter<'a, C: Compression + 'a> {
+    tree_: &'a Tree<C>,
+    min_: na::Vector3<u32>,
+    max_: na::Vector3<u32>,
+    include_void_: bool,
+    frames_: Vec<RegionFrame>,
+}
+impl<'a, C: Compression + 'a> RegionIter<'a, C> {
+    /// Get the frame of the children of a node or a leaf above depth 0 overlapping the region.
+    fn frame(&self, parent: RegionParent, pos: na::Vector3<u32>, depth: u8) -> RegionFrame {
+        let (lo, hi) = overlapping_children(pos, depth, self.min_, self.max_);
+        RegionFrame {
+            parent: parent,
+            pos: pos,
+            depth: depth,
+            lo: lo,
+            hi: hi,
+            next: lo,
+        }
+    }
+}
+impl<'a, C: Compression + 'a> Iterator for RegionIter<'a, C> {
+    /// Position of the first box within the sub-cube, the depth and the leaf. Sub-cubes above
+    /// depth 0 are reported as a whole, so their size can't be told from the position and the leaf
+    /// alone and the depth is part of the item.
+    type Item = (na::Vector3<u32>, u8, Leaf);
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            loop {
+                let frame = match self.frames_.last_mut() {
+                    Some(frame) => {
+                        if frame.next.z > frame.hi.z {
+                            None
+                        } else {
+                            let current = *frame;
+                            // Advance to the next overlapping child.
+                            frame.next.x += 1;
+                            if frame.next.x > frame.hi.x {
+                                frame.next.x = frame.lo.x;
+                                frame.next.y += 1;
+                                if frame.next.y > frame.hi.y {
+                                    frame.next.y = frame.lo.y;
+                                    frame.next.z += 1;
+                                }
+                            }
+                            Some(current)
+                        }
+                    },
+                    None => return None,
+                };
+                let frame = match frame {
+                    Some(frame) => frame,
+                    None => {
+                        self.frames_.pop();
+                        continue;
+                    },
+                };
+                let index = (
+                    (frame.next.x as u16) << (0 * CHUNK_SIDE_LEN_LOG2)
+                    |
+                    (frame.next.y as u16) << (1 * CHUNK_SIDE_LEN_LOG2)
+                    |
+                    (frame.next.z as u16) << (2 * CHUNK_SIDE_LEN_LOG2)
+                );
+                let pos = child_pos(frame.pos, frame.depth, index);
+                let child = match frame.parent {
+                    RegionParent::Node(node_index) => {
+                        let child = *self.tree_.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+                        if child.is_node() {
+                            let child_node = self.tree_.node_of(node_index, index, child);
+                            let child_frame = self.frame(RegionParent::Node(child_node), pos, frame.depth - 1);
+                            self.frames_.push(child_frame);
+                            continue;
+                        }
+                        if let Some(id) = self.tree_.compressed_id(node_index, index, child) {
+                            let child_frame = self.frame(RegionParent::Compressed(child, id), pos, frame.depth - 1);
+                            self.frames_.push(child_frame);
+                            continue;
+                        }
+                        child
+                    },
+                    parent => self.tree_.region_child(parent, frame.pos, frame.depth, index),
+                };
+                if frame.depth > 0 && child.is_paged() {
+                    continue;
+                }
+                if frame.depth > 0 {
+                    // Only sub-cubes made of identical boxes, which are entirely within the region,
+                    // are reported as a whole.
+                    if !is_within(pos, frame.depth, self.min_, self.max_) {
+                        let child_frame = self.frame(RegionParent::Leaf(child), pos, frame.depth - 1);
+                        self.frames_.push(child_frame);
+                        continue;
+                    }
+                }
+                if child.is_void() && !self.include_void_ {
+                    continue;
+                }
+                return Some((pos, frame.depth, Leaf::new(child)));
+            }
+        }
+    }
+}
+
+/// Level of detail of cast_view_with. Sub-cubes above depth 0, which appear smaller than a number
+/// of pixels, are reported as single coarse boxes instead of their individual boxes.
+#[derive(Copy, Clone, Debug)]
+pub struct Lod {
+    /// Number of pixels covered by a length of one box at a distance of one box, e.g. half the
+    /// height of the viewport divided by the tangent of half the vertical field of view.
+    pub pixels_per_unit: f64,
+    /// Sub-cubes, whose side length appears smaller than that number of pixels at the point
+    /// closest to the origin, are reported as coarse boxes.
+    pub min_pixels: f64,
+}
+
+/// Occlusion culling of cast_view_with. The children of each node are traversed roughly front to
+/// back and solid boxes already reported are drawn into a coarse depth buffer covering the view.
+/// Sub-cubes, which are behind the depth buffer at all samples within and around them, are
+/// skipped. Gaps between occluders, which are narrower than the samples, may hide what is behind.
+#[derive(Copy, Clone, Debug)]
+pub struct Occlusion {
+    /// Number of cells of the depth buffer per axis.
+    pub resolution: u32,
+}
+
+/// Order, in which cast_view_with reports boxes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ViewOrder {
+    /// Children are traversed in the order of their indices.
+    Index,
+    /// Boxes are reported before the boxes behind them as seen from the origin.
+    FrontToBack,
+    /// Boxes are reported after the boxes behind them as seen from the origin.
+    BackToFront,
+}
+
+/// Options of cast_view_with.
+#[derive(Clone, Debug)]
+pub struct ViewOptions {
+    /// Number of threads traversing the children of the root node, which are kept by the tree
+    /// across calls. The boxes are reported on the calling thread in the same order regardless of
+    /// the number of threads. Ignored with occlusion culling, which depends on the boxes reported
+    /// before.
+    pub num_threads: usize,
+    /// Level of detail or None to report individual boxes only.
+    pub lod: Option<Lod>,
+    /// Occlusion culling or None to report all boxes within the view. Occlusion culling always
+    /// traverses front to back.
+    pub occlusion: Option<Occlusion>,
+    /// Order, in which the boxes are reported.
+    pub order: ViewOrder,
+}
+impl Default for ViewOptions {
+    fn default() -> Self {
+        ViewOptions {
+            num_threads: 1,
+            lod: None,
+            occlusion: None,
+            order: ViewOrder::Index,
+        }
+    }
+}
+
+/// Coarse depth buffer of the occlusion culling of cast_view_with. Points are projected onto the
+/// plane at a distance of one box in view direction, which is divided into a grid of cells. Each
+/// cell holds a depth in view direction, behind which everything within the cell is hidden by
+/// occluders. Occluders covering a cell only partially are gathered in a mask of the samples of the
+/// cell they cover, until they cover all of them.
+struct DepthBuffer {
+    origin: na::Point3<f64>,
+    forward: na::Vector3<f64>,
+    right: na::Vector3<f64>,
+    up: na::Vector3<f64>,
+    min: (f64, f64),
+    /// Number of cells per unit of the projection plane.
+    scale: (f64, f64),
+    resolution: u32,
+    depths: Vec<f64>,
+    /// Samples covered by the occluders gathered per cell and the largest depth of them.
+    masks: Vec<(u16, f64)>,
+}
+
+/// Number of samples per cell of a depth buffer along each axis.
+const CELL_SAMPLES: usize = 4;
+impl DepthBuffer {
+    /// Creates an empty depth buffer covering the view of cast_view.
+    fn new(origin: na::Point3<f64>, planes: [na::Vector3<f64>; 4], resolution: u32) -> Self {
+        let forward = (planes[0] + planes[1] + planes[2] + planes[3]).normalize();
+        let right = planes[0] - planes[1];
+        let right = (right - forward * na::dot(&right, &forward)).normalize();
+        let up = na::cross(&forward, &right);
+        let mut buffer = DepthBuffer {
+            origin: origin,
+            forward: forward,
+            right: right,
+            up: up,
+            min: (0.0, 0.0),
+            scale: (0.0, 0.0),
+            resolution: resolution,
+            depths: vec![::std::f64::INFINITY; (resolution as usize) * (resolution as usize)],
+            masks: vec![(0, 0.0); (resolution as usize) * (resolution as usize)],
+        };
+        // The edges of the view are the intersections of adjacent planes.
+        let mut min = (::std::f64::INFINITY, ::std::f64::INFINITY);
+        let mut max = (-::std::f64::INFINITY, -::std::f64::INFINITY);
+        for &(a, b) in [(0, 2), (0, 3), (1, 2), (1, 3)].iter() {
+            let mut edge = na::cross(&planes[a], &planes[b]);
+            if na::dot(&edge, &forward) < 0.0 {
+                edge = -edge;
+            }
+            match buffer.project(edge) {
+                Some((u, v)) => {
+                    min = (min.0.min(u), min.1.min(v));
+                    max = (max.0.max(u), max.1.max(v));
+                },
+                // The view is too wide to be covered, so nothing is culled.
+                None => {
+                    buffer.resolution = 0;
+                    return buffer;
+                },
+            }
+        }
+        buffer.min = min;
+        buffer.scale = (resolution as f64 / (max.0 - min.0), resolution as f64 / (max.1 - min.1));
+        buffer
+    }
+    /// Projects a point relative to the origin, which must be in front of the origin.
+    fn project(&self, point: na::Vector3<f64>) -> Option<(f64, f64)> {
+        let depth = na::dot(&point, &self.forward);
+        if depth < 1e-6 {
+            return None;
+        }
+        Some((na::dot(&point, &self.right) / depth, na::dot(&point, &self.up) / depth))
+    }
+    /// Get the corners of the sub-cube of a child at a specific depth relative to the origin and
+    /// the rectangle covered by their projection, or None if not all of them are in front of the
+    /// origin. The position is the position of the first box within the sub-cube.
+    fn project_cube(&self, pos: na::Vector3<u32>, depth: u8) -> Option<(na::Vector3<f64>, na::Vector3<f64>, (f64, f64), (f64, f64))> {
+        let side_len = (1u64 << (depth * CHUNK_SIDE_LEN_LOG2)) as f64;
+        let min = na::Vector3::new(pos.x as f64 - self.origin.x, pos.y as f64 - self.origin.y, pos.z as f64 - self.origin.z);
+        let max = na::Vector3::new(min.x + side_len, min.y + side_len, min.z + side_len);
+        let mut lo = (::std::f64::INFINITY, ::std::f64::INFINITY);
+        let mut hi = (-::std::f64::INFINITY, -::std::f64::INFINITY);
+        for corner in 0..8 {
+            let point = na::Vector3::new(
+                if (corner & 1) != 0 { max.x } else { min.x },
+                if (corner & 2) != 0 { max.y } else { min.y },
+                if (corner & 4) != 0 { max.z } else { min.z },
+            );
+            let (u, v) = match self.project(point) {
+                Some(uv) => uv,
+                None => return None,
+            };
+            lo = (lo.0.min(u), lo.1.min(v));
+            hi = (hi.0.max(u), hi.1.max(v));
+        }
+        Some((min, max, lo, hi))
+    }
+    /// Whether the sub-cube of a child at a specific depth is hidden within all cells overlapped by
+    /// its projection. The position is the position of the first box within the sub-cube.
+    fn is_hidden(&self, pos: na::Vector3<u32>, depth: u8) -> bool {
+        if self.resolution == 0 {
+            return false;
+        }
+        let (min, max, lo, hi) = match self.project_cube(pos, depth) {
+            Some(projection) => projection,
+            None => return false,
+        };
+        let mut min_depth = ::std::f64::INFINITY;
+        for corner in 0..8 {
+            let point = na::Vector3::new(
+                if (corner & 1) != 0 { max.x } else { min.x },
+                if (corner & 2) != 0 { max.y } else { min.y },
+                if (corner & 4) != 0 { max.z } else { min.z },
+            );
+            min_depth = min_depth.min(na::dot(&point, &self.forward));
+        }
+        let last = (self.resolution - 1) as f64;
+        let x0 = ((lo.0 - self.min.0) * self.scale.0).floor();
+        let x1 = ((hi.0 - self.min.0) * self.scale.0).ceil() - 1.0;
+        let y0 = ((lo.1 - self.min.1) * self.scale.1).floor();
+        let y1 = ((hi.1 - self.min.1) * self.scale.1).ceil() - 1.0;
+        if x1 < 0.0 || y1 < 0.0 || x0 > last || y0 > last {
+            return false;
+        }
+        for y in (y0.max(0.0) as usize)..(y1.min(last) as usize + 1) {
+            for x in (x0.max(0.0) as usize)..(x1.min(last) as usize + 1) {
+                if self.depths[x + y * self.resolution as usize] >= min_depth {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+    /// Draws the sub-cube of a solid child at a specific depth into the cells overlapped by its
+    /// projection. The position is the position of the first box within the sub-cube.
+    fn add_occluder(&mut self, pos: na::Vector3<u32>, depth: u8) {
+        if self.resolution == 0 {
+            return;
+        }
+        let (min, max, lo, hi) = match self.project_cube(pos, depth) {
+            Some(projection) => projection,
+            None => return,
+        };
+        let last = (self.resolution - 1) as f64;
+        let x0 = ((lo.0 - self.min.0) * self.scale.0).floor().max(0.0);
+        let x1 = (((hi.0 - self.min.0) * self.scale.0).ceil() - 1.0).min(last);
+        let y0 = ((lo.1 - self.min.1) * self.scale.1).floor().max(0.0);
+        let y1 = (((hi.1 - self.min.1) * self.scale.1).ceil() - 1.0).min(last);
+        if x0 > x1 || y0 > y1 {
+            return;
+        }
+        let full = (1u32 << (CELL_SAMPLES * CELL_SAMPLES)) - 1;
+        for y in (y0 as usize)..(y1 as usize + 1) {
+            for x in (x0 as usize)..(x1 as usize + 1) {
+                let mut mask = 0u32;
+                let mut max_entry = 0.0f64;
+                for sample in 0..(CELL_SAMPLES * CELL_SAMPLES) {
+                    let sample_x = x as f64 + ((sample % CELL_SAMPLES) as f64 + 0.5) / CELL_SAMPLES as f64;
+                    let sample_y = y as f64 + ((sample / CELL_SAMPLES) as f64 + 0.5) / CELL_SAMPLES as f64;
+                    let u = self.min.0 + sample_x / self.scale.0;
+                    let v = self.min.1 + sample_y / self.scale.1;
+                    if let Some(entry) = self.entry(min, max, u, v) {
+                        mask |= 1 << sample;
+                        max_entry = max_entry.max(entry);
+                    }
+                }
+                if mask == 0 {
+                    continue;
+                }
+                let cell = x + y * self.resolution as usize;
+                let (mut covered, mut covered_depth) = self.masks[cell];
+                if mask != full {
+                    covered |= mask as u16;
+                    covered_depth = covered_depth.max(max_entry);
+                    if covered as u32 != full {
+                        self.masks[cell] = (covered, covered_depth);
+                        continue;
+                    }
+                    // The occluders gathered so far cover the cell together.
+                    max_entry = covered_depth;
+                    self.masks[cell] = (0, 0.0);
+                }
+                if self.depths[cell] > max_entry {
+                    self.depths[cell] = max_entry;
+                }
+            }
+        }
+    }
+    /// Get the depth, at which the ray through a point of the projection plane enters a sub-cube
+    /// with corners relative to the origin, or None if it misses it.
+    fn entry(&self, min: na::Vector3<f64>, max: na::Vector3<f64>, u: f64, v: f64) -> Option<f64> {
+        // The ray advances by one unit in view direction per step.
+        let dir = self.forward + self.right * u + self.up * v;
+        let mut entry = 0.0f64;
+        let mut exit = ::std::f64::INFINITY;
+        for axis in 0..3 {
+            if dir[axis] == 0.0 {
+                if min[axis] > 0.0 || max[axis] < 0.0 {
+                    return None;
+                }
+            } else {
+                let t0 = min[axis] / dir[axis];
+                let t1 = max[axis] / dir[axis];
+                entry = entry.max(t0.min(t1));
+                exit = exit.min(t0.max(t1));
+            }
+        }
+        if entry <= exit { Some(entry) } else { None }
+    }
+}
+
+/// Job of a worker thread, which points to a closure on the stack of the thread waiting for it.
+struct Job(*const (dyn Fn() + Sync));
+unsafe impl Send for Job {}
+
+/// Threads of a tree, which are kept across calls of cast_view_with. They are spawned on demand and
+/// run until the tree is dropped.
+struct Workers {
+    jobs: Vec<mpsc::Sender<Job>>,
+    threads: Vec<thread::JoinHandle<()>>,
+    done_sender: mpsc::Sender<bool>,
+    done: mpsc::Receiver<bool>,
+}
+impl Workers {
+    fn new() -> Self {
+        let (done_sender, done) = mpsc::channel();
+        Workers {
+            jobs: Vec::new(),
+            threads: Vec::new(),
+            done_sender: done_sender,
+            done: done,
+        }
+    }
+    /// Runs a closure on num_threads threads and waits for all of them to return. Returns false, if
+    /// any of them panicked.
+    fn run<Run: Fn() + Sync>(&mut self, num_threads: usize, job: &Run) -> bool {
+        while self.jobs.len() < num_threads {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let done = self.done_sender.clone();
+            self.threads.push(thread::spawn(move || {
+                for job in receiver.iter() {
+                    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| unsafe { (*job.0)() }));
+                    if done.send(result.is_ok()).is_err() {
+                        return;
+                    }
+                }
+            }));
+            self.jobs.push(sender);
+        }
+        // The lifetime of the closure is erased to send it to the threads. A thread calls it once
+        // per job and reports to done afterwards, also if the call panicked, so the closure isn't
+        // used anymore, once all threads, which got the job, have reported. Nothing from sending
+        // the jobs up to there may unwind, or the threads would call a closure, which is gone.
+        let job: &(dyn Fn() + Sync) = job;
+        let job: *const (dyn Fn() + Sync) = unsafe { transmute(job) };
+        let mut num_jobs = 0;
+        for sender in self.jobs[..num_threads].iter() {
+            if sender.send(Job(job)).is_ok() {
+                num_jobs += 1;
+            }
+        }
+        let mut ok = num_jobs == num_threads;
+        for _ in 0..num_jobs {
+            // Receiving can't fail, since done_sender is kept.
+            ok &= self.done.recv() == Ok(true);
+        }
+        ok
+    }
+}
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.jobs.clear();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Number of leafs kept by a leaf cache.
+const LEAF_CACHE_LEN: usize = 8;
+
+/// Leafs, which have been looked up last, with the position of the first box within their
+/// sub-cube and their depth. The neighbors of the boxes along the boundary of a sub-cube are
+/// usually found within a few leafs.
+struct LeafCache {
+    leafs: Vec<(na::Vector3<u32>, u8, Child)>,
+    next: usize,
+}
+impl LeafCache {
+    fn new() -> Self {
+        LeafCache {
+            leafs: Vec::with_capacity(LEAF_CACHE_LEN),
+            next: 0,
+        }
+    }
 }
 
 /// Additional information about a node. A chunk and an info form a node. They are stored in
@@ -314,13 +1287,66 @@ pub trait Compression {
 #[derive(Clone)]
 pub struct Info {
     /// Index of the parent node, 0 if root.
-    pub parent_index: u16,
+    pub parent_index: u32,
+    /// Index of the child referring to the node within the chunk of the parent node, 0 if root.
+    pub index_in_parent: u16,
     /// Number of void children.
     pub num_void_children: u16,
-    /// Number of children which are solid.
+    /// Number of children which are solid. Like nodes, paged leafs are not counted by any of the
+    /// counters.
     pub num_solid_children: u16,
+    /// Number of children which are neither void nor solid.
+    pub num_transparent_children: u16,
     /// Number of values which have the visible flag set in the corresponding chunk.
     pub num_surface_children: u16,
+    /// Number of children which are nodes with surface, see has_surface.
+    pub num_surface_nodes: u16,
+}
+impl Info {
+    /// Creates the info of a node with only void children.
+    pub fn new(parent_index: u32) -> Self {
+        Info {
+            parent_index: parent_index,
+            index_in_parent: 0,
+            num_void_children: CHUNK_LEN,
+            num_solid_children: 0,
+            num_transparent_children: 0,
+            num_surface_children: 0,
+            num_surface_nodes: 0,
+        }
+    }
+    /// Whether any leaf within the sub-cube of the node has the visible flag set.
+    pub fn has_surface(&self) -> bool {
+        self.num_surface_children != 0 || self.num_surface_nodes != 0
+    }
+    /// Counts a child, which has been added to the corresponding chunk.
+    fn add_child(&mut self, child: Child) {
+        if child.is_node() || child.is_paged() {
+            return;
+        }
+        if child.is_void() {
+            self.num_void_children += 1;
+        } else if child.is_solid() {
+            self.num_solid_children += 1;
+        } else {
+            self.num_transparent_children += 1;
+        }
+        if child.is_surface() { self.num_surface_children += 1; }
+    }
+    /// Uncounts a child, which has been removed from the corresponding chunk.
+    fn remove_child(&mut self, child: Child) {
+        if child.is_node() || child.is_paged() {
+            return;
+        }
+        if child.is_void() {
+            self.num_void_children -= 1;
+        } else if child.is_solid() {
+            self.num_solid_children -= 1;
+        } else {
+            self.num_transparent_children -= 1;
+        }
+        if child.is_surface() { self.num_surface_children -= 1; }
+    }
 }
 /// An octree like structer with CHUNK_LEN^3=16^3=4096 entries per node.
 /// On its leafs it stores box types.
@@ -329,90 +1355,162 @@ pub struct Tree<C: Compression + ?Sized> {
     chunks_: Vec<Chunk<Child>>,
     infos_: Vec<Info>,
     depth_: u8,
-    first_free_node_: u16,
-    num_free_nodes_: u16,
+    first_free_node_: u32,
+    num_free_nodes_: u32,
+    max_nodes_: u32,
+    far_nodes_: HashMap<(u32, u16), u32>,
+    /// Ids of the compressed leafs by the index of their parent node and their index within the
+    /// chunk of the parent. A compressed leaf is a hidden leaf at depth 1, which stands for the
+    /// chunk of hidden boxes passed to the compression, and has the box specifier of the first box
+    /// of the chunk.
+    compressed_: HashMap<(u32, u16), u16>,
+    /// Depth of the children, whose sub-cubes are recorded as dirty regions, if recording.
+    dirty_depth_: Option<u8>,
+    /// Positions of the first boxes within the dirty regions.
+    dirty_regions_: HashSet<(u32, u32, u32)>,
+    /// Threads of cast_view_with.
+    workers_: Mutex<Workers>,
     compression_: C,
 }
 impl<C: Compression> Tree<C> {
-    /// Creates a new empty tree.
-    pub fn new(depth: u8, max_nodes: u16, compression: C) -> Self {
-        unsafe {
-            // Check for valid range of depth.
-            if depth < 2 || depth > MAX_DEPTH {
-                panic!(
-                    "depth must be in range {} - {}, but {} was specified",
-                    2,
-                    MAX_DEPTH,
-                    depth
-                );
-            }
-            // Check for valid range of max_nodes.
-            if max_nodes < (depth as u16) || max_nodes > (1 << NODE_INDEX_BITS) {
-                panic!(
-                    "max_nodes must be in range (depth={}) - {}, but {} was specified",
-                    depth,
-                    (1 << NODE_INDEX_BITS),
-                    max_nodes
-                );
-            }
-            // Create chunks.
-            let mut chunks = Vec::new();
-            chunks.resize(max_nodes as usize, Chunk {
+    /// Creates a new empty tree. Only the root node is allocated, further nodes are allocated on
+    /// demand until the tree is made of max_nodes nodes.
+    pub fn new(depth: u8, max_nodes: u32, compression: C) -> Self {
+        // Check for valid range of depth.
+        if depth < 2 || depth > MAX_DEPTH {
+            panic!(
+                "depth must be in range {} - {}, but {} was specified",
+                2,
+                MAX_DEPTH,
+                depth
+            );
+        }
+        // Check for valid range of max_nodes.
+        if max_nodes < (depth as u32) {
+            panic!(
+                "max_nodes must be at least (depth={}), but {} was specified",
+                depth,
+                max_nodes
+            );
+        }
+        // Create and return the tree with only the root node.
+        Tree {
+            chunks_: vec![Chunk {
                 children: [Child::void(); CHUNK_LEN as usize]
-            } );
-            for i in 1..(max_nodes - 1) {
-                chunks.get_unchecked_mut(i as usize).get_unchecked_mut(0).value = (i + 1) as u16;
-            }
-            chunks.get_unchecked_mut((max_nodes - 1) as usize).get_unchecked_mut(0).value = 0;
-            // Create infos.
-            let mut infos = Vec::new();
-            infos.resize(max_nodes as usize, Info {
-                parent_index: 0,
-                num_void_children: CHUNK_LEN,
-                num_solid_children: 0,
-                num_surface_children: 0,
-            });
-            // Create and return the tree.
-            Tree {
-                chunks_: chunks,
-                infos_: infos,
-                depth_: depth,
-                first_free_node_: 1,
-                num_free_nodes_: max_nodes - 1,
-                compression_: compression,
-            }
+            }],
+            infos_: vec![Info::new(0)],
+            depth_: depth,
+            first_free_node_: 0,
+            num_free_nodes_: max_nodes - 1,
+            max_nodes_: max_nodes,
+            far_nodes_: HashMap::new(),
+            compressed_: HashMap::new(),
+            dirty_depth_: None,
+            dirty_regions_: HashSet::new(),
+            workers_: Mutex::new(Workers::new()),
+            compression_: compression,
         }
     }
     pub fn chunks(&self) -> &[Chunk<Child>] {
         &self.chunks_
     }
-    pub unsafe fn chunks_mut(&mut self) -> &mut [Chunk<Child>] {
-        &mut self.chunks_
-    }
     pub fn infos(&self) -> &[Info] {
         &self.infos_
     }
-    pub unsafe fn infos_mut(&mut self) -> &mut [Info] {
-        &mut self.infos_
-    }
     pub fn depth(&self) -> &u8 {
         &self.depth_
     }
-    pub fn first_free_node(&self) -> &u16 {
+    pub fn first_free_node(&self) -> &u32 {
         &self.first_free_node_
     }
-    pub unsafe fn first_free_node_mut(&mut self) -> &mut u16 {
-        &mut self.first_free_node_
+    pub fn max_nodes(&self) -> &u32 {
+        &self.max_nodes_
+    }
+    /// Number of nodes, which are currently allocated, including the free nodes, which have been
+    /// returned to the tree.
+    pub fn num_nodes(&self) -> u32 {
+        self.chunks_.len() as u32
     }
-    pub fn num_free_nodes(&self) -> &u16 {
+    /// Number of nodes, which may still be taken, including the ones, which are not allocated yet.
+    pub fn num_free_nodes(&self) -> &u32 {
         &self.num_free_nodes_
     }
-    pub unsafe fn num_free_nodes_mut(&mut self) -> &mut u16 {
-        &mut self.num_free_nodes_
+    pub fn compression(&self) -> &C {
+        &self.compression_
+    }
+    /// Starts recording the regions, whose boxes are changed by edits, including the regions of
+    /// the boxes, whose surface flags are changed. A region is the sub-cube of a child at a
+    /// specific depth.
+    pub fn track_dirty_regions(&mut self, depth: u8) {
+        // Check for valid range of depth.
+        if depth >= self.depth_ {
+            panic!(
+                "depth must be in range {} - {}, but {} was specified",
+                0,
+                self.depth_ - 1,
+                depth
+            );
+        }
+        if self.dirty_depth_ != Some(depth) {
+            self.dirty_regions_.clear();
+        }
+        self.dirty_depth_ = Some(depth);
+    }
+    /// Stops recording dirty regions and forgets the recorded ones.
+    pub fn untrack_dirty_regions(&mut self) {
+        self.dirty_depth_ = None;
+        self.dirty_regions_.clear();
+    }
+    pub fn dirty_depth(&self) -> &Option<u8> {
+        &self.dirty_depth_
+    }
+    /// Get the positions of the first boxes within the regions, which have been changed since the
+    /// last call, and forget them.
+    pub fn drain_dirty_regions(&mut self) -> Vec<na::Vector3<u32>> {
+        self.dirty_regions_.drain().map(|(x, y, z)| na::Vector3::new(x, y, z)).collect()
+    }
+    /// Records the regions overlapping an axis aligned box region from min to max inclusive as
+    /// dirty, when recording. If around is set, the region is extended by one box in every
+    /// direction, wrapping around the boundary of the world, to cover changed surface flags.
+    fn mark_dirty(&mut self, min: na::Vector3<u32>, max: na::Vector3<u32>, around: bool) {
+        let depth = match self.dirty_depth_ {
+            Some(depth) => depth,
+            None => return,
+        };
+        let world_side_len = 1u64 << (self.depth_ * CHUNK_SIDE_LEN_LOG2);
+        let side_len_log2 = (depth * CHUNK_SIDE_LEN_LOG2) as u64;
+        let num_regions = world_side_len >> side_len_log2;
+        // First region and number of regions per axis.
+        let mut ranges = [(0u64, 0u64); 3];
+        for axis in 0..3 {
+            let (min, max) = (min[axis] as u64, max[axis] as u64);
+            ranges[axis] = if !around {
+                (min >> side_len_log2, (max >> side_len_log2) - (min >> side_len_log2) + 1)
+            } else if max - min + 3 >= world_side_len {
+                (0, num_regions)
+            } else {
+                let first = ((min + world_side_len - 1) % world_side_len) >> side_len_log2;
+                let last = ((max + 1) % world_side_len) >> side_len_log2;
+                (first, (last + num_regions - first) % num_regions + 1)
+            };
+        }
+        for z in 0..ranges[2].1 {
+            for y in 0..ranges[1].1 {
+                for x in 0..ranges[0].1 {
+                    self.dirty_regions_.insert((
+                        (((ranges[0].0 + x) % num_regions) << side_len_log2) as u32,
+                        (((ranges[1].0 + y) % num_regions) << side_len_log2) as u32,
+                        (((ranges[2].0 + z) % num_regions) << side_len_log2) as u32,
+                    ));
+                }
+            }
+        }
     }
-    /// Get the type of a box at a specific position.
+    /// Get the type of a box at a specific position. Boxes within paged leafs are reported as the
+    /// paged leaf.
     pub fn get_at_pos(&self, mut pos: na::Vector3<u32>) -> Leaf {
         unsafe {
+            let orig_pos = pos;
             pos = {
                 let init_rotate = (self.depth_ * CHUNK_SIDE_LEN_LOG2) as u32;
                 na::Vector3::new(
@@ -421,7 +1519,7 @@ impl<C: Compression> Tree<C> {
                     pos.z.rotate_right(init_rotate)
                 )
             };
-            let mut node_index: u16 = 0;
+            let mut node_index: u32 = 0;
             let mut depth = self.depth_;
             loop {
                 pos = {
@@ -442,21 +1540,54 @@ impl<C: Compression> Tree<C> {
                 );
                 let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
                 if !child.is_node() {
-                    if child.ext_spec() != 0 && depth != 0 {
-                        // Handle compressed or static
-                        panic!("not jet implemented");
-                    }
-                    return Leaf::new(child);
+                    // A leaf above depth 0 stands for a sub-cube made of identical boxes, unless
+                    // it is compressed.
+                    return Leaf::new(self.peek_compressed(node_index, index, orig_pos, depth, child));
+                }
+                node_index = self.node_of(node_index, index, child);
+            }
+        }
+    }
+    /// Get the id of a leaf child of a node, if it is a compressed leaf.
+    unsafe fn compressed_id(&self, node_index: u32, index: u16, child: Child) -> Option<u16> {
+        if child.is_hidden() && !self.compressed_.is_empty() {
+            self.compressed_.get(&(node_index, index)).cloned()
+        } else {
+            None
+        }
+    }
+    /// Get the box at a position within a leaf child of a node at a specific depth. Compressed
+    /// leafs are looked into, if the compression is able to, other leafs are returned unchanged.
+    unsafe fn peek_compressed(&self, node_index: u32, index: u16, pos: na::Vector3<u32>, depth: u8, leaf: Child) -> Child {
+        if depth == 1 {
+            if let Some(id) = self.compressed_id(node_index, index, leaf) {
+                let peeked = self.compression_.peek(
+                    sub_cube_pos(pos, depth), depth, HiddenLeaf::from_box_spec(id), child_index_at(pos, 0)
+                );
+                if let Some(peeked) = peeked {
+                    return *peeked.as_child();
                 }
-                node_index = child.node_index();
             }
         }
+        leaf
+    }
+    /// Get the type of the neighbor box of a box at a specific position.
+    pub fn neighbor(&self, pos: na::Vector3<u32>, dir: na::Vector3<u32>) -> Leaf {
+        self.get_at_pos(self.neighbor_pos(pos, dir))
     }
-    pub unsafe fn neighbor_mut(&mut self, index: u16, mut pos: na::Vector3<u32>, dir: na::Vector3<u32>) -> &mut Child {
+    /// Get the position of the neighbor box, wrapped around at the boundary of the world.
+    fn neighbor_pos(&self, pos: na::Vector3<u32>, dir: na::Vector3<u32>) -> na::Vector3<u32> {
         let mask = 1u32.wrapping_shl((self.depth_ * CHUNK_SIDE_LEN_LOG2) as u32).wrapping_sub(1);
-        pos.x = pos.x.wrapping_add(dir.x) & mask;
-        pos.y = pos.y.wrapping_add(dir.y) & mask;
-        pos.z = pos.z.wrapping_add(dir.z) & mask;
+        na::Vector3::new(
+            pos.x.wrapping_add(dir.x) & mask,
+            pos.y.wrapping_add(dir.y) & mask,
+            pos.z.wrapping_add(dir.z) & mask,
+        )
+    }
+    /// Get the node index, the index within its chunk and the depth of the leaf containing the
+    /// neighbor box.
+    unsafe fn neighbor_location(&self, mut pos: na::Vector3<u32>, dir: na::Vector3<u32>) -> (u32, u16, u8) {
+        pos = self.neighbor_pos(pos, dir);
         pos = {
             let init_rotate = (self.depth_ * CHUNK_SIDE_LEN_LOG2) as u32;
             na::Vector3::new(
@@ -465,7 +1596,7 @@ impl<C: Compression> Tree<C> {
                 pos.z.rotate_right(init_rotate)
             )
         };
-        let mut node_index: u16 = 0;
+        let mut node_index: u32 = 0;
         let mut depth = self.depth_;
         loop {
             pos = {
@@ -486,20 +1617,579 @@ impl<C: Compression> Tree<C> {
             );
             let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
             if !child.is_node() {
-                if child.ext_spec() != 0 && depth != 0 {
-                    // Handle compressed or static
-                    panic!("not jet implemented");
+                return (node_index, index, depth);
+            }
+            node_index = self.node_of(node_index, index, child);
+        }
+    }
+    /// Get the node index of a child of a node, which is a node itself.
+    pub fn child_node_index(&self, node_index: u32, index: u16) -> u32 {
+        let child = self.chunks_[node_index as usize][index as usize];
+        if !child.is_node() {
+            panic!("child {} of node {} is not a node", index, node_index);
+        }
+        unsafe { self.node_of(node_index, index, child) }
+    }
+    /// Get the node index of a child of a node, which is a node itself. Far nodes are looked up by
+    /// the index of the parent node and the index of the child within the chunk of the parent.
+    unsafe fn node_of(&self, node_index: u32, index: u16, child: Child) -> u32 {
+        let child_node = child.node_index();
+        if child_node == FAR_NODE_INDEX {
+            *self.far_nodes_.get(&(node_index, index)).expect("far node not registered")
+        } else {
+            child_node as u32
+        }
+    }
+    /// Replaces a child of a node by a node and keeps the counters of the nodes info up to date.
+    /// Nodes, whose index doesn't fit into the child value, are registered as far nodes.
+    unsafe fn set_child_node(&mut self, node_index: u32, index: u16, child_node: u32) {
+        if child_node < (FAR_NODE_INDEX as u32) {
+            self.replace_child(node_index, index, Child::node_from_index(child_node as u16));
+        } else {
+            self.replace_child(node_index, index, Child::node_from_index(FAR_NODE_INDEX));
+            self.far_nodes_.insert((node_index, index), child_node);
+        }
+        let surface = {
+            let info = self.infos_.get_unchecked_mut(child_node as usize);
+            info.parent_index = node_index;
+            info.index_in_parent = index;
+            info.has_surface()
+        };
+        if surface {
+            self.count_surface_node(node_index, true);
+        }
+    }
+    /// Whether a node other than the root node is referenced by its parent. This is not the case
+    /// for nodes, which have been detached from the tree and are about to be freed, or for nodes,
+    /// which have not been linked yet.
+    unsafe fn is_linked(&self, node_index: u32) -> bool {
+        let info = self.infos_.get_unchecked(node_index as usize);
+        let child = *self.chunks_.get_unchecked(info.parent_index as usize).get_unchecked(info.index_in_parent as usize);
+        child.is_node() && self.node_of(info.parent_index, info.index_in_parent, child) == node_index
+    }
+    /// Counts or uncounts a child of a node, which is a node with surface, and passes on the
+    /// change, if the node gains or loses its surface by that.
+    unsafe fn count_surface_node(&mut self, node_index: u32, surface: bool) {
+        let had_surface = {
+            let info = self.infos_.get_unchecked_mut(node_index as usize);
+            let had_surface = info.has_surface();
+            if surface {
+                info.num_surface_nodes += 1;
+            } else {
+                info.num_surface_nodes -= 1;
+            }
+            had_surface
+        };
+        self.pass_on_surface(node_index, had_surface);
+    }
+    /// Updates the number of nodes with surface of the parent of a linked node, if the node has
+    /// gained or lost its surface, and so on up to the root node.
+    unsafe fn pass_on_surface(&mut self, node_index: u32, had_surface: bool) {
+        let (surface, parent_index) = {
+            let info = self.infos_.get_unchecked(node_index as usize);
+            (info.has_surface(), info.parent_index)
+        };
+        if surface != had_surface && node_index != 0 && self.is_linked(node_index) {
+            self.count_surface_node(parent_index, surface);
+        }
+    }
+    /// Replaces a child of a node and keeps the counters of the nodes info up to date.
+    /// Returns the replaced child.
+    unsafe fn replace_child(&mut self, node_index: u32, index: u16, new_child: Child) -> Child {
+        let orig_child = {
+            let child = self.chunks_.get_unchecked_mut(node_index as usize).get_unchecked_mut(index as usize);
+            let orig_child = *child;
+            *child = new_child;
+            orig_child
+        };
+        let orig_surface_node = orig_child.is_node() &&
+            self.infos_.get_unchecked(self.node_of(node_index, index, orig_child) as usize).has_surface();
+        if orig_child.is_node() && orig_child.node_index() == FAR_NODE_INDEX {
+            self.far_nodes_.remove(&(node_index, index));
+        } else if orig_child.is_hidden() && !self.compressed_.is_empty() {
+            self.compressed_.remove(&(node_index, index));
+        }
+        let had_surface = {
+            let info = self.infos_.get_unchecked_mut(node_index as usize);
+            let had_surface = info.has_surface();
+            info.remove_child(orig_child);
+            info.add_child(new_child);
+            if orig_surface_node {
+                info.num_surface_nodes -= 1;
+            }
+            had_surface
+        };
+        self.pass_on_surface(node_index, had_surface);
+        orig_child
+    }
+    /// Sets the surface flag of a leaf child of a node and keeps the counters of the nodes info up
+    /// to date.
+    unsafe fn set_child_surface(&mut self, node_index: u32, index: u16, surface: bool) {
+        let child = self.chunks_.get_unchecked_mut(node_index as usize).get_unchecked_mut(index as usize);
+        if child.is_surface() != surface {
+            child.set_surface(surface);
+            let had_surface = {
+                let info = self.infos_.get_unchecked_mut(node_index as usize);
+                let had_surface = info.has_surface();
+                if surface {
+                    info.num_surface_children += 1;
+                } else {
+                    info.num_surface_children -= 1;
+                }
+                had_surface
+            };
+            self.pass_on_surface(node_index, had_surface);
+        }
+    }
+    /// Recalculates the surface flags of the box at a specific position and its neighbors. Must be
+    /// called whenever a box has been added or removed at the position or its solidity changed.
+    /// The solidity of the 5x5x5 neighborhood is gathered once and then used for the boxes in the
+    /// 3x3x3 neighborhood. Compressed leafs containing boxes, which become exposed, are split down
+    /// to depth 0. Returns the distinct nodes at depth 0 containing boxes of the 3x3x3
+    /// neighborhood and their number, or OutOfNodes if a compressed leaf could not be split. In
+    /// that case the surface flags are left partially updated.
+    unsafe fn update_surface_around(&mut self, pos: na::Vector3<u32>) -> Result<([u32; 27], usize), OutOfNodes> {
+        let mut flags = [0u32; 5];
+        for z in 0..5u32 {
+            let mut i = 0;
+            for y in 0..5u32 {
+                for x in 0..5u32 {
+                    let d = na::Vector3::new(x.wrapping_sub(2), y.wrapping_sub(2), z.wrapping_sub(2));
+                    let (node_index, index, _) = self.neighbor_location(pos, d);
+                    if !self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize).is_opaque() {
+                        flags[z as usize] |= 1u32 << i;
+                    }
+                    i += 1;
+                }
+            }
+        }
+        let mut nodes = [0u32; 27];
+        let mut num_nodes = 0;
+        for z in 0..3u32 {
+            let mut i: u8 = 0;
+            for y in 0..3u32 {
+                for x in 0..3u32 {
+                    let d = na::Vector3::new(x.wrapping_sub(1), y.wrapping_sub(1), z.wrapping_sub(1));
+                    let (mut node_index, mut index, mut depth) = self.neighbor_location(pos, d);
+                    let surface = (
+                        (
+                            flags[(z + 0) as usize] |
+                            flags[(z + 1) as usize] |
+                            flags[(z + 2) as usize]
+                        ) &
+                        (0b0100011100010u32 << i)
+                    ) != 0;
+                    let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+                    if !child.is_void() && !child.is_paged() {
+                        if surface && depth == 1 && self.compressed_id(node_index, index, child).is_some() {
+                            // Boxes within compressed leafs are hidden, so they can't be marked
+                            // as surface without decompressing them.
+                            let neighbor_pos = self.neighbor_pos(pos, d);
+                            node_index = match self.split_leaf(node_index, index, depth, sub_cube_pos(neighbor_pos, depth)) {
+                                Some(new_node) => new_node,
+                                None => return Err(OutOfNodes),
+                            };
+                            index = child_index_at(neighbor_pos, 0);
+                            depth = 0;
+                        }
+                        // The surface flag of a leaf above depth 0 is set, when any of its boxes is
+                        // part of the surface. Other boxes of it may still be exposed, so it is
+                        // never cleared here.
+                        if depth == 0 || surface {
+                            self.set_child_surface(node_index, index, surface);
+                        }
+                    }
+                    if depth == 0 && !nodes[..num_nodes].contains(&node_index) {
+                        nodes[num_nodes] = node_index;
+                        num_nodes += 1;
+                    }
+                    i += 1;
+                }
+                i += 2;
+            }
+        }
+        Ok((nodes, num_nodes))
+    }
+    /// Calculates whether the box at a specific position is part of the surface. This is the case,
+    /// when the box itself or a box of the cross shaped neighborhood in x/y direction within the
+    /// three layers around the box in z direction is not solid. Paged leafs are treated as solid.
+    unsafe fn is_surface_at(&self, pos: na::Vector3<u32>) -> bool {
+        for z in 0..3u32 {
+            for &(x, y) in [(1u32, 0u32), (0, 1), (1, 1), (2, 1), (1, 2)].iter() {
+                let d = na::Vector3::new(x.wrapping_sub(1), y.wrapping_sub(1), z.wrapping_sub(1));
+                let (node_index, index, _) = self.neighbor_location(pos, d);
+                if !self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize).is_opaque() {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    /// Get the mask of the faces of the box at a specific position, which are exposed. A face is
+    /// exposed, when the neighbor box behind it is not solid and of a different type. Faces next to
+    /// paged leafs are never exposed.
+    pub fn exposed_faces(&self, pos: na::Vector3<u32>) -> u8 {
+        let leaf = *self.get_at_pos(pos);
+        unsafe { self.exposed_faces_at(pos, leaf) }
+    }
+    /// Get the mask of the exposed faces of a box of a leaf at a specific position.
+    unsafe fn exposed_faces_at(&self, pos: na::Vector3<u32>, leaf: Child) -> u8 {
+        let mut mask = 0;
+        for face in 0..6u8 {
+            let mut dir = na::Vector3::new(0u32, 0, 0);
+            dir[(face / 2) as usize] = if (face & 1) != 0 { 1 } else { !0 };
+            let (node_index, index, _) = self.neighbor_location(pos, dir);
+            let neighbor = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+            if !neighbor.is_opaque() && neighbor.ext_spec() != leaf.ext_spec() {
+                mask |= 1 << face;
+            }
+        }
+        mask
+    }
+    /// Like exposed_faces_at for a leaf at depth 0 at an index within the chunk of a node. The
+    /// neighbors within the same chunk are looked up directly.
+    unsafe fn exposed_faces_in(&self, node_index: u32, index: u16, pos: na::Vector3<u32>, leaf: Child) -> u8 {
+        let mut mask = 0;
+        for face in 0..6u8 {
+            let shift = (face / 2) * CHUNK_SIDE_LEN_LOG2;
+            let side_index = ((index >> shift) as u32) & CHUNK_SIDE_MASK;
+            let neighbor = if (face & 1) != 0 && side_index != CHUNK_SIDE_MASK {
+                *self.chunks_.get_unchecked(node_index as usize).get_unchecked((index + (1 << shift)) as usize)
+            } else if (face & 1) == 0 && side_index != 0 {
+                *self.chunks_.get_unchecked(node_index as usize).get_unchecked((index - (1 << shift)) as usize)
+            } else {
+                let mut dir = na::Vector3::new(0u32, 0, 0);
+                dir[(face / 2) as usize] = if (face & 1) != 0 { 1 } else { !0 };
+                let (node_index, index, _) = self.neighbor_location(pos, dir);
+                *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize)
+            };
+            if !neighbor.is_opaque() && neighbor.ext_spec() != leaf.ext_spec() {
+                mask |= 1 << face;
+            }
+        }
+        mask
+    }
+    /// Takes a node from the free nodes or appends a new node, if there are no free nodes left
+    /// and the tree may still grow. Returns None, if there are no free nodes available.
+    unsafe fn alloc_node(&mut self, parent_index: u32) -> Option<u32> {
+        if self.num_free_nodes_ == 0 {
+            return None;
+        }
+        let new_node = if self.first_free_node_ != 0 {
+            let new_node = self.first_free_node_;
+            let chunk = self.chunks_.get_unchecked_mut(new_node as usize);
+            self.first_free_node_ =
+                (chunk.get_unchecked(0).value_ as u32) |
+                ((chunk.get_unchecked(1).value_ as u32) << 16);
+            *chunk.get_unchecked_mut(0) = Child::void();
+            *chunk.get_unchecked_mut(1) = Child::void();
+            new_node
+        } else {
+            self.chunks_.push(Chunk {
+                children: [Child::void(); CHUNK_LEN as usize]
+            });
+            self.infos_.push(Info::new(0));
+            (self.chunks_.len() - 1) as u32
+        };
+        self.num_free_nodes_ -= 1;
+        self.infos_.get_unchecked_mut(new_node as usize).parent_index = parent_index;
+        Some(new_node)
+    }
+    /// Replaces a leaf child above depth 0 by a new node, whose children all equal the leaf, so
+    /// that single boxes within the sub-cube of the leaf can be changed. The depth is the depth of
+    /// the leaf and the position is the position of the first box within its sub-cube.
+    /// Compressed leafs are decompressed into the new node instead.
+    /// Returns the index of the new node or None without changing the tree, if there are no free
+    /// nodes available.
+    unsafe fn split_leaf(&mut self, node_index: u32, index: u16, depth: u8, pos: na::Vector3<u32>) -> Option<u32> {
+        let leaf = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+        if leaf.is_paged() {
+            // The boxes of a paged leaf are unknown, so it can't be split.
+            return None;
+        }
+        let new_node = match self.alloc_node(node_index) {
+            Some(new_node) => new_node,
+            None => return None,
+        };
+        if let Some(id) = self.compressed_id(node_index, index, leaf) {
+            self.compression_.decompress(
+                pos,
+                depth,
+                HiddenLeaf::from_box_spec(id),
+                self.chunks_.get_unchecked_mut(new_node as usize).as_hidden_leaf_mut_unchecked()
+            );
+            let mut info = Info::new(node_index);
+            info.num_void_children = 0;
+            for child in self.chunks_.get_unchecked(new_node as usize).iter() {
+                info.add_child(*child);
+            }
+            *self.infos_.get_unchecked_mut(new_node as usize) = info;
+        } else if !leaf.is_void() {
+            let child_side_len_log2 = ((depth - 1) * CHUNK_SIDE_LEN_LOG2) as u32;
+            let mut num_surface_children = 0;
+            for i in 0..CHUNK_LEN {
+                let (x, y, z) = (
+                    ((i >> (0 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK,
+                    ((i >> (1 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK,
+                    ((i >> (2 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK,
+                );
+                let boundary =
+                    x == 0 || x == CHUNK_SIDE_MASK ||
+                    y == 0 || y == CHUNK_SIDE_MASK ||
+                    z == 0 || z == CHUNK_SIDE_MASK;
+                // Only the boxes at the boundary of a solid sub-cube may be exposed.
+                let surface = if !leaf.is_solid() {
+                    true
+                } else if !boundary {
+                    false
+                } else if depth == 1 {
+                    self.is_surface_at(na::Vector3::new(
+                        pos.x + (x << child_side_len_log2),
+                        pos.y + (y << child_side_len_log2),
+                        pos.z + (z << child_side_len_log2),
+                    ))
+                } else {
+                    leaf.is_surface()
+                };
+                if surface {
+                    num_surface_children += 1;
+                }
+                *self.chunks_.get_unchecked_mut(new_node as usize).get_unchecked_mut(i as usize) =
+                    Child::leaf_from_surface_ext_spec(surface, leaf.ext_spec());
+            }
+            let info = self.infos_.get_unchecked_mut(new_node as usize);
+            info.num_void_children = 0;
+            info.num_solid_children = if leaf.is_solid() { CHUNK_LEN } else { 0 };
+            info.num_transparent_children = if leaf.is_solid() { 0 } else { CHUNK_LEN };
+            info.num_surface_children = num_surface_children;
+        }
+        self.set_child_node(node_index, index, new_node);
+        Some(new_node)
+    }
+    /// Counts the free nodes needed to set a box at a specific position. These are the leafs above
+    /// depth 0 on the way down to the box, which need to be split, and the compressed leafs within
+    /// the 3x3x3 neighborhood, which may need to be decompressed to expose boxes. This is checked
+    /// before the tree is changed, so that most edits, which can't be done, are rejected without
+    /// splitting leafs and restoring them afterwards.
+    /// Returns None, if the box is already set.
+    unsafe fn num_nodes_needed(&self, pos: na::Vector3<u32>, new_leaf: Leaf) -> Option<u32> {
+        let zero = na::Vector3::new(0, 0, 0);
+        let (node_index, index, depth) = self.neighbor_location(pos, zero);
+        let leaf = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+        if depth > 0 && leaf.is_paged() {
+            // Paged leafs can't be split, so no number of free nodes is enough.
+            return Some(u32::MAX);
+        }
+        let compressed_id = if depth == 1 { self.compressed_id(node_index, index, leaf) } else { None };
+        let orig_leaf = match compressed_id {
+            Some(id) => self.compression_.peek(
+                sub_cube_pos(pos, 1), 1, HiddenLeaf::from_box_spec(id), child_index_at(pos, 0)
+            ).map(|leaf| *leaf.as_child()),
+            None => Some(leaf),
+        };
+        // The box of a compressed leaf, which can't be looked into, is assumed to differ.
+        if orig_leaf.map_or(false, |orig_leaf| orig_leaf.ext_spec() == new_leaf.ext_spec()) {
+            return None;
+        }
+        // Each leaf above depth 0 on the way down to the box needs to be split.
+        let mut num_nodes = depth as u32;
+        // Positions of the first boxes of the compressed leafs within the neighborhood, which need
+        // to be split.
+        let mut splits: Vec<na::Vector3<u32>> = Vec::new();
+        if compressed_id.is_some() {
+            splits.push(sub_cube_pos(pos, 1));
+        }
+        // The boxes within a leaf, even within a compressed one, have the solidity of the leaf.
+        if leaf.is_void() || new_leaf.is_void() || leaf.is_solid() != new_leaf.is_solid() {
+            for z in 0..3u32 {
+                for y in 0..3u32 {
+                    for x in 0..3u32 {
+                        let d = na::Vector3::new(x.wrapping_sub(1), y.wrapping_sub(1), z.wrapping_sub(1));
+                        let (node_index, index, depth) = self.neighbor_location(pos, d);
+                        if depth != 1 {
+                            continue;
+                        }
+                        let leaf = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+                        if self.compressed_id(node_index, index, leaf).is_none() {
+                            continue;
+                        }
+                        let split = sub_cube_pos(self.neighbor_pos(pos, d), 1);
+                        if !splits.contains(&split) {
+                            splits.push(split);
+                            num_nodes += 1;
+                        }
+                    }
                 }
-                return self.chunks_.get_unchecked_mut(node_index as usize).get_unchecked_mut(index as usize)
             }
-            node_index = child.node_index();
         }
+        Some(num_nodes)
+    }
+    /// Get the parent node index, the index within the chunk of the parent, the depth and the
+    /// position of the first box within the sub-cube of a node other than the root node.
+    unsafe fn node_location(&self, node_index: u32) -> (u32, u16, u8, na::Vector3<u32>) {
+        let mut indices = [0u16; MAX_DEPTH as usize];
+        let mut num_indices = 0;
+        let mut node = node_index;
+        while node != 0 {
+            let info = self.infos_.get_unchecked(node as usize);
+            indices[num_indices] = info.index_in_parent;
+            num_indices += 1;
+            node = info.parent_index;
+        }
+        let depth = self.depth_ - num_indices as u8;
+        let mut pos = na::Vector3::new(0u32, 0u32, 0u32);
+        for (i, &index) in indices[..num_indices].iter().enumerate() {
+            let shift = ((depth + i as u8) * CHUNK_SIDE_LEN_LOG2) as u32;
+            pos.x |= (((index >> (0 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK) << shift;
+            pos.y |= (((index >> (1 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK) << shift;
+            pos.z |= (((index >> (2 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK) << shift;
+        }
+        (self.infos_.get_unchecked(node_index as usize).parent_index, indices[0], depth, pos)
+    }
+    /// Whether all children of a node are hidden leafs, which are not all identical.
+    unsafe fn is_hidden_node(&self, node_index: u32) -> bool {
+        let info = self.infos_.get_unchecked(node_index as usize);
+        info.num_solid_children == CHUNK_LEN &&
+        info.num_surface_children == 0 &&
+        self.uniform_leaf(node_index).is_none()
+    }
+    /// Replaces nodes at depth 1, whose children are all hidden, by compressed leafs. Other nodes
+    /// and nodes, which have been freed meanwhile, are skipped. Compressed leafs are not
+    /// compressed again, so that their ids don't need to be passed to the compression.
+    unsafe fn compress_hidden_nodes(&mut self, nodes: &[u32]) {
+        for &node_index in nodes.iter() {
+            if node_index == 0 || !self.is_hidden_node(node_index) {
+                continue;
+            }
+            let (parent_index, index, depth, pos) = self.node_location(node_index);
+            if depth != 1 {
+                continue;
+            }
+            let (leaf, first_box) = {
+                let chunk = self.chunks_.get_unchecked(node_index as usize).as_hidden_leaf_unchecked();
+                (self.compression_.compress(pos, depth, chunk), *chunk.get_unchecked(0).as_child())
+            };
+            if let Some(leaf) = leaf {
+                self.replace_child(parent_index, index, first_box);
+                self.compressed_.insert((parent_index, index), leaf.box_spec());
+                self.free_node(node_index);
+            }
+        }
+    }
+    /// Get the leaf, which is able to replace a node, because all of its children are identical
+    /// leafs, or None if the node is not uniform.
+    unsafe fn uniform_leaf(&self, node_index: u32) -> Option<Child> {
+        let info = self.infos_.get_unchecked(node_index as usize);
+        if info.num_void_children == CHUNK_LEN {
+            return Some(Child::void());
+        }
+        if info.num_solid_children != CHUNK_LEN && info.num_transparent_children != CHUNK_LEN {
+            return None;
+        }
+        let chunk = self.chunks_.get_unchecked(node_index as usize);
+        let ext_spec = chunk.get_unchecked(0).ext_spec();
+        for child in chunk.iter() {
+            if child.ext_spec() != ext_spec {
+                return None;
+            }
+        }
+        if !self.compressed_.is_empty() {
+            // Compressed leafs look like hidden leafs, but stand for different boxes, even when
+            // the other children are surface leafs of the same type.
+            if (0..CHUNK_LEN).any(|index| self.compressed_.contains_key(&(node_index, index))) {
+                return None;
+            }
+        }
+        Some(Child::leaf_from_surface_ext_spec(info.num_surface_children != 0, ext_spec))
+    }
+    /// Replaces the nodes along a path, which are made of identical leafs, by a single leaf child
+    /// of their parents. The path contains the node index and the child index within the node for
+    /// each depth and is processed beginning at a specific depth until a node is found, which is
+    /// not uniform. The root node is never replaced.
+    unsafe fn collapse_path(&mut self, path: &[(u32, u16); MAX_DEPTH as usize], first_depth: u8) {
+        for depth in (first_depth as usize)..((self.depth_ - 1) as usize) {
+            let (node_index, _) = path[depth];
+            let leaf = match self.uniform_leaf(node_index) {
+                Some(leaf) => leaf,
+                None => break,
+            };
+            let (parent_index, index) = path[depth + 1];
+            self.replace_child(parent_index, index, leaf);
+            self.free_node(node_index);
+        }
+    }
+    /// Undoes the splits of the leafs along a path of an edit, which failed at a specific depth.
+    /// The nodes, which have been created for the leafs, are replaced by the leafs again and
+    /// decompressed nodes are compressed again.
+    unsafe fn undo_splits(&mut self, path: &[(u32, u16); MAX_DEPTH as usize], depth: u8) {
+        for depth in (depth as usize)..((self.depth_ - 1) as usize) {
+            let (node_index, _) = path[depth];
+            let (parent_index, index) = path[depth + 1];
+            let child = *self.chunks_.get_unchecked(parent_index as usize).get_unchecked(index as usize);
+            if !child.is_node() || self.node_of(parent_index, index, child) != node_index {
+                // Already compressed together with the boxes around it.
+                continue;
+            }
+            if let Some(leaf) = self.uniform_leaf(node_index) {
+                self.replace_child(parent_index, index, leaf);
+                self.free_node(node_index);
+            } else if self.is_hidden_node(node_index) {
+                self.compress_hidden_nodes(&[node_index]);
+            } else {
+                break;
+            }
+        }
+    }
+    /// Restores the box at depth 0 at the end of the path of an edit, which failed while updating
+    /// the surface flags around it, and undoes the splits of the edit. Leafs, which have already
+    /// been decompressed to update the surface flags, are compressed again.
+    unsafe fn restore_box(&mut self, path: &[(u32, u16); MAX_DEPTH as usize], pos: na::Vector3<u32>, orig_leaf: Child) {
+        let (node_index, index) = path[0];
+        self.replace_child(node_index, index, orig_leaf);
+        // With the original box back in place, no compressed leaf needs to be split.
+        let (nodes, num_nodes) = match self.update_surface_around(pos) {
+            Ok(nodes) => nodes,
+            Err(_) => unreachable!(),
+        };
+        self.compress_hidden_nodes(&nodes[..num_nodes]);
+        self.undo_splits(path, 0);
+    }
+    /// Returns a node, which is not referenced anymore, to the free nodes.
+    unsafe fn free_node(&mut self, node_index: u32) {
+        *self.chunks_.get_unchecked_mut(node_index as usize) = Chunk {
+            children: [Child::void(); CHUNK_LEN as usize]
+        };
+        {
+            let chunk = self.chunks_.get_unchecked_mut(node_index as usize);
+            chunk.get_unchecked_mut(0).value_ = self.first_free_node_ as u16;
+            chunk.get_unchecked_mut(1).value_ = (self.first_free_node_ >> 16) as u16;
+        }
+        *self.infos_.get_unchecked_mut(node_index as usize) = Info::new(0);
+        self.first_free_node_ = node_index;
+        self.num_free_nodes_ += 1;
     }
-    pub fn set_non_void_at_pos(&mut self, mut pos: na::Vector3<u32>, new_leaf: Leaf) -> bool {
+    pub fn set_non_void_at_pos(&mut self, pos: na::Vector3<u32>, new_leaf: Leaf) -> bool {
+        self.try_set_non_void_at_pos(pos, new_leaf).is_ok()
+    }
+    /// Sets a non void box at a specific position. Either the box is set or the tree is left
+    /// unchanged, if there are not enough free nodes available. Boxes within paged leafs can't be
+    /// set, they are rejected with OutOfNodes as well.
+    pub fn try_set_non_void_at_pos(&mut self, mut pos: na::Vector3<u32>, new_leaf: Leaf) -> Result<(), OutOfNodes> {
         unsafe {
             if new_leaf.is_void() {
                 panic!("expected non void");
             }
+            if new_leaf.is_paged() {
+                panic!("expected no paged leaf");
+            }
+            match self.num_nodes_needed(pos, new_leaf) {
+                // The box is already set.
+                None => return Ok(()),
+                Some(num_nodes) => if num_nodes > self.num_free_nodes_ {
+                    return Err(OutOfNodes);
+                },
+            }
+            let orig_pos = pos;
             pos = {
                 let init_rotate = (self.depth_ * CHUNK_SIDE_LEN_LOG2) as u32;
                 na::Vector3::new(
@@ -508,7 +2198,9 @@ impl<C: Compression> Tree<C> {
                     pos.z.rotate_right(init_rotate)
                 )
             };
-            let mut chunk: u16 = 0;
+            // Node index and child index within the node for each depth.
+            let mut path = [(0u32, 0u16); MAX_DEPTH as usize];
+            let mut chunk: u32 = 0;
             let mut depth = self.depth_;
             loop {
                 pos = {
@@ -527,260 +2219,2782 @@ impl<C: Compression> Tree<C> {
                     |
                     ((pos.z & CHUNK_SIDE_MASK) as u16) << (2 * CHUNK_SIDE_LEN_LOG2)
                 );
+                path[depth as usize] = (chunk, index);
                 if depth == 0 {
-                    let orig_leaf = {
-                        let leaf = self.chunks_.get_unchecked_mut(chunk as usize).get_unchecked_mut(index as usize);
-                        let orig_leaf = *leaf;
-                        *leaf = *new_leaf.as_child();
-                        orig_leaf
-                    };
-                    if orig_leaf.is_solid() == new_leaf.is_solid() {
-                        // TODO: Debug! Just remove
-                        panic!("not jet supported {} {} {:b} {:b}", chunk, pos, orig_leaf.value, new_leaf.value);
-                        self.chunks_.get_unchecked_mut(chunk as usize).get_unchecked_mut(index as usize).set_surface(orig_leaf.is_surface());
-                    } else if !new_leaf.is_solid() {
-                        // TODO: Debug! Just remove
-                        panic!("usage of transparent data not jet supported");
-                        for z in 0..5u32 {
-                            for y in 0..5u32 {
-                                for x in 0..5u32 {
-                                    let d = na::Vector3::new(x.wrapping_sub(2), y.wrapping_sub(2), z.wrapping_sub(2));
-                                    let n = self.neighbor_mut(index, pos, d);
-                                    if !n.is_void() {
-                                        n.set_surface(true);
-                                    }
-                                }
-                            }
-                        }
+                    let orig_leaf = *self.chunks_.get_unchecked(chunk as usize).get_unchecked(index as usize);
+                    if orig_leaf.ext_spec() == new_leaf.ext_spec() {
+                        // The box is already set.
+                        return Ok(());
+                    }
+                    let (nodes, num_nodes) = if !orig_leaf.is_void() && orig_leaf.is_solid() == new_leaf.is_solid() {
+                        // The solidity does not change, so neither do the surface flags.
+                        self.replace_child(
+                            chunk, index,
+                            Child::leaf_from_surface_ext_spec(orig_leaf.is_surface(), new_leaf.ext_spec())
+                        );
+                        self.mark_dirty(orig_pos, orig_pos, false);
+                        ([chunk; 27], 1)
                     } else {
-                        let mut flags = [0u32; 5];
-                        for z in 0..5u32 {
-                            let mut i = 0;
-                            for y in 0..5u32 {
-                                for x in 0..5u32 {
-                                    let d = na::Vector3::new(x.wrapping_sub(2), y.wrapping_sub(2), z.wrapping_sub(2));
-                                    if !self.neighbor_mut(index, pos, d).is_solid() {
-                                        flags[z as usize] |= 1u32 << i;
-                                    }
-                                    i += 1;
-                                }
-                            }
+                        self.replace_child(chunk, index, *new_leaf.as_child());
+                        self.mark_dirty(orig_pos, orig_pos, true);
+                        match self.update_surface_around(pos) {
+                            Ok(nodes) => nodes,
+                            Err(err) => {
+                                self.restore_box(&path, orig_pos, orig_leaf);
+                                return Err(err);
+                            },
                         }
-                        for z in 0..3u32 {
-                            let mut i: u8 = 0;
-                            for y in 0..3u32 {
-                                for x in 0..3u32 {
-                                    let d = na::Vector3::new(x.wrapping_sub(1), y.wrapping_sub(1), z.wrapping_sub(1));
-                                    let n = self.neighbor_mut(index, pos, d);
-                                    let surface = (
-                                        (
-                                            flags[(z + 0) as usize] |
-                                            flags[(z + 1) as usize] |
-                                            flags[(z + 2) as usize]
-                                        ) &
-                                        (0b0100011100010u32 << i)
-                                    ) != 0;
-                                    if !n.is_void() {
-                                        n.set_surface(surface);
-                                    }
-                                    i += 1;
-                                }
-                                i += 2;
-                            }
-                        }
-                    }
-                    if orig_leaf.is_void() {
-                        let info = self.infos_.get_unchecked_mut(chunk as usize);
-                        info.num_void_children += 1;
-                    }
-                    return true;
+                    };
+                    self.collapse_path(&path, 0);
+                    self.compress_hidden_nodes(&nodes[..num_nodes]);
+                    self.debug_check();
+                    return Ok(());
                 } else {
-                    let mut child = *self.chunks_.get_unchecked_mut(chunk as usize).get_unchecked_mut(index as usize);
-                    if child.is_void() {
-                        let new_node = self.first_free_node_;
-                        if new_node == 0 {
-                            // TODO: reverse previus inserted nodes
-                            return false;
-                        }
-                        self.num_free_nodes_ -= 1;
-                        self.first_free_node_ = self.chunks_.get_unchecked(new_node as usize).get_unchecked(0).value;
-                        *self.chunks_.get_unchecked_mut(new_node as usize).get_unchecked_mut(0) = Child::void();
-                        child = Child::node_from_index(new_node);
-                        *self.chunks_.get_unchecked_mut(chunk as usize).get_unchecked_mut(index as usize) = child;
-                        self.infos_.get_unchecked_mut(new_node as usize).parent_index = chunk;
+                    let child = *self.chunks_.get_unchecked(chunk as usize).get_unchecked(index as usize);
+                    if child.is_node() {
+                        chunk = self.node_of(chunk, index, child);
+                    } else {
+                        chunk = match self.split_leaf(chunk, index, depth, sub_cube_pos(orig_pos, depth)) {
+                            Some(new_node) => new_node,
+                            None => {
+                                self.undo_splits(&path, depth);
+                                return Err(OutOfNodes);
+                            },
+                        };
                     }
-                    chunk = child.node_index();
                 }
             }
         }
     }
-    pub fn set_void_at_pos(&mut self, pos: na::Vector3<u32>) {
-        panic!("not jet implemented");
-        // TODO
+    /// Removes the box at a specific position. Boxes which become exposed are marked as surface and
+    /// nodes which become entirely void are returned to the free nodes.
+    /// Returns false, if the box is part of a sub-cube made of identical boxes, which could not be
+    /// split, because there are no free nodes available.
+    pub fn set_void_at_pos(&mut self, pos: na::Vector3<u32>) -> bool {
+        self.try_set_void_at_pos(pos).is_ok()
+    }
+    /// Removes the box at a specific position. Either the box is removed or the tree is left
+    /// unchanged, if a sub-cube made of identical boxes or a paged leaf could not be split.
+    pub fn try_set_void_at_pos(&mut self, mut pos: na::Vector3<u32>) -> Result<(), OutOfNodes> {
+        unsafe {
+            match self.num_nodes_needed(pos, Leaf::void()) {
+                // There is no box to remove.
+                None => return Ok(()),
+                Some(num_nodes) => if num_nodes > self.num_free_nodes_ {
+                    return Err(OutOfNodes);
+                },
+            }
+            let orig_pos = pos;
+            pos = {
+                let init_rotate = (self.depth_ * CHUNK_SIDE_LEN_LOG2) as u32;
+                na::Vector3::new(
+                    pos.x.rotate_right(init_rotate),
+                    pos.y.rotate_right(init_rotate),
+                    pos.z.rotate_right(init_rotate)
+                )
+            };
+            // Node index and child index within the node for each depth.
+            let mut path = [(0u32, 0u16); MAX_DEPTH as usize];
+            let mut chunk: u32 = 0;
+            let mut depth = self.depth_;
+            let orig_leaf = loop {
+                pos = {
+                    const CHUNK_SIDE_LEN_LOG2_U32: u32 = CHUNK_SIDE_LEN_LOG2 as u32;
+                    na::Vector3::new(
+                        pos.x.rotate_left(CHUNK_SIDE_LEN_LOG2_U32),
+                        pos.y.rotate_left(CHUNK_SIDE_LEN_LOG2_U32),
+                        pos.z.rotate_left(CHUNK_SIDE_LEN_LOG2_U32),
+                    )
+                };
+                depth -= 1;
+                let index = (
+                    ((pos.x & CHUNK_SIDE_MASK) as u16) << (0 * CHUNK_SIDE_LEN_LOG2)
+                    |
+                    ((pos.y & CHUNK_SIDE_MASK) as u16) << (1 * CHUNK_SIDE_LEN_LOG2)
+                    |
+                    ((pos.z & CHUNK_SIDE_MASK) as u16) << (2 * CHUNK_SIDE_LEN_LOG2)
+                );
+                path[depth as usize] = (chunk, index);
+                let child = *self.chunks_.get_unchecked(chunk as usize).get_unchecked(index as usize);
+                if child.is_void() {
+                    return Ok(());
+                }
+                if depth == 0 {
+                    break self.replace_child(chunk, index, Child::void());
+                }
+                if child.is_node() {
+                    chunk = self.node_of(chunk, index, child);
+                } else {
+                    chunk = match self.split_leaf(chunk, index, depth, sub_cube_pos(orig_pos, depth)) {
+                        Some(new_node) => new_node,
+                        None => {
+                            self.undo_splits(&path, depth);
+                            return Err(OutOfNodes);
+                        },
+                    };
+                }
+            };
+            self.mark_dirty(orig_pos, orig_pos, true);
+            let (nodes, num_nodes) = match self.update_surface_around(pos) {
+                Ok(nodes) => nodes,
+                Err(err) => {
+                    self.restore_box(&path, orig_pos, orig_leaf);
+                    return Err(err);
+                },
+            };
+            // Nodes which became entirely void are returned to the free nodes.
+            self.collapse_path(&path, 0);
+            self.compress_hidden_nodes(&nodes[..num_nodes]);
+            self.debug_check();
+            Ok(())
+        }
     }
     pub fn set_at_pos(&mut self, pos: na::Vector3<u32>, new_leaf: Leaf) -> bool {
+        self.try_set_at_pos(pos, new_leaf).is_ok()
+    }
+    /// Sets a box at a specific position. Either the box is set or the tree is left unchanged, if
+    /// there are not enough free nodes available.
+    pub fn try_set_at_pos(&mut self, pos: na::Vector3<u32>, new_leaf: Leaf) -> Result<(), OutOfNodes> {
         if new_leaf.is_void() {
-            self.set_void_at_pos(pos);
-            true
+            self.try_set_void_at_pos(pos)
         } else {
-            self.set_non_void_at_pos(pos, new_leaf)
+            self.try_set_non_void_at_pos(pos, new_leaf)
         }
     }
 
-    pub fn cast_view<Callback: FnMut(na::Vector3<u32>, Child)>(
-        &self,
-        origin: na::Point3<f64>, planes: [na::Vector3<f64>; 4], dist: f64,
-        callback: &mut Callback
-    ) {
-        unsafe {
-            // (x-ox)*px + (y-oy)*py + (z-oz)*pz > 0
-            let mut deltas = [na::Vector3::new(0i64, 0, 0); 5];
-            let mut dists = [0i64; 5];
-            for i in 0..4 {
-                deltas[i].x = (planes[i].x * ((1 << 28) as f64)).ceil() as i64;
-                deltas[i].y = (planes[i].y * ((1 << 28) as f64)).ceil() as i64;
-                deltas[i].z = (planes[i].z * ((1 << 28) as f64)).ceil() as i64;
-                dists[i] = (
-                    (-origin.x * (deltas[i].x as f64)).ceil() as i64
-                    +
-                    (-origin.y * (deltas[i].y as f64)).ceil() as i64
-                    +
-                    (-origin.z * (deltas[i].z as f64)).ceil() as i64
-                );
-                deltas[i].x <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
-                deltas[i].y <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
-                deltas[i].z <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
-            }
-            let mut plane4 = na::Vector3::new(0.0f64, 0.0, 0.0);
-            for i in 0..4 {
-                plane4 -= planes[i];
-            }
-            plane4 = plane4.normalize();
-            deltas[4].x = (plane4.x * ((1 << 28) as f64)).ceil() as i64;
-            deltas[4].y = (plane4.y * ((1 << 28) as f64)).ceil() as i64;
-            deltas[4].z = (plane4.z * ((1 << 28) as f64)).ceil() as i64;
-            dists[4] = (
-                (-origin.x * (deltas[4].x as f64)).ceil() as i64
-                +
-                (-origin.y * (deltas[4].y as f64)).ceil() as i64
-                +
-                (-origin.z * (deltas[4].z as f64)).ceil() as i64
-                +
-                ((dist * ((1 << 28) as f64)).ceil() as i64)
-            );
-            deltas[4].x <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
-            deltas[4].y <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
-            deltas[4].z <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
-
-            for i in 0..5 {
-                if deltas[i].x > 0 { dists[i] += deltas[i].x; }
-                if deltas[i].y > 0 { dists[i] += deltas[i].y; }
-                if deltas[i].z > 0 { dists[i] += deltas[i].z; }
-            }
-
-            let mut depth: u8 = (self.depth_ as u8) - 1;
-            let mut chunk: u16 = 0;
-            let mut pos = na::Vector3::new(0u32, 0, 0);
-            let mut index: u16 = 0;
-            loop {
-                if dists[0] >= 0 && dists[1] >= 0 && dists[2] >= 0 && dists[3] >= 0 && dists[4] >= 0 {
-                    let child = *self.chunks_.get_unchecked(chunk as usize).get_unchecked(index as usize);
-                    if child.is_node() {
-                        depth -= 1;
-                        chunk = child.node_index();
-                        index = 0;
-                        pos.x *= CHUNK_SIDE_LEN as u32;
-                        pos.y *= CHUNK_SIDE_LEN as u32;
-                        pos.z *= CHUNK_SIDE_LEN as u32;
-                        for i in 0..5 {
-                            if deltas[i].x > 0 { dists[i] -= deltas[i].x; }
-                            if deltas[i].y > 0 { dists[i] -= deltas[i].y; }
-                            if deltas[i].z > 0 { dists[i] -= deltas[i].z; }
-                        }
-                        for i in 0..5 {
-                            deltas[i].x /= CHUNK_SIDE_LEN as i64;
-                            deltas[i].y /= CHUNK_SIDE_LEN as i64;
-                            deltas[i].z /= CHUNK_SIDE_LEN as i64;
-                        }
-                        for i in 0..5 {
-                            if deltas[i].x > 0 { dists[i] += deltas[i].x; }
-                            if deltas[i].y > 0 { dists[i] += deltas[i].y; }
-                            if deltas[i].z > 0 { dists[i] += deltas[i].z; }
-                        }
-                        continue;
-                    } else if child.is_surface() {
-                        callback(pos, child);
+    /// Writes a node and its descendants in breadth first order, so that the indices of the nodes
+    /// don't need to be saved. They are followed by the chunks of the compressed leafs within them
+    /// and the compressed leafs, which refer to their chunk by its number, so that a chunk shared
+    /// by several compressed leafs is written only once. Compressed leafs themselves are written as
+    /// the first box of their chunk. The position is the position of the first box within the
+    /// sub-cube of the node and the depth is the depth of its children. Fails without writing
+    /// anything, if the compression can't be looked into.
+    unsafe fn write_nodes<W: Write>(&self, node_index: u32, pos: na::Vector3<u32>, depth: u8, writer: &mut W) -> io::Result<()> {
+        let mut nodes = vec![(node_index, pos, depth)];
+        // Compressed leafs with the number of their parent among the nodes written, their index
+        // within the chunk of the parent and the number of their chunk.
+        let mut compressed_leafs: Vec<(u32, u16, u32)> = Vec::new();
+        // Numbers of the chunks written by the ids of the compressed leafs.
+        let mut chunk_numbers: HashMap<u16, u32> = HashMap::new();
+        let mut chunk_bytes: Vec<u8> = Vec::new();
+        let mut i = 0;
+        while i < nodes.len() {
+            let (node_index, pos, depth) = nodes[i];
+            for index in 0..CHUNK_LEN {
+                let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+                if child.is_node() {
+                    nodes.push((self.node_of(node_index, index, child), child_pos(pos, depth, index), depth - 1));
+                    continue;
+                }
+                if depth != 1 {
+                    continue;
+                }
+                let id = match self.compressed_id(node_index, index, child) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let num_chunks = chunk_numbers.len() as u32;
+                let chunk_number = *chunk_numbers.entry(id).or_insert(num_chunks);
+                if chunk_number == num_chunks {
+                    for box_index in 0..CHUNK_LEN {
+                        let leaf = match self.compression_.peek(child_pos(pos, depth, index), depth, HiddenLeaf::from_box_spec(id), box_index) {
+                            Some(leaf) => leaf,
+                            None => return Err(io::Error::new(io::ErrorKind::Other, "compressed leafs can't be looked into")),
+                        };
+                        chunk_bytes.push(leaf.value_ as u8);
+                        chunk_bytes.push((leaf.value_ >> 8) as u8);
                     }
                 }
-                loop {
-                    for i in 0..5 {
-                        dists[i] += deltas[i].x;
+                compressed_leafs.push((i as u32, index, chunk_number));
+            }
+            i += 1;
+        }
+        try!(write_u32(writer, nodes.len() as u32));
+        let mut bytes = vec![0u8; 2 * CHUNK_LEN as usize];
+        for &(node_index, _, _) in nodes.iter() {
+            for index in 0..CHUNK_LEN {
+                let mut child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+                if child.is_node() {
+                    child = Child::new(NODE_BIT_MASK);
+                }
+                bytes[2 * index as usize] = child.value_ as u8;
+                bytes[2 * index as usize + 1] = (child.value_ >> 8) as u8;
+            }
+            try!(writer.write_all(&bytes));
+        }
+        try!(write_u32(writer, chunk_numbers.len() as u32));
+        try!(writer.write_all(&chunk_bytes));
+        try!(write_u32(writer, compressed_leafs.len() as u32));
+        for &(node_number, index, chunk_number) in compressed_leafs.iter() {
+            try!(write_u32(writer, node_number));
+            try!(write_u16(writer, index));
+            try!(write_u32(writer, chunk_number));
+        }
+        Ok(())
+    }
+    /// Reads nodes written by write_nodes into newly allocated nodes and validates their structure
+    /// and the surface flags, which don't depend on the neighbors. The first node becomes a child
+    /// of the parent node, which has to be linked by the caller. The position is the position of
+    /// the first box within its sub-cube and the depth is the depth of its children. Compressed
+    /// leafs are compressed again using the compression of the tree or read as nodes, if it
+    /// doesn't take their chunk. Returns the allocated nodes. On failure all allocated nodes are
+    /// returned to the free nodes and the compressed leafs within them are released.
+    unsafe fn read_nodes<R: Read>(&mut self, parent_index: u32, pos: na::Vector3<u32>, depth: u8, reader: &mut R) -> io::Result<Vec<u32>> {
+        let num_nodes = try!(read_u32(reader));
+        if num_nodes == 0 {
+            return Err(invalid_data("invalid number of nodes"));
+        }
+        if num_nodes > self.num_free_nodes_ {
+            return Err(io::Error::new(io::ErrorKind::Other, OutOfNodes));
+        }
+        // Nodes with the position of the first box within their sub-cube and the depth of their
+        // children.
+        let mut nodes = vec![(self.alloc_node(parent_index).unwrap(), pos, depth)];
+        let mut bytes = vec![0u8; 2 * CHUNK_LEN as usize];
+        let mut result = Ok(());
+        'nodes: for i in 0..(num_nodes as usize) {
+            if i >= nodes.len() {
+                result = Err(invalid_data("node not referenced"));
+                break;
+            }
+            if let Err(err) = reader.read_exact(&mut bytes) {
+                result = Err(err);
+                break;
+            }
+            let (node_index, pos, depth) = nodes[i];
+            for index in 0..CHUNK_LEN {
+                let child = Child::new(
+                    (bytes[2 * index as usize] as u16) | ((bytes[2 * index as usize + 1] as u16) << 8)
+                );
+                if child.is_node() {
+                    if depth == 0 || nodes.len() as u32 >= num_nodes {
+                        result = Err(invalid_data("invalid node reference"));
+                        break 'nodes;
                     }
-                    if (pos.x % (CHUNK_SIDE_LEN as u32)) != ((CHUNK_SIDE_LEN - 1) as u32) {
-                        pos.x += 1;
-                    } else {
-                        pos.x = (pos.x / (CHUNK_SIDE_LEN as u32)) * (CHUNK_SIDE_LEN as u32);
-                        for i in 0..5 {
-                            dists[i] -= deltas[i].x * (CHUNK_SIDE_LEN as i64);
-                        }
-                        for i in 0..5 {
-                            dists[i] += deltas[i].y;
-                        }
-                        if (pos.y % (CHUNK_SIDE_LEN as u32)) != ((CHUNK_SIDE_LEN - 1) as u32) {
-                            pos.y += 1;
-                        } else {
-                            pos.y = (pos.y / (CHUNK_SIDE_LEN as u32)) * (CHUNK_SIDE_LEN as u32);
-                            for i in 0..5 {
-                                dists[i] -= deltas[i].y * (CHUNK_SIDE_LEN as i64);
-                            }
-                            for i in 0..5 {
-                                dists[i] += deltas[i].z;
-                            }
-                            if (pos.z % (CHUNK_SIDE_LEN as u32)) != ((CHUNK_SIDE_LEN - 1) as u32) {
-                                pos.z += 1;
-                            } else {
-                                depth += 1;
-                                if depth == (self.depth_ as u8) {
-                                    return;
-                                }
-                                pos.z = (pos.z / (CHUNK_SIDE_LEN as u32)) * (CHUNK_SIDE_LEN as u32);
-                                for i in 0..5 {
-                                    dists[i] -= deltas[i].z * (CHUNK_SIDE_LEN as i64);
-                                }
-                                chunk = self.infos_.get_unchecked(chunk as usize).parent_index;
-                                pos.x /= CHUNK_SIDE_LEN as u32;
-                                pos.y /= CHUNK_SIDE_LEN as u32;
-                                pos.z /= CHUNK_SIDE_LEN as u32;
-                                for i in 0..5 {
-                                    if deltas[i].x > 0 { dists[i] -= deltas[i].x; }
-                                    if deltas[i].y > 0 { dists[i] -= deltas[i].y; }
-                                    if deltas[i].z > 0 { dists[i] -= deltas[i].z; }
-                                }
-                                for i in 0..5 {
-                                    deltas[i].x *= CHUNK_SIDE_LEN as i64;
-                                    deltas[i].y *= CHUNK_SIDE_LEN as i64;
-                                    deltas[i].z *= CHUNK_SIDE_LEN as i64;
-                                }
-                                for i in 0..5 {
-                                    if deltas[i].x > 0 { dists[i] += deltas[i].x; }
-                                    if deltas[i].y > 0 { dists[i] += deltas[i].y; }
-                                    if deltas[i].z > 0 { dists[i] += deltas[i].z; }
-                                }
-                                index = (
-                                    ((pos.x % (CHUNK_SIDE_LEN as u32)) * 1)
-                                    |
-                                    ((pos.y % (CHUNK_SIDE_LEN as u32)) * (CHUNK_SIDE_LEN as u32))
-                                    |
-                                    ((pos.z % (CHUNK_SIDE_LEN as u32)) * (((CHUNK_SIDE_LEN as u16)*(CHUNK_SIDE_LEN as u16)) as u32))
-                                ) as u16;
-                                continue;
-                            }
-                        }
+                    let child_node = self.alloc_node(node_index).unwrap();
+                    nodes.push((child_node, child_pos(pos, depth, index), depth - 1));
+                    self.set_child_node(node_index, index, child_node);
+                } else {
+                    if depth > 0 && child.is_paged() {
+                        result = Err(invalid_data("invalid paged leaf"));
+                        break 'nodes;
+                    }
+                    // Void leafs are never part of the surface and other leafs, which are not
+                    // solid, always are.
+                    if !child.is_solid() && child.is_surface() == child.is_void() {
+                        result = Err(invalid_data("invalid surface flag"));
+                        break 'nodes;
+                    }
+                    self.replace_child(node_index, index, child);
+                }
+            }
+        }
+        if result.is_ok() && nodes.len() as u32 != num_nodes {
+            result = Err(invalid_data("invalid node reference"));
+        }
+        if result.is_ok() {
+            result = self.read_compressed_leafs(&mut nodes, reader);
+        }
+        if let Err(err) = result {
+            for &(node_index, pos, depth) in nodes.iter() {
+                for index in 0..CHUNK_LEN {
+                    if depth == 1 {
+                        self.release_compressed(node_index, index, child_pos(pos, depth, index));
                     }
-                    index += 1;
-                    break;
+                    self.replace_child(node_index, index, Child::void());
+                }
+                self.free_node(node_index);
+            }
+            return Err(err);
+        }
+        Ok(nodes.iter().map(|&(node_index, _, _)| node_index).collect())
+    }
+    /// Reads the chunks and the compressed leafs written by write_nodes after the nodes read by
+    /// read_nodes. A compressed leaf, whose chunk isn't taken by the compression, is read as a
+    /// node, which is added to the nodes.
+    unsafe fn read_compressed_leafs<R: Read>(&mut self, nodes: &mut Vec<(u32, na::Vector3<u32>, u8)>, reader: &mut R) -> io::Result<()> {
+        let num_nodes = nodes.len();
+        let num_chunks = try!(read_u32(reader));
+        // Each chunk is referenced by at least one leaf child of the nodes.
+        if num_chunks as u64 > num_nodes as u64 * CHUNK_LEN as u64 {
+            return Err(invalid_data("invalid number of chunks"));
+        }
+        let mut chunks = Vec::with_capacity(num_chunks as usize);
+        let mut bytes = vec![0u8; 2 * CHUNK_LEN as usize];
+        for _ in 0..num_chunks {
+            try!(reader.read_exact(&mut bytes));
+            let mut chunk = Chunk {
+                children: [HiddenLeaf::from_box_spec(0); CHUNK_LEN as usize]
+            };
+            for index in 0..CHUNK_LEN {
+                let child = Child::new(
+                    (bytes[2 * index as usize] as u16) | ((bytes[2 * index as usize + 1] as u16) << 8)
+                );
+                if !child.is_hidden() {
+                    return Err(invalid_data("invalid compressed chunk"));
                 }
+                *chunk.get_unchecked_mut(index as usize) = *child.as_hidden_unchecked();
             }
+            chunks.push(chunk);
+        }
+        let num_compressed_leafs = try!(read_u32(reader));
+        for _ in 0..num_compressed_leafs {
+            let node_number = try!(read_u32(reader)) as usize;
+            let index = try!(read_u16(reader));
+            let chunk_number = try!(read_u32(reader)) as usize;
+            if node_number >= num_nodes || nodes[node_number].2 != 1 || index >= CHUNK_LEN || chunk_number >= chunks.len() {
+                return Err(invalid_data("invalid compressed leaf"));
+            }
+            let (node_index, pos, depth) = nodes[node_number];
+            let chunk = &chunks[chunk_number];
+            let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+            if child.value_ != chunk.get_unchecked(0).value_ || self.compressed_.contains_key(&(node_index, index)) {
+                return Err(invalid_data("invalid compressed leaf"));
+            }
+            let child_pos = child_pos(pos, depth, index);
+            if let Some(leaf) = self.compression_.compress(child_pos, depth, chunk) {
+                self.compressed_.insert((node_index, index), leaf.box_spec());
+                continue;
+            }
+            let child_node = match self.alloc_node(node_index) {
+                Some(child_node) => child_node,
+                None => return Err(io::Error::new(io::ErrorKind::Other, OutOfNodes)),
+            };
+            *self.chunks_.get_unchecked_mut(child_node as usize).as_hidden_leaf_mut_unchecked() = *chunk;
+            let mut info = Info::new(node_index);
+            info.num_void_children = 0;
+            for child in self.chunks_.get_unchecked(child_node as usize).iter() {
+                info.add_child(*child);
+            }
+            *self.infos_.get_unchecked_mut(child_node as usize) = info;
+            self.set_child_node(node_index, index, child_node);
+            nodes.push((child_node, child_pos, depth - 1));
+        }
+        Ok(())
+    }
+    /// Writes the tree in a binary format. Only the nodes reachable from the root node are written
+    /// in breadth first order, so that the indices of the nodes don't need to be saved. Compressed
+    /// leafs are written together with their chunks, which requires a compression, which can be
+    /// looked into. All values are written in little endian byte order.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        unsafe {
+            try!(writer.write_all(&FILE_MAGIC));
+            try!(write_u16(writer, FILE_VERSION));
+            try!(writer.write_all(&[self.depth_]));
+            try!(write_u32(writer, self.max_nodes_));
+            self.write_nodes(0, na::Vector3::new(0, 0, 0), self.depth_ - 1, writer)
+        }
+    }
+    /// Reads a tree, which has been written by write_to. The counters of the node infos are
+    /// recalculated and compressed leafs and hidden nodes are compressed using the given
+    /// compression. The structure of the tree and its surface flags are validated with check.
+    pub fn read_from<R: Read>(reader: &mut R, compression: C) -> io::Result<Self> {
+        unsafe {
+            let mut magic = [0u8; 4];
+            try!(reader.read_exact(&mut magic));
+            if magic != FILE_MAGIC {
+                return Err(invalid_data("not a saved box tree"));
+            }
+            if try!(read_u16(reader)) != FILE_VERSION {
+                return Err(invalid_data("unsupported version of a saved box tree"));
+            }
+            let mut depth = [0u8; 1];
+            try!(reader.read_exact(&mut depth));
+            let depth = depth[0];
+            if depth < 2 || depth > MAX_DEPTH {
+                return Err(invalid_data("invalid depth"));
+            }
+            let max_nodes = try!(read_u32(reader));
+            if max_nodes < (depth as u32) {
+                return Err(invalid_data("invalid number of nodes"));
+            }
+            // The tree starts without any node, so that the root node is the first one read.
+            let mut tree = Tree {
+                chunks_: Vec::new(),
+                infos_: Vec::new(),
+                depth_: depth,
+                first_free_node_: 0,
+                num_free_nodes_: max_nodes,
+                max_nodes_: max_nodes,
+                far_nodes_: HashMap::new(),
+                compressed_: HashMap::new(),
+                dirty_depth_: None,
+                dirty_regions_: HashSet::new(),
+                workers_: Mutex::new(Workers::new()),
+                compression_: compression,
+            };
+            let nodes = try!(tree.read_nodes(0, na::Vector3::new(0, 0, 0), depth - 1, reader));
+            tree.compress_hidden_nodes(&nodes);
+            if !tree.check().is_empty() {
+                return Err(invalid_data("inconsistent tree"));
+            }
+            Ok(tree)
+        }
+    }
+    /// Get the node index of the parent and the index within its chunk of the child at a specific
+    /// depth, which contains the box at a position, or None if the box is part of a leaf above
+    /// that depth.
+    unsafe fn child_location(&self, pos: na::Vector3<u32>, depth: u8) -> Option<(u32, u16)> {
+        let mut node_index: u32 = 0;
+        let mut child_depth = self.depth_ - 1;
+        loop {
+            let index = child_index_at(pos, child_depth);
+            if child_depth == depth {
+                return Some((node_index, index));
+            }
+            let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+            if !child.is_node() {
+                return None;
+            }
+            node_index = self.node_of(node_index, index, child);
+            child_depth -= 1;
+        }
+    }
+    /// Writes the node at a specific depth, which contains the box at a position, and its
+    /// descendants in the same format as write_to does for the root node.
+    /// Returns false without writing anything, if there is no node at that depth.
+    pub fn write_node_to<W: Write>(&self, pos: na::Vector3<u32>, depth: u8, writer: &mut W) -> io::Result<bool> {
+        unsafe {
+            let (parent_index, index) = match self.child_location(pos, depth) {
+                Some(location) => location,
+                None => return Ok(false),
+            };
+            let child = *self.chunks_.get_unchecked(parent_index as usize).get_unchecked(index as usize);
+            if !child.is_node() {
+                return Ok(false);
+            }
+            let node_index = self.node_of(parent_index, index, child);
+            try!(self.write_nodes(node_index, sub_cube_pos(pos, depth), depth - 1, writer));
+            Ok(true)
+        }
+    }
+    /// Reads nodes written by write_node_to and replaces the leaf at a specific depth, which
+    /// contains the box at a position, by them. Hidden nodes are compressed afterwards. The surface
+    /// flags are taken as they have been written. On failure the tree is left unchanged.
+    pub(crate) unsafe fn read_node_from<R: Read>(&mut self, pos: na::Vector3<u32>, depth: u8, reader: &mut R) -> io::Result<()> {
+        let (parent_index, index) = match self.child_location(pos, depth) {
+            Some(location) => location,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "sub-cube is part of a leaf")),
+        };
+        if self.chunks_.get_unchecked(parent_index as usize).get_unchecked(index as usize).is_node() {
+            return Err(io::Error::new(io::ErrorKind::Other, "sub-cube is already a node"));
+        }
+        let nodes = try!(self.read_nodes(parent_index, sub_cube_pos(pos, depth), depth - 1, reader));
+        self.set_child_node(parent_index, index, nodes[0]);
+        let side_len_mask = 1u32.wrapping_shl((depth * CHUNK_SIDE_LEN_LOG2) as u32).wrapping_sub(1);
+        let min = sub_cube_pos(pos, depth);
+        self.mark_dirty(min, min + na::Vector3::new(side_len_mask, side_len_mask, side_len_mask), false);
+        self.compress_hidden_nodes(&nodes);
+        Ok(())
+    }
+    /// Replaces the node at a specific depth, which contains the box at a position, and its
+    /// descendants by a leaf. The surface flags are not updated. Returns false, if there is no node
+    /// at that depth.
+    pub(crate) unsafe fn replace_node_by_leaf(&mut self, pos: na::Vector3<u32>, depth: u8, leaf: Leaf) -> bool {
+        let (parent_index, index) = match self.child_location(pos, depth) {
+            Some(location) => location,
+            None => return false,
+        };
+        let child = *self.chunks_.get_unchecked(parent_index as usize).get_unchecked(index as usize);
+        if !child.is_node() {
+            return false;
+        }
+        let node_index = self.node_of(parent_index, index, child);
+        self.replace_child(parent_index, index, *leaf.as_child());
+        self.free_nodes_below(node_index, sub_cube_pos(pos, depth), depth - 1);
+        let side_len_mask = 1u32.wrapping_shl((depth * CHUNK_SIDE_LEN_LOG2) as u32).wrapping_sub(1);
+        let min = sub_cube_pos(pos, depth);
+        self.mark_dirty(min, min + na::Vector3::new(side_len_mask, side_len_mask, side_len_mask), false);
+        true
+    }
+    /// Returns a node, which is not referenced anymore, and its descendants to the free nodes and
+    /// releases compressed leafs within them. The position is the position of the first box within
+    /// the sub-cube of the node and the depth is the depth of its children.
+    unsafe fn free_nodes_below(&mut self, node_index: u32, pos: na::Vector3<u32>, depth: u8) {
+        for index in 0..CHUNK_LEN {
+            let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+            if child.is_node() {
+                let child_node = self.node_of(node_index, index, child);
+                self.replace_child(node_index, index, Child::void());
+                self.free_nodes_below(child_node, child_pos(pos, depth, index), depth - 1);
+            } else if depth == 1 {
+                self.release_compressed(node_index, index, child_pos(pos, depth, index));
+            }
+        }
+        self.free_node(node_index);
+    }
+    /// Releases a leaf child at depth 1 of a node, which is about to be removed from the tree, if
+    /// it is compressed. The compression keeps track of the compressed leafs in use, so it is
+    /// decompressed into a chunk, which is dropped.
+    unsafe fn release_compressed(&mut self, node_index: u32, index: u16, pos: na::Vector3<u32>) {
+        let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+        if let Some(id) = self.compressed_id(node_index, index, child) {
+            self.compressed_.remove(&(node_index, index));
+            let mut chunk = Chunk {
+                children: [HiddenLeaf::from_box_spec(0); CHUNK_LEN as usize]
+            };
+            self.compression_.decompress(pos, 1, HiddenLeaf::from_box_spec(id), &mut chunk);
+        }
+    }
+    /// Get a child of a node or of a leaf above depth 0 as if the leaf was split. The boxes of
+    /// compressed leafs, which can't be looked into, are reported as the leaf itself.
+    unsafe fn region_child(&self, parent: RegionParent, pos: na::Vector3<u32>, depth: u8, index: u16) -> Child {
+        match parent {
+            RegionParent::Node(node_index) => {
+                *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize)
+            },
+            RegionParent::Leaf(leaf) => leaf,
+            RegionParent::Compressed(leaf, id) => {
+                let child = self.compression_.peek(pos, depth + 1, HiddenLeaf::from_box_spec(id), index);
+                child.map_or(leaf, |child| *child.as_child())
+            },
+        }
+    }
+    /// Get a child of a node or of a leaf above depth 0 like region_child does together with the
+    /// region parent of its children, if it is a node or a compressed leaf.
+    unsafe fn region_child_parent(
+        &self, parent: RegionParent, pos: na::Vector3<u32>, depth: u8, index: u16
+    ) -> (Child, Option<RegionParent>) {
+        let child = self.region_child(parent, pos, depth, index);
+        if let RegionParent::Node(node_index) = parent {
+            if child.is_node() {
+                return (child, Some(RegionParent::Node(self.node_of(node_index, index, child))));
+            }
+            if let Some(id) = self.compressed_id(node_index, index, child) {
+                return (child, Some(RegionParent::Compressed(child, id)));
+            }
+        }
+        (child, None)
+    }
+    /// Counts the leafs above depth 0, which fill_region needs to split, because they are partially
+    /// within the region and not made of the leaf filled in. Without a leaf, the boxes within the
+    /// region may be set to anything, so all leafs overlapping the region are split down to depth
+    /// 0. The position is the position of the first box within the sub-cube of the parent and the
+    /// depth is the depth of its children.
+    unsafe fn num_fill_splits(
+        &self, parent: RegionParent, pos: na::Vector3<u32>, depth: u8,
+        min: na::Vector3<u32>, max: na::Vector3<u32>, leaf: Option<Leaf>
+    ) -> u32 {
+        let mut num_splits = 0u32;
+        let (lo, hi) = overlapping_children(pos, depth, min, max);
+        for z in lo.z..(hi.z + 1) {
+            for y in lo.y..(hi.y + 1) {
+                for x in lo.x..(hi.x + 1) {
+                    let index = ((x | (y << CHUNK_SIDE_LEN_LOG2) | (z << (2 * CHUNK_SIDE_LEN_LOG2))) as u16);
+                    let child_pos = child_pos(pos, depth, index);
+                    if depth == 0 || (leaf.is_some() && is_within(child_pos, depth, min, max)) {
+                        continue;
+                    }
+                    match self.region_child_parent(parent, pos, depth, index) {
+                        (_, Some(RegionParent::Node(child_node))) => {
+                            num_splits = num_splits.saturating_add(
+                                self.num_fill_splits(RegionParent::Node(child_node), child_pos, depth - 1, min, max, leaf)
+                            );
+                        },
+                        (_, Some(child_parent)) => {
+                            num_splits = num_splits.saturating_add(
+                                self.num_fill_splits(child_parent, child_pos, depth - 1, min, max, leaf).saturating_add(1)
+                            );
+                        },
+                        // Paged leafs can't be split, so no number of free nodes is enough.
+                        (child, None) => if child.is_paged() {
+                            return u32::MAX;
+                        } else if leaf.map_or(true, |leaf| child.ext_spec() != leaf.ext_spec()) {
+                            num_splits = num_splits.saturating_add(
+                                self.num_fill_splits(RegionParent::Leaf(child), child_pos, depth - 1, min, max, leaf).saturating_add(1)
+                            );
+                        },
+                    }
+                }
+            }
+        }
+        num_splits
+    }
+    /// Counts the compressed leafs within one box around an axis aligned box region from min to max
+    /// inclusive, which is within the world.
+    unsafe fn num_compressed_around(&self, min: na::Vector3<u32>, max: na::Vector3<u32>) -> u32 {
+        let world_max = ((1u64 << (self.depth_ * CHUNK_SIDE_LEN_LOG2)) - 1) as u32;
+        let zero = na::Vector3::new(0, 0, 0);
+        let inner_min = na::Vector3::new(min.x.saturating_add(1), min.y.saturating_add(1), min.z.saturating_add(1));
+        let inner_max = na::Vector3::new(max.x.saturating_sub(1), max.y.saturating_sub(1), max.z.saturating_sub(1));
+        // The neighbors across the boundary of the world are on its opposite side, so the region
+        // extended by one box is made of up to two ranges along each axis.
+        let mut ranges = [[(0u32, 0u32); 2]; 3];
+        let mut num_ranges = [1usize; 3];
+        for axis in 0..3 {
+            let lo = min[axis].saturating_sub(1);
+            let hi = max[axis].saturating_add(1).min(world_max);
+            ranges[axis][0] = (lo, hi);
+            if min[axis] == 0 && hi < world_max {
+                ranges[axis][1] = (world_max, world_max);
+                num_ranges[axis] = 2;
+            } else if max[axis] == world_max && lo > 0 {
+                ranges[axis][1] = (0, 0);
+                num_ranges[axis] = 2;
+            }
+        }
+        let mut num_compressed = 0u32;
+        for z in 0..num_ranges[2] {
+            for y in 0..num_ranges[1] {
+                for x in 0..num_ranges[0] {
+                    let outer_min = na::Vector3::new(ranges[0][x].0, ranges[1][y].0, ranges[2][z].0);
+                    let outer_max = na::Vector3::new(ranges[0][x].1, ranges[1][y].1, ranges[2][z].1);
+                    num_compressed = num_compressed.saturating_add(self.num_compressed_within_shell(
+                        RegionParent::Node(0), zero, self.depth_ - 1, outer_min, outer_max, inner_min, inner_max
+                    ));
+                }
+            }
+        }
+        num_compressed
+    }
+    /// Counts the compressed leafs, which overlap the region from min to max, but not the region
+    /// from inner_min to inner_max. The position is the position of the first box within the
+    /// sub-cube of the parent and the depth is the depth of its children.
+    unsafe fn num_compressed_within_shell(
+        &self, parent: RegionParent, pos: na::Vector3<u32>, depth: u8,
+        min: na::Vector3<u32>, max: na::Vector3<u32>,
+        inner_min: na::Vector3<u32>, inner_max: na::Vector3<u32>
+    ) -> u32 {
+        if depth == 0 {
+            return 0;
+        }
+        let mut num_compressed = 0;
+        let (lo, hi) = overlapping_children(pos, depth, min, max);
+        for z in lo.z..(hi.z + 1) {
+            for y in lo.y..(hi.y + 1) {
+                for x in lo.x..(hi.x + 1) {
+                    let index = ((x | (y << CHUNK_SIDE_LEN_LOG2) | (z << (2 * CHUNK_SIDE_LEN_LOG2))) as u16);
+                    let child_pos = child_pos(pos, depth, index);
+                    if is_within(child_pos, depth, inner_min, inner_max) {
+                        continue;
+                    }
+                    match self.region_child_parent(parent, pos, depth, index) {
+                        (_, Some(RegionParent::Node(child_node))) => {
+                            num_compressed += self.num_compressed_within_shell(
+                                RegionParent::Node(child_node), child_pos, depth - 1, min, max, inner_min, inner_max
+                            );
+                        },
+                        (_, Some(_)) => num_compressed += 1,
+                        (_, None) => {},
+                    }
+                }
+            }
+        }
+        num_compressed
+    }
+    /// Fills the children of a node overlapping a region with a leaf. Children entirely within the
+    /// region are replaced by the leaf, others are split and filled recursively. Nodes, which
+    /// become made of identical boxes, are replaced by a single leaf. The surface flags of solid
+    /// boxes are left cleared. Nodes at depth 0, which have been changed, are collected.
+    /// Returns OutOfNodes, if a leaf could not be split.
+    unsafe fn fill_node(
+        &mut self, node_index: u32, pos: na::Vector3<u32>, depth: u8,
+        min: na::Vector3<u32>, max: na::Vector3<u32>, leaf: Leaf, nodes: &mut Vec<u32>
+    ) -> Result<(), OutOfNodes> {
+        let new_child = Child::leaf_from_surface_ext_spec(!leaf.is_void() && !leaf.is_solid(), leaf.ext_spec());
+        let (lo, hi) = overlapping_children(pos, depth, min, max);
+        for z in lo.z..(hi.z + 1) {
+            for y in lo.y..(hi.y + 1) {
+                for x in lo.x..(hi.x + 1) {
+                    let index = ((x | (y << CHUNK_SIDE_LEN_LOG2) | (z << (2 * CHUNK_SIDE_LEN_LOG2))) as u16);
+                    let child_pos = child_pos(pos, depth, index);
+                    let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+                    if depth == 0 || is_within(child_pos, depth, min, max) {
+                        if child.is_node() {
+                            let child_node = self.node_of(node_index, index, child);
+                            self.replace_child(node_index, index, new_child);
+                            self.free_nodes_below(child_node, child_pos, depth - 1);
+                        } else {
+                            if depth == 1 {
+                                self.release_compressed(node_index, index, child_pos);
+                            }
+                            self.replace_child(node_index, index, new_child);
+                        }
+                        continue;
+                    }
+                    let child_node = if child.is_node() {
+                        self.node_of(node_index, index, child)
+                    } else if child.ext_spec() == leaf.ext_spec() && self.compressed_id(node_index, index, child).is_none() {
+                        // Already made of the leaf filled in.
+                        continue;
+                    } else {
+                        match self.split_leaf(node_index, index, depth, child_pos) {
+                            Some(child_node) => child_node,
+                            None => return Err(OutOfNodes),
+                        }
+                    };
+                    try!(self.fill_node(child_node, child_pos, depth - 1, min, max, leaf, nodes));
+                    if let Some(uniform_leaf) = self.uniform_leaf(child_node) {
+                        self.replace_child(node_index, index, uniform_leaf);
+                        self.free_node(child_node);
+                    }
+                }
+            }
+        }
+        if depth == 0 {
+            nodes.push(node_index);
+        }
+        Ok(())
+    }
+    /// Recalculates the surface flag of a single box like update_surface_around does for each box
+    /// of the neighborhood. Nodes at depth 0, which contain the box, are collected.
+    /// Returns OutOfNodes, if a compressed leaf could not be split.
+    unsafe fn update_surface_at(&mut self, pos: na::Vector3<u32>, nodes: &mut Vec<u32>) -> Result<(), OutOfNodes> {
+        let zero = na::Vector3::new(0, 0, 0);
+        let (mut node_index, mut index, mut depth) = self.neighbor_location(pos, zero);
+        let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+        if child.is_void() || child.is_paged() {
+            return Ok(());
+        }
+        let surface = self.is_surface_at(pos);
+        if surface && depth == 1 {
+            if self.compressed_id(node_index, index, child).is_some() {
+                node_index = match self.split_leaf(node_index, index, depth, sub_cube_pos(pos, depth)) {
+                    Some(new_node) => new_node,
+                    None => return Err(OutOfNodes),
+                };
+                index = child_index_at(pos, 0);
+                depth = 0;
+            }
+        }
+        if depth == 0 || surface {
+            self.set_child_surface(node_index, index, surface);
+        }
+        if depth == 0 {
+            nodes.push(node_index);
+        }
+        Ok(())
+    }
+    /// Get a number of free nodes, which is enough for any edits of the boxes within an axis aligned
+    /// box region from min to max inclusive, or u32::MAX, if the region overlaps paged leafs, which
+    /// can't be split. It covers splitting all leafs overlapping the region down to depth 0 and
+    /// the compressed leafs around the region, whose boxes may become exposed.
+    pub fn max_nodes_needed(&self, min: na::Vector3<u32>, max: na::Vector3<u32>) -> u32 {
+        unsafe {
+            let world_max = ((1u64 << (self.depth_ * CHUNK_SIDE_LEN_LOG2)) - 1) as u32;
+            let max = na::Vector3::new(max.x.min(world_max), max.y.min(world_max), max.z.min(world_max));
+            if min.x > max.x || min.y > max.y || min.z > max.z {
+                return 0;
+            }
+            let zero = na::Vector3::new(0, 0, 0);
+            self.num_fill_splits(RegionParent::Node(0), zero, self.depth_ - 1, min, max, None)
+                .saturating_add(self.num_compressed_around(min, max))
+        }
+    }
+    /// Fills an axis aligned box region from min to max inclusive with a leaf. Sub-cubes of the
+    /// tree entirely within the region are replaced by single leafs and the surface flags are only
+    /// recalculated along the boundary of the region. Either the region is filled or the tree is
+    /// left unchanged, if there are not enough free nodes available. Paged leafs entirely within
+    /// the region are replaced, paged leafs partially within it can't be split and the region is
+    /// rejected with OutOfNodes.
+    pub fn fill_region(&mut self, min: na::Vector3<u32>, max: na::Vector3<u32>, leaf: Leaf) -> Result<(), OutOfNodes> {
+        if leaf.is_paged() {
+            panic!("expected no paged leaf");
+        }
+        unsafe {
+            let world_max = ((1u64 << (self.depth_ * CHUNK_SIDE_LEN_LOG2)) - 1) as u32;
+            let max = na::Vector3::new(max.x.min(world_max), max.y.min(world_max), max.z.min(world_max));
+            if min.x > max.x || min.y > max.y || min.z > max.z {
+                return Ok(());
+            }
+            let zero = na::Vector3::new(0, 0, 0);
+            let mut num_nodes = self.num_fill_splits(RegionParent::Node(0), zero, self.depth_ - 1, min, max, Some(leaf));
+            // Boxes within compressed leafs around the region may become exposed.
+            if !leaf.is_solid() {
+                num_nodes = num_nodes.saturating_add(self.num_compressed_around(min, max));
+            }
+            if num_nodes > self.num_free_nodes_ {
+                return Err(OutOfNodes);
+            }
+            let mut nodes = Vec::new();
+            // The nodes needed have been counted above, so this only fails, if the count is off.
+            try!(self.fill_node(0, zero, self.depth_ - 1, min, max, leaf, &mut nodes));
+            // Only the boxes within one box of the boundary of the region may have changed their
+            // surface flags, the ones further inside are set correctly by fill_node.
+            let mask = world_max as i64;
+            for z in (min.z as i64 - 1)..(max.z as i64 + 2) {
+                for y in (min.y as i64 - 1)..(max.y as i64 + 2) {
+                    let inner =
+                        z > min.z as i64 && z < max.z as i64 &&
+                        y > min.y as i64 && y < max.y as i64;
+                    let mut x = min.x as i64 - 1;
+                    while x < max.x as i64 + 2 {
+                        try!(self.update_surface_at(na::Vector3::new((x & mask) as u32, (y & mask) as u32, (z & mask) as u32), &mut nodes));
+                        x = if inner && x == min.x as i64 { (max.x as i64).max(x + 1) } else { x + 1 };
+                    }
+                }
+            }
+            nodes.sort();
+            nodes.dedup();
+            self.compress_hidden_nodes(&nodes);
+            self.mark_dirty(min, max, true);
+            self.debug_check();
+            Ok(())
+        }
+    }
+    /// Iterates over the boxes within an axis aligned box region from min to max inclusive. Each
+    /// item is the position of the first box within a sub-cube, its depth and its leaf. Sub-cubes
+    /// above depth 0 made of identical boxes are only reported as a whole, when they are entirely
+    /// within the region, otherwise the boxes within the region are reported individually or as
+    /// smaller sub-cubes. Void boxes are skipped, unless include_void is set. Paged leafs, whose
+    /// boxes are unknown, are always skipped.
+    pub fn iter_region<'a>(&'a self, min: na::Vector3<u32>, max: na::Vector3<u32>, include_void: bool) -> RegionIter<'a, C> {
+        let world_max = ((1u64 << (self.depth_ * CHUNK_SIDE_LEN_LOG2)) - 1) as u32;
+        let max = na::Vector3::new(max.x.min(world_max), max.y.min(world_max), max.z.min(world_max));
+        let mut iter = RegionIter {
+            tree_: self,
+            min_: min,
+            max_: max,
+            include_void_: include_void,
+            frames_: Vec::new(),
+        };
+        if min.x <= max.x && min.y <= max.y && min.z <= max.z {
+            let frame = iter.frame(RegionParent::Node(0), na::Vector3::new(0, 0, 0), self.depth_ - 1);
+            iter.frames_.push(frame);
+        }
+        iter
+    }
+    /// Follows a ray through the world and returns the first non void box hit within a maximal
+    /// distance. The direction doesn't need to be normalized. Void leafs above depth 0 are crossed
+    /// as a whole, so empty space is skipped at the granularity of the sub-cubes of the tree.
+    /// Paged leafs, whose boxes are unknown, are crossed like void leafs. Nothing is hit by a ray
+    /// without direction, with an origin or direction, which isn't finite, or with a distance,
+    /// which is not a number.
+    pub fn raycast(&self, origin: na::Point3<f64>, direction: na::Vector3<f64>, max_dist: f64) -> Option<RayHit> {
+        unsafe {
+            // Scaling by the largest component first keeps tiny directions from vanishing.
+            let scale = direction.x.abs().max(direction.y.abs()).max(direction.z.abs());
+            let finite = (0..3).all(|axis| origin[axis].is_finite() && direction[axis].is_finite());
+            if !finite || scale == 0.0 || max_dist.is_nan() {
+                return None;
+            }
+            let direction = (direction / scale).normalize();
+            let world_side_len = (1u64 << (self.depth_ * CHUNK_SIDE_LEN_LOG2)) as f64;
+            // Clip the ray against the bounds of the world.
+            let mut t_min = 0.0f64;
+            let mut t_max = max_dist;
+            let mut normal = na::Vector3::new(0i32, 0, 0);
+            for axis in 0..3 {
+                if direction[axis] == 0.0 {
+                    if origin[axis] < 0.0 || origin[axis] >= world_side_len {
+                        return None;
+                    }
+                    continue;
+                }
+                let t0 = (0.0 - origin[axis]) / direction[axis];
+                let t1 = (world_side_len - origin[axis]) / direction[axis];
+                let (t_enter, t_exit) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+                if t_enter > t_min {
+                    t_min = t_enter;
+                    normal = na::Vector3::new(0, 0, 0);
+                    normal[axis] = if direction[axis] > 0.0 { -1 } else { 1 };
+                }
+                if t_exit < t_max {
+                    t_max = t_exit;
+                }
+            }
+            if t_min > t_max {
+                return None;
+            }
+            let mut t = t_min;
+            let mut pos = na::Vector3::new(0u32, 0, 0);
+            for axis in 0..3 {
+                let coord = (origin[axis] + direction[axis] * t).floor().max(0.0).min(world_side_len - 1.0);
+                pos[axis] = coord as u32;
+            }
+            loop {
+                let (node_index, index, depth) = self.neighbor_location(pos, na::Vector3::new(0, 0, 0));
+                let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+                if !child.is_void() && !(depth > 0 && child.is_paged()) {
+                    return Some(RayHit {
+                        pos: pos,
+                        leaf: Leaf::new(self.peek_compressed(node_index, index, pos, depth, child)),
+                        normal: normal,
+                        distance: t,
+                    });
+                }
+                // Leave the void or paged sub-cube through the face, which is hit first.
+                let min = sub_cube_pos(pos, depth);
+                let side_len = 1u64 << (depth * CHUNK_SIDE_LEN_LOG2);
+                let mut exit_axis = 0;
+                let mut t_exit = ::std::f64::INFINITY;
+                for axis in 0..3 {
+                    let bound = if direction[axis] > 0.0 {
+                        min[axis] as f64 + side_len as f64
+                    } else if direction[axis] < 0.0 {
+                        min[axis] as f64
+                    } else {
+                        continue;
+                    };
+                    let t_axis = (bound - origin[axis]) / direction[axis];
+                    if t_axis < t_exit {
+                        t_exit = t_axis;
+                        exit_axis = axis;
+                    }
+                }
+                if t_exit > max_dist {
+                    return None;
+                }
+                t = t.max(t_exit);
+                for axis in 0..3 {
+                    if axis == exit_axis {
+                        continue;
+                    }
+                    let coord = (origin[axis] + direction[axis] * t).floor()
+                        .max(min[axis] as f64)
+                        .min((min[axis] as u64 + side_len - 1) as f64);
+                    pos[axis] = coord as u32;
+                }
+                if direction[exit_axis] > 0.0 {
+                    let next = min[exit_axis] as u64 + side_len;
+                    if next as f64 >= world_side_len {
+                        return None;
+                    }
+                    pos[exit_axis] = next as u32;
+                } else {
+                    if min[exit_axis] == 0 {
+                        return None;
+                    }
+                    pos[exit_axis] = min[exit_axis] - 1;
+                }
+                normal = na::Vector3::new(0, 0, 0);
+                normal[exit_axis] = if direction[exit_axis] > 0.0 { -1 } else { 1 };
+            }
+        }
+    }
+    pub fn cast_view<Callback: FnMut(na::Vector3<u32>, Child)>(
+        &self,
+        origin: na::Point3<f64>, planes: [na::Vector3<f64>; 4], dist: f64,
+        callback: &mut Callback
+    ) {
+        unsafe {
+            let (deltas, dists) = self.view_planes(origin, planes, dist);
+            self.cast_view_children(origin, deltas, dists, None, &ViewOptions::default(), &mut |pos, _, child, _| callback(pos, child));
+        }
+    }
+    /// Like cast_view, but the children of the root node are traversed by num_threads threads.
+    /// The boxes are gathered per child of the root node and reported afterwards on the calling
+    /// thread in the same order as by cast_view.
+    pub fn cast_view_parallel<Callback: FnMut(na::Vector3<u32>, Child)>(
+        &self,
+        origin: na::Point3<f64>, planes: [na::Vector3<f64>; 4], dist: f64, num_threads: usize,
+        callback: &mut Callback
+    ) where C: Sync {
+        let options = ViewOptions {
+            num_threads: num_threads,
+            .. Default::default()
+        };
+        self.cast_view_with(origin, planes, dist, &options, &mut |pos, _, child, _| callback(pos, child));
+    }
+    /// Like cast_view, but with options. The callback gets the position of the first box within
+    /// the sub-cube of a reported leaf, its depth, the leaf and the mask of its exposed faces. Only
+    /// coarse boxes have a depth above 0 and all their faces are considered exposed.
+    pub fn cast_view_with<Callback: FnMut(na::Vector3<u32>, u8, Child, u8)>(
+        &self,
+        origin: na::Point3<f64>, planes: [na::Vector3<f64>; 4], dist: f64, options: &ViewOptions,
+        callback: &mut Callback
+    ) where C: Sync {
+        let (deltas, dists) = self.view_planes(origin, planes, dist);
+        let root_pos = na::Vector3::new(0, 0, 0);
+        if let Some(occlusion) = options.occlusion {
+            let mut depth_buffer = DepthBuffer::new(origin, planes, occlusion.resolution);
+            unsafe {
+                self.cast_view_ordered(
+                    origin, 0, root_pos, self.depth_ - 1, deltas, dists, options, ViewOrder::FrontToBack,
+                    Some(&mut depth_buffer), callback
+                );
+            }
+            return;
+        }
+        if options.order != ViewOrder::Index {
+            if options.num_threads <= 1 {
+                unsafe {
+                    self.cast_view_ordered(
+                        origin, 0, root_pos, self.depth_ - 1, deltas, dists, options, options.order,
+                        None, callback
+                    );
+                }
+                return;
+            }
+            let offsets = Self::child_order(origin, root_pos, self.depth_ - 1, options.order);
+            self.cast_view_jobs(offsets.len(), options.num_threads, |index, boxes| {
+                unsafe {
+                    self.cast_view_ordered_child(
+                        origin, 0, root_pos, self.depth_ - 1, offsets[index], deltas, dists,
+                        options, options.order, None,
+                        &mut |pos, depth, child, faces| boxes.push((pos, depth, child, faces))
+                    );
+                }
+            }, callback);
+            return;
+        }
+        if options.num_threads <= 1 {
+            unsafe { self.cast_view_children(origin, deltas, dists, None, options, callback); }
+            return;
+        }
+        self.cast_view_jobs(CHUNK_LEN as usize, options.num_threads, |index, boxes| {
+            // Leafs, which are not part of the surface, like the many void children of the root
+            // node, don't report any box.
+            let child = self.chunks_[0][index];
+            if !child.is_node() && (!child.is_surface() || child.is_paged()) {
+                return;
+            }
+            unsafe {
+                self.cast_view_children(
+                    origin, deltas, dists, Some(index as u16), options,
+                    &mut |pos, depth, child, faces| boxes.push((pos, depth, child, faces))
+                );
+            }
+        }, callback);
+    }
+    /// Calls cast for the indices up to num_jobs by num_threads threads of the tree, which gathers
+    /// the boxes per index, and reports them afterwards on the calling thread in the order of the
+    /// indices.
+    fn cast_view_jobs<Cast, Callback>(&self, num_jobs: usize, num_threads: usize, cast: Cast, callback: &mut Callback)
+        where Cast: Fn(usize, &mut Vec<(na::Vector3<u32>, u8, Child, u8)>) + Sync,
+              Callback: FnMut(na::Vector3<u32>, u8, Child, u8),
+              C: Sync
+    {
+        let next_index = AtomicUsize::new(0);
+        let results: Mutex<Vec<(usize, Vec<(na::Vector3<u32>, u8, Child, u8)>)>> = Mutex::new(Vec::new());
+        // A panic of a thread is raised after the workers have been unlocked, so the lock isn't
+        // poisoned and later views still run in parallel.
+        let ok = self.workers_.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).run(num_threads, &|| {
+            loop {
+                let index = next_index.fetch_add(1, Ordering::Relaxed);
+                if index >= num_jobs {
+                    return;
+                }
+                let mut boxes = Vec::new();
+                cast(index, &mut boxes);
+                if !boxes.is_empty() {
+                    results.lock().unwrap().push((index, boxes));
+                }
+            }
+        });
+        if !ok {
+            panic!("a thread of cast_view_with panicked");
+        }
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|&(index, _)| index);
+        for &(_, ref boxes) in results.iter() {
+            for &(pos, depth, child, faces) in boxes.iter() {
+                callback(pos, depth, child, faces);
+            }
+        }
+    }
+    /// Get the fixed point deltas of the four view planes and the plane at the distance of the
+    /// view per box at the depth of the children of the root node and their distances to the
+    /// corner of the first child of the root node, which is the farthest inside.
+    fn view_planes(&self, origin: na::Point3<f64>, planes: [na::Vector3<f64>; 4], dist: f64) -> ([na::Vector3<i64>; 5], [i64; 5]) {
+        // (x-ox)*px + (y-oy)*py + (z-oz)*pz > 0
+        let mut deltas = [na::Vector3::new(0i64, 0, 0); 5];
+        let mut dists = [0i64; 5];
+        for i in 0..4 {
+            deltas[i].x = (planes[i].x * ((1 << 28) as f64)).ceil() as i64;
+            deltas[i].y = (planes[i].y * ((1 << 28) as f64)).ceil() as i64;
+            deltas[i].z = (planes[i].z * ((1 << 28) as f64)).ceil() as i64;
+            dists[i] = (
+                (-origin.x * (deltas[i].x as f64)).ceil() as i64
+                +
+                (-origin.y * (deltas[i].y as f64)).ceil() as i64
+                +
+                (-origin.z * (deltas[i].z as f64)).ceil() as i64
+            );
+            deltas[i].x <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
+            deltas[i].y <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
+            deltas[i].z <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
+        }
+        let mut plane4 = na::Vector3::new(0.0f64, 0.0, 0.0);
+        for i in 0..4 {
+            plane4 -= planes[i];
+        }
+        plane4 = plane4.normalize();
+        deltas[4].x = (plane4.x * ((1 << 28) as f64)).ceil() as i64;
+        deltas[4].y = (plane4.y * ((1 << 28) as f64)).ceil() as i64;
+        deltas[4].z = (plane4.z * ((1 << 28) as f64)).ceil() as i64;
+        dists[4] = (
+            (-origin.x * (deltas[4].x as f64)).ceil() as i64
+            +
+            (-origin.y * (deltas[4].y as f64)).ceil() as i64
+            +
+            (-origin.z * (deltas[4].z as f64)).ceil() as i64
+            +
+            ((dist * ((1 << 28) as f64)).ceil() as i64)
+        );
+        deltas[4].x <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
+        deltas[4].y <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
+        deltas[4].z <<= (self.depth_ - 1) * CHUNK_SIDE_LEN_LOG2;
+
+        for i in 0..5 {
+            if deltas[i].x > 0 { dists[i] += deltas[i].x; }
+            if deltas[i].y > 0 { dists[i] += deltas[i].y; }
+            if deltas[i].z > 0 { dists[i] += deltas[i].z; }
+        }
+        (deltas, dists)
+    }
+    /// Whether a sub-cube at a specific depth above 0 appears small enough to be reported as a
+    /// coarse box. The position is the position of the sub-cube in units of its side length.
+    fn is_coarse(&self, origin: na::Point3<f64>, pos: na::Vector3<u32>, depth: u8, lod: &Lod) -> bool {
+        if depth == 0 {
+            return false;
+        }
+        let side_len = (1u64 << (depth * CHUNK_SIDE_LEN_LOG2)) as f64;
+        let mut dist_sq = 0.0;
+        for axis in 0..3 {
+            let min = pos[axis] as f64 * side_len;
+            let d = if origin[axis] < min {
+                min - origin[axis]
+            } else if origin[axis] > min + side_len {
+                origin[axis] - min - side_len
+            } else {
+                0.0
+            };
+            dist_sq += d * d;
+        }
+        side_len * lod.pixels_per_unit < lod.min_pixels * dist_sq.sqrt()
+    }
+    /// Get a leaf, which is part of the surface, within the descendants of a node to represent
+    /// the node as a coarse box, or None if none of them is part of the surface. Only a single path
+    /// of nodes with surface is followed down to a node with leaf children, which are part of the
+    /// surface.
+    unsafe fn representative_leaf(&self, mut node_index: u32) -> Option<Child> {
+        loop {
+            let info = self.infos_.get_unchecked(node_index as usize);
+            let chunk = self.chunks_.get_unchecked(node_index as usize);
+            if info.num_surface_children != 0 {
+                // Prefer the more common solidity among the children.
+                let solid = info.num_solid_children >= info.num_transparent_children;
+                let mut any = None;
+                for child in chunk.iter() {
+                    if !child.is_node() && child.is_surface() && !child.is_paged() {
+                        if child.is_solid() == solid {
+                            return Some(*child);
+                        }
+                        any = Some(*child);
+                    }
+                }
+                return any;
+            }
+            if info.num_surface_nodes == 0 {
+                return None;
+            }
+            let mut surface_node = None;
+            for index in 0..CHUNK_LEN {
+                let child = *chunk.get_unchecked(index as usize);
+                if child.is_node() {
+                    let child_node = self.node_of(node_index, index, child);
+                    if self.infos_.get_unchecked(child_node as usize).has_surface() {
+                        surface_node = Some(child_node);
+                        break;
+                    }
+                }
+            }
+            node_index = match surface_node {
+                Some(child_node) => child_node,
+                None => return None,
+            };
+        }
+    }
+    /// Get the order, in which a number of consecutive cubes are traversed along an axis. The
+    /// position is the position of the first cube in units of their side length.
+    fn axis_order(origin: f64, pos: u32, side_len: f64, len: u32, order: ViewOrder) -> Vec<u32> {
+        let mut indices: Vec<u32> = (0..len).collect();
+        if order != ViewOrder::Index {
+            indices.sort_by(|&a, &b| {
+                let dist = |i: u32| {
+                    let min = (pos + i) as f64 * side_len;
+                    (min - origin).max(origin - min - side_len).max(0.0)
+                };
+                dist(a).partial_cmp(&dist(b)).unwrap()
+            });
+            if order == ViewOrder::BackToFront {
+                indices.reverse();
+            }
+        }
+        indices
+    }
+    /// Get the offsets of the children of a node in the order, in which they are traversed. A
+    /// cube can only hide cubes after it front to back, as along each axis it is at least as
+    /// close to the origin. The position is the position of the first child in units of the side
+    /// length of the children and the depth is their depth.
+    fn child_order(origin: na::Point3<f64>, pos: na::Vector3<u32>, depth: u8, order: ViewOrder) -> Vec<na::Vector3<u32>> {
+        let side_len = (1u64 << (depth * CHUNK_SIDE_LEN_LOG2)) as f64;
+        let xs = Self::axis_order(origin.x, pos.x, side_len, CHUNK_SIDE_LEN as u32, order);
+        let ys = Self::axis_order(origin.y, pos.y, side_len, CHUNK_SIDE_LEN as u32, order);
+        let zs = Self::axis_order(origin.z, pos.z, side_len, CHUNK_SIDE_LEN as u32, order);
+        let mut offsets = Vec::with_capacity(CHUNK_LEN as usize);
+        for &z in zs.iter() {
+            for &y in ys.iter() {
+                for &x in xs.iter() {
+                    offsets.push(na::Vector3::new(x, y, z));
+                }
+            }
+        }
+        offsets
+    }
+    /// Traverses the children of a node for cast_view in the order relative to the origin and
+    /// culls the ones, which are hidden behind the boxes reported before, if there is a depth
+    /// buffer. The position is the position of the first child in units of the side length of the
+    /// children and the depth is their depth. The deltas and distances are the ones of the first
+    /// child.
+    unsafe fn cast_view_ordered<Callback: FnMut(na::Vector3<u32>, u8, Child, u8)>(
+        &self,
+        origin: na::Point3<f64>, node_index: u32, pos: na::Vector3<u32>, depth: u8,
+        deltas: [na::Vector3<i64>; 5], dists: [i64; 5], options: &ViewOptions, order: ViewOrder,
+        mut depth_buffer: Option<&mut DepthBuffer>,
+        callback: &mut Callback
+    ) {
+        for &offset in Self::child_order(origin, pos, depth, order).iter() {
+            self.cast_view_ordered_child(
+                origin, node_index, pos, depth, offset, deltas, dists, options, order,
+                depth_buffer.as_mut().map(|buffer| &mut **buffer), callback
+            );
+        }
+    }
+    /// Traverses a single child of a node for cast_view_ordered. The offset is the position of
+    /// the child within the node.
+    unsafe fn cast_view_ordered_child<Callback: FnMut(na::Vector3<u32>, u8, Child, u8)>(
+        &self,
+        origin: na::Point3<f64>, node_index: u32, pos: na::Vector3<u32>, depth: u8, offset: na::Vector3<u32>,
+        deltas: [na::Vector3<i64>; 5], dists: [i64; 5], options: &ViewOptions, order: ViewOrder,
+        depth_buffer: Option<&mut DepthBuffer>,
+        callback: &mut Callback
+    ) {
+        let mut child_dists = dists;
+        for i in 0..5 {
+            child_dists[i] += deltas[i].x * (offset.x as i64) + deltas[i].y * (offset.y as i64) + deltas[i].z * (offset.z as i64);
+            if child_dists[i] < 0 {
+                return;
+            }
+        }
+        let index = (offset.x | (offset.y << CHUNK_SIDE_LEN_LOG2) | (offset.z << (2 * CHUNK_SIDE_LEN_LOG2))) as u16;
+        let child = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+        if !child.is_node() && (!child.is_surface() || child.is_paged()) {
+            return;
+        }
+        let side_len_log2 = (depth * CHUNK_SIDE_LEN_LOG2) as u32;
+        let child_pos = pos + offset;
+        let box_pos = na::Vector3::new(
+            child_pos.x << side_len_log2, child_pos.y << side_len_log2, child_pos.z << side_len_log2
+        );
+        if let Some(ref buffer) = depth_buffer {
+            if buffer.is_hidden(box_pos, depth) {
+                return;
+            }
+        }
+        let coarse = match options.lod {
+            Some(ref lod) => self.is_coarse(origin, child_pos, depth, lod),
+            None => false,
+        };
+        if coarse && child.is_node() {
+            if let Some(leaf) = self.representative_leaf(self.node_of(node_index, index, child)) {
+                callback(box_pos, depth, leaf, ALL_FACES_MASK);
+            }
+        } else if child.is_node() {
+            let mut child_deltas = deltas;
+            for i in 0..5 {
+                if deltas[i].x > 0 { child_dists[i] -= deltas[i].x; }
+                if deltas[i].y > 0 { child_dists[i] -= deltas[i].y; }
+                if deltas[i].z > 0 { child_dists[i] -= deltas[i].z; }
+                child_deltas[i].x /= CHUNK_SIDE_LEN as i64;
+                child_deltas[i].y /= CHUNK_SIDE_LEN as i64;
+                child_deltas[i].z /= CHUNK_SIDE_LEN as i64;
+                if child_deltas[i].x > 0 { child_dists[i] += child_deltas[i].x; }
+                if child_deltas[i].y > 0 { child_dists[i] += child_deltas[i].y; }
+                if child_deltas[i].z > 0 { child_dists[i] += child_deltas[i].z; }
+            }
+            self.cast_view_ordered(
+                origin, self.node_of(node_index, index, child), child_pos * (CHUNK_SIDE_LEN as u32),
+                depth - 1, child_deltas, child_dists, options, order, depth_buffer, callback
+            );
+        } else {
+            if depth == 0 {
+                callback(box_pos, 0, child, self.exposed_faces_in(node_index, index, box_pos, child));
+            } else if coarse {
+                callback(box_pos, depth, child, ALL_FACES_MASK);
+            } else {
+                self.cast_sub_cube(
+                    origin, child_pos, depth, child, deltas, child_dists, order,
+                    &mut |pos, child, faces| callback(pos, 0, child, faces)
+                );
+            }
+            if child.is_solid() {
+                if let Some(buffer) = depth_buffer {
+                    buffer.add_occluder(box_pos, depth);
+                }
+            }
+        }
+    }
+    /// Traverses the tree for cast_view, either entirely or only the sub-cube of a single child of
+    /// the root node.
+    unsafe fn cast_view_children<Callback: FnMut(na::Vector3<u32>, u8, Child, u8)>(
+        &self,
+        origin: na::Point3<f64>, mut deltas: [na::Vector3<i64>; 5], mut dists: [i64; 5], root_index: Option<u16>,
+        options: &ViewOptions,
+        callback: &mut Callback
+    ) {
+        let mut depth: u8 = (self.depth_ as u8) - 1;
+        let mut chunk: u32 = 0;
+        let mut pos = na::Vector3::new(0u32, 0, 0);
+        let mut index: u16 = 0;
+        if let Some(root_index) = root_index {
+            index = root_index;
+            pos = na::Vector3::new(
+                ((root_index >> (0 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK,
+                ((root_index >> (1 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK,
+                ((root_index >> (2 * CHUNK_SIDE_LEN_LOG2)) as u32) & CHUNK_SIDE_MASK,
+            );
+            for i in 0..5 {
+                dists[i] += deltas[i].x * (pos.x as i64) + deltas[i].y * (pos.y as i64) + deltas[i].z * (pos.z as i64);
+            }
+        }
+        loop {
+            if dists[0] >= 0 && dists[1] >= 0 && dists[2] >= 0 && dists[3] >= 0 && dists[4] >= 0 {
+                let mut child = *self.chunks_.get_unchecked(chunk as usize).get_unchecked(index as usize);
+                if child.is_paged() {
+                    // Paged leafs aren't reported, like leafs, which are not part of the surface.
+                    child = Child::void();
+                }
+                let coarse = match options.lod {
+                    Some(ref lod) => self.is_coarse(origin, pos, depth, lod),
+                    None => false,
+                };
+                let side_len_log2 = (depth * CHUNK_SIDE_LEN_LOG2) as u32;
+                if coarse && (child.is_node() || child.is_surface()) {
+                    let leaf = if child.is_node() {
+                        self.representative_leaf(self.node_of(chunk, index, child))
+                    } else {
+                        Some(child)
+                    };
+                    if let Some(leaf) = leaf {
+                        callback(
+                            na::Vector3::new(pos.x << side_len_log2, pos.y << side_len_log2, pos.z << side_len_log2),
+                            depth, leaf, ALL_FACES_MASK
+                        );
+                    }
+                } else if child.is_node() {
+                    depth -= 1;
+                    chunk = self.node_of(chunk, index, child);
+                    index = 0;
+                    pos.x *= CHUNK_SIDE_LEN as u32;
+                    pos.y *= CHUNK_SIDE_LEN as u32;
+                    pos.z *= CHUNK_SIDE_LEN as u32;
+                    for i in 0..5 {
+                        if deltas[i].x > 0 { dists[i] -= deltas[i].x; }
+                        if deltas[i].y > 0 { dists[i] -= deltas[i].y; }
+                        if deltas[i].z > 0 { dists[i] -= deltas[i].z; }
+                    }
+                    for i in 0..5 {
+                        deltas[i].x /= CHUNK_SIDE_LEN as i64;
+                        deltas[i].y /= CHUNK_SIDE_LEN as i64;
+                        deltas[i].z /= CHUNK_SIDE_LEN as i64;
+                    }
+                    for i in 0..5 {
+                        if deltas[i].x > 0 { dists[i] += deltas[i].x; }
+                        if deltas[i].y > 0 { dists[i] += deltas[i].y; }
+                        if deltas[i].z > 0 { dists[i] += deltas[i].z; }
+                    }
+                    continue;
+                } else if child.is_surface() {
+                    if depth == 0 {
+                        callback(pos, 0, child, self.exposed_faces_in(chunk, index, pos, child));
+                    } else {
+                        self.cast_sub_cube(
+                            origin, pos, depth, child, deltas, dists, ViewOrder::Index,
+                            &mut |pos, child, faces| callback(pos, 0, child, faces)
+                        );
+                    }
+                }
+            }
+            loop {
+                if root_index.is_some() && depth == self.depth_ - 1 {
+                    return;
+                }
+                for i in 0..5 {
+                    dists[i] += deltas[i].x;
+                }
+                if (pos.x % (CHUNK_SIDE_LEN as u32)) != ((CHUNK_SIDE_LEN - 1) as u32) {
+                    pos.x += 1;
+                } else {
+                    pos.x = (pos.x / (CHUNK_SIDE_LEN as u32)) * (CHUNK_SIDE_LEN as u32);
+                    for i in 0..5 {
+                        dists[i] -= deltas[i].x * (CHUNK_SIDE_LEN as i64);
+                    }
+                    for i in 0..5 {
+                        dists[i] += deltas[i].y;
+                    }
+                    if (pos.y % (CHUNK_SIDE_LEN as u32)) != ((CHUNK_SIDE_LEN - 1) as u32) {
+                        pos.y += 1;
+                    } else {
+                        pos.y = (pos.y / (CHUNK_SIDE_LEN as u32)) * (CHUNK_SIDE_LEN as u32);
+                        for i in 0..5 {
+                            dists[i] -= deltas[i].y * (CHUNK_SIDE_LEN as i64);
+                        }
+                        for i in 0..5 {
+                            dists[i] += deltas[i].z;
+                        }
+                        if (pos.z % (CHUNK_SIDE_LEN as u32)) != ((CHUNK_SIDE_LEN - 1) as u32) {
+                            pos.z += 1;
+                        } else {
+                            depth += 1;
+                            if depth == (self.depth_ as u8) {
+                                return;
+                            }
+                            pos.z = (pos.z / (CHUNK_SIDE_LEN as u32)) * (CHUNK_SIDE_LEN as u32);
+                            for i in 0..5 {
+                                dists[i] -= deltas[i].z * (CHUNK_SIDE_LEN as i64);
+                            }
+                            chunk = self.infos_.get_unchecked(chunk as usize).parent_index;
+                            pos.x /= CHUNK_SIDE_LEN as u32;
+                            pos.y /= CHUNK_SIDE_LEN as u32;
+                            pos.z /= CHUNK_SIDE_LEN as u32;
+                            for i in 0..5 {
+                                if deltas[i].x > 0 { dists[i] -= deltas[i].x; }
+                                if deltas[i].y > 0 { dists[i] -= deltas[i].y; }
+                                if deltas[i].z > 0 { dists[i] -= deltas[i].z; }
+                            }
+                            for i in 0..5 {
+                                deltas[i].x *= CHUNK_SIDE_LEN as i64;
+                                deltas[i].y *= CHUNK_SIDE_LEN as i64;
+                                deltas[i].z *= CHUNK_SIDE_LEN as i64;
+                            }
+                            for i in 0..5 {
+                                if deltas[i].x > 0 { dists[i] += deltas[i].x; }
+                                if deltas[i].y > 0 { dists[i] += deltas[i].y; }
+                                if deltas[i].z > 0 { dists[i] += deltas[i].z; }
+                            }
+                            index = (
+                                ((pos.x % (CHUNK_SIDE_LEN as u32)) * 1)
+                                |
+                                ((pos.y % (CHUNK_SIDE_LEN as u32)) * (CHUNK_SIDE_LEN as u32))
+                                |
+                                ((pos.z % (CHUNK_SIDE_LEN as u32)) * (((CHUNK_SIDE_LEN as u16)*(CHUNK_SIDE_LEN as u16)) as u32))
+                            ) as u16;
+                            continue;
+                        }
+                    }
+                }
+                index += 1;
+                break;
+            }
+        }
+    }
+    /// Calls the callback for the boxes of the sub-cube of a leaf above depth 0, which are part of
+    /// the surface and within the view, together with their exposed faces. Only the boxes at the
+    /// boundary of the sub-cube are reported, since the ones further inside are surrounded by boxes
+    /// of the same leaf, so they are neither part of the surface nor have exposed faces. Slabs and
+    /// rows of boxes are culled against the view planes. The position is the position of the
+    /// sub-cube in units of its side length and the deltas and distances of the view planes are
+    /// the ones of the sub-cube.
+    unsafe fn cast_sub_cube<Callback: FnMut(na::Vector3<u32>, Child, u8)>(
+        &self,
+        origin: na::Point3<f64>, pos: na::Vector3<u32>, depth: u8, leaf: Child,
+        deltas: [na::Vector3<i64>; 5], dists: [i64; 5], order: ViewOrder,
+        callback: &mut Callback
+    ) {
+        let side_len_log2 = (depth * CHUNK_SIDE_LEN_LOG2) as u32;
+        let side_len = 1u32 << side_len_log2;
+        let min = na::Vector3::new(pos.x << side_len_log2, pos.y << side_len_log2, pos.z << side_len_log2);
+        // Deltas of the view planes per box and their distances to the corner of the first box of
+        // the sub-cube, which is the farthest inside.
+        let mut box_deltas = deltas;
+        let mut box_dists = dists;
+        for i in 0..5 {
+            for axis in 0..3 {
+                if deltas[i][axis] > 0 { box_dists[i] -= deltas[i][axis]; }
+                box_deltas[i][axis] = deltas[i][axis] >> side_len_log2;
+                if box_deltas[i][axis] > 0 { box_dists[i] += box_deltas[i][axis]; }
+            }
+        }
+        let xs = Self::axis_order(origin.x, min.x, 1.0, side_len, order);
+        let ys = Self::axis_order(origin.y, min.y, 1.0, side_len, order);
+        let zs = Self::axis_order(origin.z, min.z, 1.0, side_len, order);
+        // Rows within the sub-cube only have boxes at their ends, which are at its boundary.
+        let ends = if xs[0] == 0 || xs[xs.len() - 1] == side_len - 1 {
+            [0, side_len - 1]
+        } else {
+            [side_len - 1, 0]
+        };
+        let mut leafs = LeafCache::new();
+        for &z in zs.iter() {
+            let mut slab_dists = box_dists;
+            let mut visible = true;
+            for i in 0..5 {
+                slab_dists[i] += box_deltas[i].z * (z as i64);
+                let extent = box_deltas[i].x.max(0) + box_deltas[i].y.max(0);
+                if slab_dists[i] + extent * ((side_len - 1) as i64) < 0 {
+                    visible = false;
+                }
+            }
+            if !visible {
+                continue;
+            }
+            for &y in ys.iter() {
+                let mut row_dists = slab_dists;
+                for i in 0..5 {
+                    row_dists[i] += box_deltas[i].y * (y as i64);
+                }
+                let (lo, hi) = match Self::row_range(row_dists, box_deltas, side_len) {
+                    Some(range) => range,
+                    None => continue,
+                };
+                let inner = z != 0 && z != side_len - 1 && y != 0 && y != side_len - 1;
+                let row = if inner { &ends[..] } else { &xs[..] };
+                for &x in row.iter() {
+                    if x < lo || x > hi {
+                        continue;
+                    }
+                    let box_pos = na::Vector3::new(min.x + x, min.y + y, min.z + z);
+                    if !leaf.is_solid() || self.is_surface_within(box_pos, min, side_len, &mut leafs) {
+                        callback(box_pos, leaf, self.exposed_faces_within(box_pos, min, side_len, leaf, &mut leafs));
+                    }
+                }
+            }
+        }
+    }
+    /// Get the range of the boxes of a row in x direction, which are within the view, given the
+    /// distances of the view planes to the corner of its first box, which is the farthest inside,
+    /// or None if none of them is.
+    fn row_range(dists: [i64; 5], deltas: [na::Vector3<i64>; 5], len: u32) -> Option<(u32, u32)> {
+        let (mut lo, mut hi) = (0i64, (len - 1) as i64);
+        for i in 0..5 {
+            let (dist, delta) = (dists[i], deltas[i].x);
+            if delta > 0 {
+                if dist < 0 {
+                    lo = lo.max((-dist + delta - 1) / delta);
+                }
+            } else if dist < 0 {
+                return None;
+            } else if delta < 0 {
+                hi = hi.min(dist / -delta);
+            }
+        }
+        if lo > hi {
+            return None;
+        }
+        Some((lo as u32, hi as u32))
+    }
+    /// Like is_surface_at for a box of a solid leaf, whose sub-cube starts at a position and has a
+    /// side length. The boxes of the neighborhood within the sub-cube are solid, so only the others
+    /// are looked up.
+    unsafe fn is_surface_within(&self, pos: na::Vector3<u32>, min: na::Vector3<u32>, side_len: u32, leafs: &mut LeafCache) -> bool {
+        for z in 0..3u32 {
+            for &(x, y) in [(1u32, 0u32), (0, 1), (1, 1), (2, 1), (1, 2)].iter() {
+                let d = na::Vector3::new(x.wrapping_sub(1), y.wrapping_sub(1), z.wrapping_sub(1));
+                let neighbor_pos = self.neighbor_pos(pos, d);
+                if (0..3).all(|axis| neighbor_pos[axis].wrapping_sub(min[axis]) < side_len) {
+                    continue;
+                }
+                if !self.cached_leaf(neighbor_pos, leafs).is_opaque() {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    /// Like exposed_faces_at for a box of a leaf, whose sub-cube starts at a position and has a
+    /// side length. The faces towards the boxes within the sub-cube are not exposed, so only the
+    /// neighbors outside of it are looked up.
+    unsafe fn exposed_faces_within(
+        &self,
+        pos: na::Vector3<u32>, min: na::Vector3<u32>, side_len: u32, leaf: Child, leafs: &mut LeafCache
+    ) -> u8 {
+        let mut mask = 0;
+        for face in 0..6u8 {
+            let axis = (face / 2) as usize;
+            let inside = if (face & 1) != 0 {
+                pos[axis] - min[axis] != side_len - 1
+            } else {
+                pos[axis] != min[axis]
+            };
+            if inside {
+                continue;
+            }
+            let mut dir = na::Vector3::new(0u32, 0, 0);
+            dir[axis] = if (face & 1) != 0 { 1 } else { !0 };
+            let neighbor = self.cached_leaf(self.neighbor_pos(pos, dir), leafs);
+            if !neighbor.is_opaque() && neighbor.ext_spec() != leaf.ext_spec() {
+                mask |= 1 << face;
+            }
+        }
+        mask
+    }
+    /// Get the leaf containing the box at a position, which is looked up in a leaf cache first.
+    unsafe fn cached_leaf(&self, pos: na::Vector3<u32>, leafs: &mut LeafCache) -> Child {
+        for &(min, depth, leaf) in leafs.leafs.iter() {
+            if sub_cube_pos(pos, depth) == min {
+                return leaf;
+            }
+        }
+        let (node_index, index, depth) = self.neighbor_location(pos, na::Vector3::new(0, 0, 0));
+        let leaf = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+        let entry = (sub_cube_pos(pos, depth), depth, leaf);
+        if leafs.leafs.len() < LEAF_CACHE_LEN {
+            leafs.leafs.push(entry);
+        } else {
+            leafs.leafs[leafs.next] = entry;
+            leafs.next = (leafs.next + 1) % LEAF_CACHE_LEN;
+        }
+        leaf
+    }
+    /// Walks the entire tree and gathers memory and occupancy statistics.
+    pub fn stats(&self) -> Stats {
+        let node_size = size_of::<Chunk<Child>>() + size_of::<Info>();
+        let mut stats = Stats {
+            num_nodes_per_depth: vec![0; self.depth_ as usize + 1],
+            num_void_leafs: 0,
+            num_solid_leafs: 0,
+            num_transparent_leafs: 0,
+            num_surface_leafs: 0,
+            num_compressed_leafs: 0,
+            num_paged_leafs: 0,
+            ext_spec_histogram: BTreeMap::new(),
+            bytes_used: 0,
+            bytes_allocated:
+                self.chunks_.capacity() * size_of::<Chunk<Child>>() +
+                self.infos_.capacity() * size_of::<Info>(),
+            bytes_max: self.max_nodes_ as usize * node_size,
+            max_nodes: self.max_nodes_,
+            num_free_nodes: self.num_free_nodes_,
+            num_free_list_nodes: 0,
+            num_free_runs: 0,
+        };
+        // Nodes with the depth of their children.
+        let mut nodes = vec![(0u32, self.depth_ - 1)];
+        while let Some((node_index, depth)) = nodes.pop() {
+            stats.num_nodes_per_depth[depth as usize + 1] += 1;
+            for index in 0..CHUNK_LEN {
+                let child = self.chunks_[node_index as usize][index as usize];
+                if child.is_node() {
+                    nodes.push((self.child_node_index(node_index, index), depth - 1));
+                    continue;
+                }
+                if depth > 0 && child.is_paged() {
+                    stats.num_paged_leafs += 1;
+                    continue;
+                }
+                if child.is_surface() {
+                    stats.num_surface_leafs += 1;
+                }
+                if child.is_void() {
+                    stats.num_void_leafs += 1;
+                    continue;
+                }
+                if depth == 1 && self.compressed_.contains_key(&(node_index, index)) {
+                    stats.num_compressed_leafs += 1;
+                } else {
+                    if child.is_solid() {
+                        stats.num_solid_leafs += 1;
+                    } else {
+                        stats.num_transparent_leafs += 1;
+                    }
+                    *stats.ext_spec_histogram.entry(child.ext_spec()).or_insert(0) += 1;
+                }
+            }
+        }
+        stats.bytes_used = stats.num_nodes_used() as usize * node_size;
+        let mut free_nodes = Vec::new();
+        let mut node_index = self.first_free_node_;
+        while node_index != 0 {
+            free_nodes.push(node_index);
+            let chunk = &self.chunks_[node_index as usize];
+            node_index = (chunk[0].value_ as u32) | ((chunk[1].value_ as u32) << 16);
+        }
+        free_nodes.sort();
+        stats.num_free_list_nodes = free_nodes.len() as u32;
+        stats.num_free_runs = free_nodes.iter().enumerate().filter(|&(i, &node_index)| {
+            i == 0 || free_nodes[i - 1] + 1 != node_index
+        }).count() as u32;
+        stats
+    }
+    /// Walks the entire tree and returns all inconsistencies found. The surface flags are only
+    /// checked, when the structure itself is intact. Surface flags of leafs above depth 0 may be
+    /// set spuriously and are only checked for void leafs. Boxes next to paged leafs are skipped,
+    /// since their flags have been set, before the leafs were paged out. A paged leaf at depth 0 is
+    /// reported as void leaf with surface flag. May be used in tests and is asserted after every
+    /// edit of a debug build with the check feature.
+    pub fn check(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let num_chunks = self.chunks_.len();
+        if self.infos_.len() != num_chunks {
+            violations.push(Violation::InfoCount { num_chunks: num_chunks, num_infos: self.infos_.len() });
+            return violations;
+        }
+        let mut used = vec![false; num_chunks];
+        used[0] = true;
+        let mut far_children = HashSet::new();
+        let mut compressed_children = HashSet::new();
+        // Leafs, whose surface flags need to be checked, with the position of the first box
+        // within their sub-cube and their depth.
+        let mut leafs: Vec<(na::Vector3<u32>, u8, Child)> = Vec::new();
+        // Nodes with the position of the first box within their sub-cube and the depth of their
+        // children.
+        let mut nodes = vec![(0u32, na::Vector3::new(0u32, 0, 0), self.depth_ - 1)];
+        while let Some((node_index, pos, depth)) = nodes.pop() {
+            let mut info = Info::new(0);
+            info.num_void_children = 0;
+            for index in 0..CHUNK_LEN {
+                let child = self.chunks_[node_index as usize][index as usize];
+                info.add_child(child);
+                let child_pos = child_pos(pos, depth, index);
+                if !child.is_node() {
+                    if depth == 1 && child.is_hidden() && self.compressed_.contains_key(&(node_index, index)) {
+                        compressed_children.insert((node_index, index));
+                    }
+                    if child.is_surface() && (child.is_void() || (depth == 0 && child.is_paged())) {
+                        violations.push(Violation::StaleSurface { pos: child_pos, depth: depth, surface: true });
+                    } else if depth == 0 && !child.is_void() {
+                        leafs.push((child_pos, depth, child));
+                    }
+                    continue;
+                }
+                if depth == 0 {
+                    violations.push(Violation::NodeAtDepth0 { node_index: node_index, index: index });
+                    continue;
+                }
+                let child_node = if child.node_index() == FAR_NODE_INDEX {
+                    far_children.insert((node_index, index));
+                    match self.far_nodes_.get(&(node_index, index)) {
+                        Some(&child_node) => child_node,
+                        None => {
+                            violations.push(Violation::UnregisteredFarNode { node_index: node_index, index: index });
+                            continue;
+                        },
+                    }
+                } else {
+                    child.node_index() as u32
+                };
+                if child_node == 0 || child_node as usize >= num_chunks {
+                    violations.push(Violation::InvalidNodeIndex { node_index: node_index, index: index, child_node: child_node });
+                    continue;
+                }
+                if used[child_node as usize] {
+                    violations.push(Violation::SharedNode { node_index: child_node });
+                    continue;
+                }
+                used[child_node as usize] = true;
+                let parent_index = self.infos_[child_node as usize].parent_index;
+                if parent_index != node_index {
+                    violations.push(Violation::WrongParent { node_index: child_node, parent_index: parent_index, expected: node_index });
+                }
+                let index_in_parent = self.infos_[child_node as usize].index_in_parent;
+                if index_in_parent != index {
+                    violations.push(Violation::WrongIndexInParent { node_index: child_node, index_in_parent: index_in_parent, expected: index });
+                }
+                if self.infos_[child_node as usize].has_surface() {
+                    info.num_surface_nodes += 1;
+                }
+                nodes.push((child_node, child_pos, depth - 1));
+            }
+            let node_info = &self.infos_[node_index as usize];
+            if node_info.num_void_children != info.num_void_children ||
+                node_info.num_solid_children != info.num_solid_children ||
+                node_info.num_transparent_children != info.num_transparent_children ||
+                node_info.num_surface_children != info.num_surface_children ||
+                node_info.num_surface_nodes != info.num_surface_nodes {
+                violations.push(Violation::WrongCounters { node_index: node_index });
+            }
+        }
+        for &(node_index, index) in self.far_nodes_.keys() {
+            if !far_children.contains(&(node_index, index)) {
+                violations.push(Violation::StaleFarNode { node_index: node_index, index: index });
+            }
+        }
+        for &(node_index, index) in self.compressed_.keys() {
+            if !compressed_children.contains(&(node_index, index)) {
+                violations.push(Violation::StaleCompressedLeaf { node_index: node_index, index: index });
+            }
+        }
+        // Walk the free list.
+        let mut free = vec![false; num_chunks];
+        let mut num_free_nodes: u64 = 0;
+        let mut node_index = self.first_free_node_;
+        while node_index != 0 {
+            if node_index as usize >= num_chunks || used[node_index as usize] || free[node_index as usize] {
+                violations.push(Violation::InvalidFreeNode { node_index: node_index });
+                break;
+            }
+            free[node_index as usize] = true;
+            num_free_nodes += 1;
+            let chunk = &self.chunks_[node_index as usize];
+            node_index = (chunk[0].value_ as u32) | ((chunk[1].value_ as u32) << 16);
+        }
+        let expected = num_free_nodes + (self.max_nodes_ as u64).saturating_sub(num_chunks as u64);
+        if self.num_free_nodes_ as u64 != expected {
+            violations.push(Violation::FreeNodeCount { num_free_nodes: self.num_free_nodes_, expected: expected as u32 });
+        }
+        for node_index in 0..num_chunks {
+            if !used[node_index] && !free[node_index] {
+                violations.push(Violation::LeakedNode { node_index: node_index as u32 });
+            }
+        }
+        if !violations.is_empty() {
+            return violations;
+        }
+        unsafe {
+            'leafs: for &(pos, depth, leaf) in leafs.iter() {
+                // Same neighborhood as is_surface_at.
+                let mut surface = false;
+                for z in 0..3u32 {
+                    for &(x, y) in [(1u32, 0u32), (0, 1), (1, 1), (2, 1), (1, 2)].iter() {
+                        let d = na::Vector3::new(x.wrapping_sub(1), y.wrapping_sub(1), z.wrapping_sub(1));
+                        let (node_index, index, neighbor_depth) = self.neighbor_location(pos, d);
+                        let neighbor = *self.chunks_.get_unchecked(node_index as usize).get_unchecked(index as usize);
+                        if neighbor_depth > 0 && neighbor.is_paged() {
+                            continue 'leafs;
+                        }
+                        if !neighbor.is_solid() {
+                            surface = true;
+                        }
+                    }
+                }
+                if leaf.is_surface() != surface {
+                    violations.push(Violation::StaleSurface { pos: pos, depth: depth, surface: leaf.is_surface() });
+                }
+            }
+        }
+        violations
+    }
+    /// Asserts, that check finds no inconsistencies, if the check feature is enabled.
+    fn debug_check(&self) {
+        if cfg!(feature = "check") {
+            let violations = self.check();
+            debug_assert!(violations.is_empty(), "tree is inconsistent: {}",
+                violations.iter().map(|violation| violation.to_string()).collect::<Vec<_>>().join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra as na;
+    use nalgebra::Norm;
+    use std::collections::{HashMap, HashSet, BTreeSet};
+    use std::panic;
+    use super::*;
+
+    fn assert_consistent<C: Compression>(tree: &Tree<C>) {
+        let violations = tree.check();
+        assert!(violations.is_empty(), "{}",
+            violations.iter().map(|violation| violation.to_string()).collect::<Vec<_>>().join(", "));
+    }
+
+    /// Children, infos and free nodes of a tree, which must not change, when an edit fails.
+    fn snapshot<C: Compression>(tree: &Tree<C>) -> (Vec<u16>, Vec<(u32, u16, u16, u16, u16)>, u32, u32) {
+        (
+            tree.chunks().iter().flat_map(|chunk| chunk.iter().map(|child| child.value_)).collect(),
+            tree.infos().iter().map(|info| (
+                info.parent_index,
+                info.index_in_parent,
+                info.num_void_children,
+                info.num_solid_children,
+                info.num_surface_children
+            )).collect(),
+            *tree.first_free_node(),
+            *tree.num_free_nodes()
+        )
+    }
+
+    /// Whether a box is not void and any box within the neighborhood of is_surface_at is not solid.
+    fn is_exposed<C: Compression>(tree: &Tree<C>, pos: na::Vector3<u32>) -> bool {
+        if tree.get_at_pos(pos).is_void() {
+            return false;
+        }
+        let mask = (1u32 << (*tree.depth() * CHUNK_SIDE_LEN_LOG2)) - 1;
+        (0..3u32).any(|z| [(1u32, 0u32), (0, 1), (1, 1), (2, 1), (1, 2)].iter().any(|&(x, y)| {
+            let neighbor = na::Vector3::new(
+                (pos.x + x).wrapping_sub(1) & mask,
+                (pos.y + y).wrapping_sub(1) & mask,
+                (pos.z + z).wrapping_sub(1) & mask
+            );
+            !tree.get_at_pos(neighbor).is_solid()
+        }))
+    }
+
+    /// Positions of the boxes within a cube from the origin, which are exposed.
+    fn exposed_boxes<C: Compression>(tree: &Tree<C>, side_len: u32) -> BTreeSet<(u32, u32, u32)> {
+        let mut boxes = BTreeSet::new();
+        for z in 0..side_len {
+            for y in 0..side_len {
+                for x in 0..side_len {
+                    if is_exposed(tree, na::Vector3::new(x, y, z)) {
+                        boxes.insert((x, y, z));
+                    }
+                }
+            }
+        }
+        boxes
+    }
+
+    /// Planes of a view from an origin looking along a direction with a field of view of 90
+    /// degrees.
+    fn view_planes(direction: na::Vector3<f64>) -> [na::Vector3<f64>; 4] {
+        let forward = direction.normalize();
+        let right = na::cross(&forward, &na::Vector3::new(0.0, 1.0, 0.0)).normalize();
+        let up = na::cross(&right, &forward);
+        [(forward + right).normalize(), (forward - right).normalize(), (forward + up).normalize(), (forward - up).normalize()]
+    }
+
+    fn random(seed: &mut u64) -> u32 {
+        *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (*seed >> 33) as u32
+    }
+
+    /// Fills the boxes from the origin up to a height with layers of three different solid boxes,
+    /// which are shifted by one box every 16 boxes along the x axis.
+    fn fill_rows<C: Compression>(tree: &mut Tree<C>, side_len: u32, height: u32) {
+        for y in 0..height {
+            for x in 0..(side_len / 16) {
+                let spec = 1 + ((x + y) % 3) as u16;
+                let min = na::Vector3::new(x * 16, y, 0);
+                let max = na::Vector3::new(x * 16 + 15, y, side_len - 1);
+                assert!(tree.fill_region(min, max, Leaf::from_solid_box_spec(true, spec)).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn set_void() {
+        let mut tree = Tree::new(3, 64, NoCompression);
+        let num_free_nodes = *tree.num_free_nodes();
+        let leaf = Leaf::from_solid_box_spec(true, 7);
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(19, 4, 4), leaf).is_ok());
+        assert!(!tree.get_at_pos(na::Vector3::new(2, 2, 2)).is_surface());
+        assert!(tree.get_at_pos(na::Vector3::new(0, 2, 2)).is_surface());
+        assert!(tree.set_void_at_pos(na::Vector3::new(2, 2, 2)));
+        assert_consistent(&tree);
+        assert!(tree.get_at_pos(na::Vector3::new(2, 2, 2)).is_void());
+        assert!(tree.get_at_pos(na::Vector3::new(2, 2, 3)).is_surface());
+        assert!(tree.get_at_pos(na::Vector3::new(2, 3, 2)).is_surface());
+        // Nodes which become entirely void are returned to the free nodes.
+        for z in 0..5 {
+            for y in 0..5 {
+                for x in 0..20 {
+                    assert!(tree.set_void_at_pos(na::Vector3::new(x, y, z)));
+                }
+            }
+        }
+        assert_consistent(&tree);
+        assert_eq!(*tree.num_free_nodes(), num_free_nodes);
+        assert!(tree.chunks()[0].iter().all(|child| child.is_void()));
+    }
+
+    #[test]
+    fn collapse() {
+        let mut tree = Tree::new(3, 64, NoCompression);
+        let num_free_nodes = *tree.num_free_nodes();
+        let leaf = Leaf::from_solid_box_spec(true, 7);
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..32 {
+                    assert!(tree.set_at_pos(na::Vector3::new(x, y, z), leaf));
+                }
+            }
+        }
+        // Only the node at depth 1 is left, the two nodes at depth 0 have become leafs.
+        assert_consistent(&tree);
+        assert_eq!(*tree.num_free_nodes(), num_free_nodes - 1);
+        assert_eq!(tree.get_at_pos(na::Vector3::new(20, 3, 9)).ext_spec(), leaf.ext_spec());
+        assert!(tree.set_void_at_pos(na::Vector3::new(5, 5, 5)));
+        assert_consistent(&tree);
+        assert_eq!(*tree.num_free_nodes(), num_free_nodes - 2);
+        // The surface flags of the leaf next to the split node cover its whole sub-cube.
+        for z in 0..17 {
+            for y in 0..17 {
+                for x in 0..16 {
+                    let pos = na::Vector3::new(x, y, z);
+                    assert_eq!(tree.get_at_pos(pos).is_surface(), is_exposed(&tree, pos), "{:?}", pos);
+                }
+            }
+        }
+        assert!(tree.set_at_pos(na::Vector3::new(5, 5, 5), leaf));
+        assert_consistent(&tree);
+        assert_eq!(*tree.num_free_nodes(), num_free_nodes - 1);
+    }
+
+    #[test]
+    fn set_out_of_nodes() {
+        let leaf = Leaf::from_solid_box_spec(true, 7);
+        let mut tree = Tree::new(3, 4, NoCompression);
+        assert!(tree.set_at_pos(na::Vector3::new(0, 0, 0), leaf));
+        assert!(tree.set_at_pos(na::Vector3::new(17, 0, 0), leaf));
+        let before = snapshot(&tree);
+        assert_eq!(tree.try_set_at_pos(na::Vector3::new(300, 0, 0), leaf), Err(OutOfNodes));
+        assert!(snapshot(&tree) == before);
+        // A sub-cube made of identical boxes can't be split.
+        let mut tree = Tree::new(3, 3, NoCompression);
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(15, 15, 15), leaf).is_ok());
+        assert!(tree.set_at_pos(na::Vector3::new(20, 0, 0), leaf));
+        assert_eq!(*tree.num_free_nodes(), 0);
+        let before = snapshot(&tree);
+        assert_eq!(tree.try_set_at_pos(na::Vector3::new(3, 3, 3), Leaf::void()), Err(OutOfNodes));
+        assert_eq!(tree.try_set_at_pos(na::Vector3::new(3, 3, 3), Leaf::from_solid_box_spec(true, 8)), Err(OutOfNodes));
+        assert!(snapshot(&tree) == before);
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn replace_same_solidity() {
+        let mut tree = Tree::new(3, 64, NoCompression);
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..16 {
+                    let spec = if (x + y + z) % 2 == 0 { 7 } else { 9 };
+                    assert!(tree.set_at_pos(na::Vector3::new(x, y, z), Leaf::from_solid_box_spec(true, spec)));
+                }
+            }
+        }
+        let num_free_nodes = *tree.num_free_nodes();
+        // Setting a box to what it already is changes nothing.
+        let before = snapshot(&tree);
+        assert!(tree.set_at_pos(na::Vector3::new(0, 0, 0), Leaf::from_solid_box_spec(true, 7)));
+        assert!(snapshot(&tree) == before);
+        // Replacing a box by one of the same solidity keeps its surface flag.
+        assert!(tree.set_at_pos(na::Vector3::new(0, 0, 1), Leaf::from_solid_box_spec(true, 3)));
+        assert!(tree.get_at_pos(na::Vector3::new(0, 0, 1)).is_surface());
+        assert_eq!(tree.get_at_pos(na::Vector3::new(0, 0, 1)).box_spec(), 3);
+        assert!(tree.set_at_pos(na::Vector3::new(5, 5, 5), Leaf::from_solid_box_spec(true, 3)));
+        assert!(!tree.get_at_pos(na::Vector3::new(5, 5, 5)).is_surface());
+        assert_consistent(&tree);
+        // Once all boxes are the same, the node collapses.
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..16 {
+                    assert!(tree.set_at_pos(na::Vector3::new(x, y, z), Leaf::from_solid_box_spec(true, 7)));
+                }
+            }
+        }
+        assert_consistent(&tree);
+        assert_eq!(*tree.num_free_nodes(), num_free_nodes + 1);
+    }
+
+    #[test]
+    fn cast_view() {
+        let mut tree = Tree::new(3, 64, NoCompression);
+        let leaf = Leaf::from_solid_box_spec(true, 7);
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(31, 31, 15), leaf).is_ok());
+        assert!(tree.set_at_pos(na::Vector3::new(40, 3, 3), leaf));
+        assert!(tree.set_at_pos(na::Vector3::new(5, 5, 5), Leaf::from_solid_box_spec(false, 2)));
+        let planes = view_planes(na::Vector3::new(0.0, 0.0, 1.0));
+        let mut boxes = BTreeSet::new();
+        tree.cast_view(na::Point3::new(16.0, 8.0, -100.0), planes, 1000.0, &mut |pos, child| {
+            assert!(child.is_surface());
+            assert!(boxes.insert((pos.x, pos.y, pos.z)));
+        });
+        assert_eq!(boxes, exposed_boxes(&tree, 48));
+    }
+
+    #[test]
+    fn cast_view_threads() {
+        let mut tree = Tree::new(3, 10000, NoCompression);
+        for x in 0..40 {
+            for z in 0..40 {
+                let leaf = Leaf::from_solid_box_spec(x % 3 != 0, 1 + (x % 5) as u16);
+                assert!(tree.fill_region(
+                    na::Vector3::new(x * 5, 0, z * 5), na::Vector3::new(x * 5 + 4, (x * 7 + z * 3) % 40, z * 5 + 4), leaf
+                ).is_ok());
+            }
+        }
+        let views = [
+            (na::Point3::new(100.5, 60.5, -50.5), na::Vector3::new(0.0, 0.0, 1.0)),
+            (na::Point3::new(10.5, 80.5, 10.5), na::Vector3::new(1.0, -1.0, 1.0).normalize()),
+        ];
+        for &(origin, direction) in views.iter() {
+            let planes = view_planes(direction);
+            let mut serial = Vec::new();
+            tree.cast_view(origin, planes, 1000.0, &mut |pos, child| serial.push((pos, child.value())));
+            assert!(serial.len() > 1000);
+            for &num_threads in [1, 3, 8].iter() {
+                let mut parallel = Vec::new();
+                tree.cast_view_parallel(origin, planes, 1000.0, num_threads, &mut |pos, child| parallel.push((pos, child.value())));
+                assert!(parallel == serial, "{} threads", num_threads);
+            }
+            let mut serial = Vec::new();
+            let mut options = ViewOptions::default();
+            tree.cast_view_with(origin, planes, 1000.0, &options, &mut |pos, depth, child, faces| {
+                serial.push((pos, depth, child.value(), faces))
+            });
+            options.num_threads = 4;
+            let mut parallel = Vec::new();
+            tree.cast_view_with(origin, planes, 1000.0, &options, &mut |pos, depth, child, faces| {
+                parallel.push((pos, depth, child.value(), faces))
+            });
+            assert!(parallel == serial);
+        }
+    }
+
+    #[test]
+    fn cast_view_lod() {
+        let mut tree = Tree::new(3, 100, NoCompression);
+        assert!(tree.set_at_pos(na::Vector3::new(20, 8, 20), Leaf::from_solid_box_spec(true, 1)));
+        // More solid than transparent boxes in a node far away.
+        for x in 0..5 {
+            assert!(tree.set_at_pos(na::Vector3::new(2000 + x, 5, 2000), Leaf::from_solid_box_spec(false, 3)));
+        }
+        for x in 0..8 {
+            assert!(tree.set_at_pos(na::Vector3::new(2000 + x, 6, 2000), Leaf::from_solid_box_spec(true, 4)));
+        }
+        let index = |x: u32, y: u32, z: u32| (x | (y << CHUNK_SIDE_LEN_LOG2) | (z << (2 * CHUNK_SIDE_LEN_LOG2))) as u16;
+        let far_node = tree.child_node_index(tree.child_node_index(0, index(7, 0, 7)), index(13, 0, 13));
+        let origin = na::Point3::new(4.5, 8.5, 4.5);
+        let planes = view_planes(na::Vector3::new(1.0, 0.0, 1.0));
+        // The far node appears smaller than a pixel, its parent doesn't.
+        let lod = ViewOptions { lod: Some(Lod { pixels_per_unit: 20.0, min_pixels: 1.0 }), ..ViewOptions::default() };
+        let cast = |tree: &Tree<NoCompression>, options: &ViewOptions| {
+            let mut boxes = BTreeMap::new();
+            tree.cast_view_with(origin, planes, 10000.0, options, &mut |pos, depth, child, faces| {
+                assert!(boxes.insert((pos.x, pos.y, pos.z), (depth, child.value(), faces)).is_none());
+            });
+            boxes
+        };
+        let fine = cast(&tree, &ViewOptions::default());
+        assert_eq!(fine.len(), 14);
+        assert!(fine.values().all(|&(depth, _, _)| depth == 0));
+        let coarse = cast(&tree, &lod);
+        assert_eq!(coarse.len(), 2);
+        assert!(coarse[&(20, 8, 20)] == fine[&(20, 8, 20)]);
+        // The coarse box is the first solid box of the node, since solid boxes are more common.
+        let info = &tree.infos()[far_node as usize];
+        assert_eq!((info.num_solid_children, info.num_transparent_children), (8, 5));
+        let (depth, value, faces) = coarse[&(2000, 0, 2000)];
+        assert_eq!((depth, value, faces), (1, fine[&(2000, 6, 2000)].1, ALL_FACES_MASK));
+        assert!(Child::new(value).is_surface() && Child::new(value).is_solid());
+        assert_eq!(Child::new(value).box_spec(), 4);
+        // Once transparent boxes are more common, the first transparent box stands for the node.
+        for x in 5..12 {
+            assert!(tree.set_at_pos(na::Vector3::new(2000 + x, 5, 2000), Leaf::from_solid_box_spec(false, 3)));
+        }
+        let info = &tree.infos()[far_node as usize];
+        assert_eq!((info.num_solid_children, info.num_transparent_children), (8, 12));
+        let fine = cast(&tree, &ViewOptions::default());
+        let coarse = cast(&tree, &lod);
+        assert_eq!(coarse.len(), 2);
+        let (depth, value, _) = coarse[&(2000, 0, 2000)];
+        assert_eq!((depth, value), (1, fine[&(2000, 5, 2000)].1));
+        assert!(Child::new(value).is_surface() && !Child::new(value).is_solid());
+        assert_eq!(Child::new(value).box_spec(), 3);
+    }
+
+    #[test]
+    fn cast_view_occlusion() {
+        let mut tree = Tree::new(3, 1000, NoCompression);
+        let wall = Leaf::from_solid_box_spec(true, 1);
+        assert!(tree.fill_region(na::Vector3::new(40, 0, 40), na::Vector3::new(120, 60, 44), wall).is_ok());
+        // Rows of boxes behind the wall and a few boxes in front of it.
+        for y in 0..15 {
+            assert!(tree.fill_region(
+                na::Vector3::new(40, y * 4, 60), na::Vector3::new(120, y * 4 + 1, 100), Leaf::from_solid_box_spec(true, 2)
+            ).is_ok());
+        }
+        for x in 0..8 {
+            assert!(tree.set_at_pos(na::Vector3::new(65 + x * 4, 30, 30), Leaf::from_solid_box_spec(x % 2 == 0, 3)));
+        }
+        let origin = na::Point3::new(80.5, 30.5, 10.5);
+        let planes = view_planes(na::Vector3::new(0.0, 0.0, 1.0));
+        let cast = |options: &ViewOptions| {
+            let mut boxes = BTreeSet::new();
+            tree.cast_view_with(origin, planes, 1000.0, options, &mut |pos, _, _, _| {
+                assert!(boxes.insert((pos.x, pos.y, pos.z)));
+            });
+            boxes
+        };
+        let all = cast(&ViewOptions::default());
+        let occlusion = Some(Occlusion { resolution: 128 });
+        let visible = cast(&ViewOptions { occlusion: occlusion, order: ViewOrder::FrontToBack, ..ViewOptions::default() });
+        assert!(visible.is_subset(&all));
+        assert!(visible.len() * 2 < all.len());
+        // Everything in front of the wall is reported.
+        assert_eq!(all.iter().filter(|&&(_, _, z)| z < 40).count(), 8);
+        for &(x, y, z) in all.iter().filter(|&&(_, _, z)| z < 40) {
+            assert!(visible.contains(&(x, y, z)), "{:?}", (x, y, z));
+        }
+        assert!(all.contains(&(80, 28, 60)));
+        assert!(!visible.contains(&(80, 28, 60)));
+    }
+
+    #[test]
+    fn cast_view_order() {
+        let mut tree = Tree::new(3, 1000, NoCompression);
+        // Boxes along rays from the box of the origin, which cross the boundaries of nodes.
+        let rays = [(0i32, 0i32, 1i32), (1, 0, 2), (-1, 1, 2), (1, 1, 3), (0, -1, 2), (2, 1, 4)];
+        let start = (250i32, 250i32, 230i32);
+        for (ray, &(dx, dy, dz)) in rays.iter().enumerate() {
+            for k in 1..40 {
+                let pos = na::Vector3::new((start.0 + k * dx) as u32, (start.1 + k * dy) as u32, (start.2 + k * dz) as u32);
+                assert!(tree.set_at_pos(pos, Leaf::from_solid_box_spec(k % 3 != 0, 1 + ray as u16)));
+            }
+        }
+        let origin = na::Point3::new(start.0 as f64 + 0.5, start.1 as f64 + 0.5, start.2 as f64 + 0.5);
+        let planes = view_planes(na::Vector3::new(0.0, 0.0, 1.0));
+        let cast = |order: ViewOrder| {
+            let mut boxes = Vec::new();
+            tree.cast_view_with(origin, planes, 1000.0, &ViewOptions { order: order, ..ViewOptions::default() }, &mut |pos, _, _, _| {
+                boxes.push((pos.x as i32, pos.y as i32, pos.z as i32));
+            });
+            boxes
+        };
+        let mut all = cast(ViewOrder::Index);
+        assert_eq!(all.len(), rays.len() * 39);
+        all.sort();
+        for &(order, sign) in [(ViewOrder::FrontToBack, 1), (ViewOrder::BackToFront, -1)].iter() {
+            let boxes = cast(order);
+            let mut sorted = boxes.clone();
+            sorted.sort();
+            assert!(sorted == all, "{:?}", order);
+            // The distances of the boxes along each ray increase or decrease in the order reported.
+            for &(dx, dy, dz) in rays.iter() {
+                let steps: Vec<i32> = boxes.iter()
+                    .map(|&(x, y, z)| (x - start.0, y - start.1, z - start.2))
+                    .filter(|&(x, y, z)| x * dz == z * dx && y * dz == z * dy)
+                    .map(|(_, _, z)| z / dz)
+                    .collect();
+                assert_eq!(steps.len(), 39);
+                assert!(steps.windows(2).all(|pair| (pair[1] - pair[0]) * sign > 0), "{:?} {:?}", order, (dx, dy, dz));
+            }
+        }
+    }
+
+    #[test]
+    fn compression() {
+        let mut tree = Tree::new(3, 64, DictionaryCompression::new());
+        let num_free_nodes = *tree.num_free_nodes();
+        fill_rows(&mut tree, 48, 48);
+        assert_consistent(&tree);
+        assert!(tree.compression().num_chunks() > 0);
+        let num_nodes_used = num_free_nodes - *tree.num_free_nodes();
+        // Digging into the hidden boxes decompresses them.
+        let mut seed = 5;
+        let mut removed = HashSet::new();
+        for _ in 0..50 {
+            let pos = (10 + random(&mut seed) % 28, 10 + random(&mut seed) % 28, 10 + random(&mut seed) % 28);
+            assert!(tree.set_void_at_pos(na::Vector3::new(pos.0, pos.1, pos.2)));
+            removed.insert(pos);
+        }
+        assert_consistent(&tree);
+        for z in 0..48 {
+            for y in 0..48 {
+                for x in 0..48 {
+                    let leaf = tree.get_at_pos(na::Vector3::new(x, y, z));
+                    if removed.contains(&(x, y, z)) {
+                        assert!(leaf.is_void());
+                    } else {
+                        assert_eq!(leaf.box_spec(), 1 + ((x / 16 + y) % 3) as u16);
+                    }
+                }
+            }
+        }
+        // Filling the holes again compresses the boxes again.
+        for &(x, y, z) in removed.iter() {
+            let spec = 1 + ((x / 16 + y) % 3) as u16;
+            assert!(tree.set_at_pos(na::Vector3::new(x, y, z), Leaf::from_solid_box_spec(true, spec)));
+        }
+        assert_consistent(&tree);
+        assert_eq!(num_free_nodes - *tree.num_free_nodes(), num_nodes_used);
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(47, 47, 47), Leaf::void()).is_ok());
+        assert_consistent(&tree);
+        assert_eq!(*tree.num_free_nodes(), num_free_nodes);
+        assert_eq!(tree.compression().num_chunks(), 0);
+    }
+
+    #[test]
+    fn compressed_out_of_nodes() {
+        // A compressed leaf, whose boxes would become exposed, can't be split without free nodes.
+        let leaf = Leaf::from_solid_box_spec(true, 7);
+        let mut tree = Tree::new(3, 30, DictionaryCompression::new());
+        fill_rows(&mut tree, 48, 48);
+        assert!(tree.compression().num_chunks() > 0);
+        let mut n = 0;
+        while tree.set_at_pos(na::Vector3::new(100 + 16 * (n % 10), 16 * (n / 10), 200), leaf) {
+            n += 1;
+        }
+        let before = snapshot(&tree);
+        assert!(!tree.set_void_at_pos(na::Vector3::new(24, 24, 24)));
+        assert!(snapshot(&tree) == before);
+        assert_eq!(tree.get_at_pos(na::Vector3::new(24, 24, 24)).box_spec(), 1 + ((1 + 24) % 3) as u16);
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn collapse_next_to_compressed() {
+        let spec = Leaf::from_solid_box_spec(true, 1);
+        let mut tree = Tree::new(3, 16, DictionaryCompression::new());
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(255, 255, 255), spec).is_ok());
+        // Hidden boxes of another type within a chunk, whose first box keeps the type of the
+        // boxes around it, are compressed into a leaf looking like these boxes.
+        for z in 17..31 {
+            for y in 17..31 {
+                for x in 17..31 {
+                    if (x + y + z) % 2 == 0 {
+                        assert!(tree.set_at_pos(na::Vector3::new(x, y, z), Leaf::from_solid_box_spec(true, 2)));
+                    }
+                }
+            }
+        }
+        assert_eq!(tree.compression().num_refs(), 1);
+        // Collapsing the node of an edit elsewhere must keep the compressed leaf.
+        assert!(tree.set_at_pos(na::Vector3::new(100, 100, 100), Leaf::from_solid_box_spec(true, 2)));
+        assert!(tree.set_at_pos(na::Vector3::new(100, 100, 100), spec));
+        assert_consistent(&tree);
+        assert_eq!(tree.compression().num_refs(), 1);
+        assert_eq!(tree.get_at_pos(na::Vector3::new(18, 18, 18)).box_spec(), 2);
+        assert_eq!(tree.get_at_pos(na::Vector3::new(18, 18, 19)).box_spec(), 1);
+    }
+
+    #[test]
+    fn uniform_leafs() {
+        let mut tree = Tree::new(3, 16, NoCompression);
+        let leaf = Leaf::from_solid_box_spec(true, 7);
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(255, 255, 255), leaf).is_ok());
+        assert!(tree.set_at_pos(na::Vector3::new(300, 0, 0), Leaf::from_solid_box_spec(true, 2)));
+        // The boxes within a leaf at depth 2 are looked up without any nodes below it.
+        assert!(tree.chunks()[0][0].is_surface() && !tree.chunks()[0][0].is_node());
+        assert_eq!(tree.get_at_pos(na::Vector3::new(100, 200, 30)).box_spec(), 7);
+        assert_eq!(tree.neighbor(na::Vector3::new(3, 3, 3), na::Vector3::new(1, 0, 0)).box_spec(), 7);
+        assert_eq!(tree.neighbor(na::Vector3::new(255, 0, 0), na::Vector3::new(1, 0, 0)).box_spec(), 0);
+        // Neighbors wrap around the world.
+        assert!(tree.neighbor(na::Vector3::new(0, 3, 3), na::Vector3::new(!0, 0, 0)).is_void());
+        assert_eq!(tree.neighbor(na::Vector3::new(4095, 3, 3), na::Vector3::new(1, 0, 0)).box_spec(), 7);
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn stats() {
+        let mut tree = Tree::new(3, 100, NoCompression);
+        for x in 0..20 {
+            assert!(tree.set_at_pos(na::Vector3::new(x, 3, 3), Leaf::from_solid_box_spec(true, 1)));
+        }
+        assert!(tree.set_at_pos(na::Vector3::new(5, 4, 3), Leaf::from_solid_box_spec(false, 2)));
+        // The two nodes of a box far away are returned to the free list.
+        assert!(tree.set_at_pos(na::Vector3::new(300, 3, 3), Leaf::from_solid_box_spec(true, 1)));
+        assert!(tree.set_void_at_pos(na::Vector3::new(300, 3, 3)));
+        let stats = tree.stats();
+        assert_eq!(stats.num_nodes_per_depth, vec![0, 2, 1, 1]);
+        assert_eq!(stats.num_nodes_used(), 4);
+        assert_eq!(stats.num_solid_leafs, 20);
+        assert_eq!(stats.num_transparent_leafs, 1);
+        assert_eq!(stats.num_surface_leafs, 21);
+        // All children of the nodes but the three child nodes and the boxes are void.
+        assert_eq!(stats.num_void_leafs, 4 * CHUNK_LEN as u64 - 3 - 21);
+        assert_eq!((stats.num_compressed_leafs, stats.num_paged_leafs), (0, 0));
+        let histogram: Vec<(u16, u64)> = stats.ext_spec_histogram.iter().map(|(&spec, &num)| (spec, num)).collect();
+        assert_eq!(histogram, vec![(2, 1), (SOLID_BIT_MASK | 1, 20)]);
+        assert_eq!(stats.bytes_used, 4 * (size_of::<Chunk<Child>>() + size_of::<Info>()));
+        assert_eq!((stats.max_nodes, stats.num_free_nodes), (100, 96));
+        assert_eq!((stats.num_free_list_nodes, stats.num_free_runs), (2, 1));
+        assert!((stats.fragmentation() - 2.0 / 6.0).abs() < 1e-9);
+        // Reusing the free nodes empties the free list.
+        assert!(tree.set_at_pos(na::Vector3::new(300, 3, 3), Leaf::from_solid_box_spec(true, 1)));
+        let stats = tree.stats();
+        assert_eq!(stats.num_nodes_per_depth, vec![0, 3, 2, 1]);
+        assert_eq!((stats.num_free_list_nodes, stats.num_free_runs), (0, 0));
+        assert_eq!(stats.fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn save_load() {
+        let mut tree = Tree::new(3, 200, DictionaryCompression::new());
+        fill_rows(&mut tree, 48, 40);
+        assert!(tree.fill_region(na::Vector3::new(0, 38, 0), na::Vector3::new(47, 39, 47), Leaf::from_solid_box_spec(false, 4)).is_ok());
+        for x in 0..20 {
+            assert!(tree.set_void_at_pos(na::Vector3::new(x + 5, 36, 20)));
+        }
+        let num_compressed_leafs = tree.stats().num_compressed_leafs;
+        assert!(num_compressed_leafs > 0);
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+        let loaded = Tree::read_from(&mut &bytes[..], DictionaryCompression::new()).unwrap();
+        assert_consistent(&loaded);
+        assert_eq!(loaded.num_free_nodes(), tree.num_free_nodes());
+        assert_eq!(loaded.compression().num_chunks(), tree.compression().num_chunks());
+        for z in 0..50 {
+            for y in 0..50 {
+                for x in 0..50 {
+                    let pos = na::Vector3::new(x, y, z);
+                    assert_eq!(loaded.get_at_pos(pos).value_, tree.get_at_pos(pos).value_, "{:?}", pos);
+                }
+            }
+        }
+        let mut saved_again = Vec::new();
+        loaded.write_to(&mut saved_again).unwrap();
+        assert!(saved_again == bytes);
+        // Without compression the compressed leafs are read as nodes.
+        let uncompressed = Tree::read_from(&mut &bytes[..], NoCompression).unwrap();
+        assert_consistent(&uncompressed);
+        assert_eq!(*uncompressed.num_free_nodes(), *tree.num_free_nodes() - num_compressed_leafs as u32);
+        assert_eq!(uncompressed.get_at_pos(na::Vector3::new(20, 20, 20)).value_, tree.get_at_pos(na::Vector3::new(20, 20, 20)).value_);
+        // Damaged files are rejected.
+        let mut damaged = bytes.clone();
+        damaged[0] = b'X';
+        assert!(Tree::read_from(&mut &damaged[..], NoCompression).is_err());
+        assert!(Tree::read_from(&mut &bytes[..bytes.len() - 1], NoCompression).is_err());
+        let mut tree = Tree::new(3, 10, NoCompression);
+        assert!(tree.set_at_pos(na::Vector3::new(1, 1, 1), Leaf::from_solid_box_spec(true, 7)));
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+        let value = tree.get_at_pos(na::Vector3::new(1, 1, 1)).value_;
+        let index = bytes.windows(2).position(|bytes| bytes == [value as u8, (value >> 8) as u8]).unwrap();
+        bytes[index + 1] &= !((SURFACE_BIT_MASK >> 8) as u8);
+        assert!(Tree::read_from(&mut &bytes[..], NoCompression).is_err());
+    }
+
+    #[test]
+    fn raycast() {
+        let mut tree = Tree::new(3, 200, NoCompression);
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(39, 9, 39), Leaf::from_solid_box_spec(true, 3)).is_ok());
+        assert!(tree.set_at_pos(na::Vector3::new(1000, 500, 700), Leaf::from_solid_box_spec(true, 9)));
+        let origin = na::Point3::new(5.5, 100.0, 5.5);
+        let down = na::Vector3::new(0.0, -1.0, 0.0);
+        let hit = tree.raycast(origin, down, 1000.0).unwrap();
+        assert_eq!(hit.pos, na::Vector3::new(5, 9, 5));
+        assert_eq!(hit.normal, na::Vector3::new(0, 1, 0));
+        assert!((hit.distance - 90.0).abs() < 1e-9);
+        assert_eq!(hit.leaf.box_spec(), 3);
+        assert!(tree.raycast(origin, down, 80.0).is_none());
+        assert!(tree.raycast(origin, down * 1e-200, 100.0).is_some());
+        assert!(tree.raycast(origin, down, ::std::f64::INFINITY).is_some());
+        // Rays without a valid direction, origin or distance hit nothing.
+        let nan = ::std::f64::NAN;
+        assert!(tree.raycast(origin, na::Vector3::new(0.0, 0.0, 0.0), 100.0).is_none());
+        assert!(tree.raycast(origin, na::Vector3::new(0.0, -1.0, nan), 100.0).is_none());
+        assert!(tree.raycast(origin, na::Vector3::new(0.0, ::std::f64::NEG_INFINITY, 0.0), 100.0).is_none());
+        assert!(tree.raycast(na::Point3::new(5.5, nan, 5.5), down, 100.0).is_none());
+        assert!(tree.raycast(origin, down, nan).is_none());
+        // Empty space is crossed up to a single box far away.
+        let hit = tree.raycast(na::Point3::new(0.5, 600.5, 0.5), na::Vector3::new(999.7, -100.0, 699.7), 1e6).unwrap();
+        assert_eq!(hit.pos, na::Vector3::new(1000, 500, 700));
+        // Rays from outside of the world compared to small steps along the ray.
+        let mut seed = 3;
+        for _ in 0..40 {
+            let origin = na::Point3::new(
+                (random(&mut seed) % 1000) as f64 / 10.0 - 30.0,
+                (random(&mut seed) % 400) as f64 / 10.0 - 10.0,
+                (random(&mut seed) % 1000) as f64 / 10.0 - 30.0
+            );
+            let target = na::Point3::new(
+                (random(&mut seed) % 400) as f64 / 10.0,
+                (random(&mut seed) % 100) as f64 / 10.0,
+                (random(&mut seed) % 400) as f64 / 10.0
+            );
+            let direction = (target - origin).normalize();
+            let mut expected = None;
+            let mut dist = 0.0;
+            while dist < 200.0 {
+                let point = origin + direction * dist;
+                if point.x >= 0.0 && point.y >= 0.0 && point.z >= 0.0 {
+                    let pos = na::Vector3::new(point.x as u32, point.y as u32, point.z as u32);
+                    if !tree.get_at_pos(pos).is_void() {
+                        expected = Some((pos, dist));
+                        break;
+                    }
+                }
+                dist += 0.002;
+            }
+            match (tree.raycast(origin, direction, 1e6), expected) {
+                (Some(hit), Some((pos, dist))) => {
+                    assert_eq!(hit.pos, pos);
+                    assert!((hit.distance - dist).abs() < 0.01);
+                },
+                (None, None) => {},
+                (hit, expected) => panic!("{:?} instead of {:?}", hit.map(|hit| hit.pos), expected),
+            }
+        }
+    }
+
+    #[test]
+    fn iter_region() {
+        let mut tree = Tree::new(3, 300, DictionaryCompression::new());
+        fill_rows(&mut tree, 48, 40);
+        assert!(tree.fill_region(na::Vector3::new(256, 0, 0), na::Vector3::new(287, 15, 15), Leaf::from_solid_box_spec(true, 4)).is_ok());
+        assert!(tree.compression().num_chunks() > 0);
+        let mut seed = 21;
+        for round in 0..20 {
+            let min = na::Vector3::new(random(&mut seed) % 300, random(&mut seed) % 50, random(&mut seed) % 60);
+            let max = na::Vector3::new(min.x + random(&mut seed) % 30, min.y + random(&mut seed) % 30, min.z + random(&mut seed) % 30);
+            let include_void = round % 2 == 0;
+            let mut boxes = HashMap::new();
+            for (pos, depth, leaf) in tree.iter_region(min, max, include_void) {
+                let side_len = 1u32 << (depth * CHUNK_SIDE_LEN_LOG2);
+                assert!(pos.x >= min.x && pos.y >= min.y && pos.z >= min.z);
+                assert!(pos.x + side_len - 1 <= max.x && pos.y + side_len - 1 <= max.y && pos.z + side_len - 1 <= max.z);
+                assert!(include_void || !leaf.is_void());
+                for z in 0..side_len {
+                    for y in 0..side_len {
+                        for x in 0..side_len {
+                            assert!(boxes.insert((pos.x + x, pos.y + y, pos.z + z), leaf.ext_spec()).is_none());
+                        }
+                    }
+                }
+            }
+            for z in min.z..(max.z + 1) {
+                for y in min.y..(max.y + 1) {
+                    for x in min.x..(max.x + 1) {
+                        let leaf = tree.get_at_pos(na::Vector3::new(x, y, z));
+                        if leaf.is_void() && !include_void {
+                            assert!(!boxes.contains_key(&(x, y, z)));
+                        } else {
+                            assert_eq!(boxes.get(&(x, y, z)), Some(&leaf.ext_spec()), "{} {} {}", x, y, z);
+                        }
+                    }
+                }
+            }
+        }
+        // Sub-cubes entirely within the region are reported as a whole.
+        let items: Vec<_> = tree.iter_region(na::Vector3::new(256, 0, 0), na::Vector3::new(287, 15, 15), false).collect();
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(|&(_, depth, _)| depth == 1));
+    }
+
+    #[test]
+    fn fill_region() {
+        let mut tree = Tree::new(3, 400, DictionaryCompression::new());
+        let mut reference = Tree::new(3, 400, NoCompression);
+        fill_rows(&mut tree, 48, 40);
+        fill_rows(&mut reference, 48, 40);
+        assert!(tree.compression().num_chunks() > 0);
+        let mut seed = 77;
+        for round in 0..12 {
+            let min = na::Vector3::new(random(&mut seed) % 60, random(&mut seed) % 50, random(&mut seed) % 60);
+            let max = na::Vector3::new(min.x + random(&mut seed) % 16, min.y + random(&mut seed) % 16, min.z + random(&mut seed) % 16);
+            let leaf = match round % 4 {
+                0 => Leaf::void(),
+                1 => Leaf::from_solid_box_spec(false, 9),
+                _ => Leaf::from_solid_box_spec(true, 1 + (round % 3) as u16),
+            };
+            assert!(tree.fill_region(min, max, leaf).is_ok());
+            for z in min.z..(max.z + 1) {
+                for y in min.y..(max.y + 1) {
+                    for x in min.x..(max.x + 1) {
+                        assert!(reference.set_at_pos(na::Vector3::new(x, y, z), leaf));
+                    }
+                }
+            }
+            assert_consistent(&tree);
+        }
+        for z in 0..78 {
+            for y in 0..68 {
+                for x in 0..78 {
+                    let pos = na::Vector3::new(x, y, z);
+                    assert_eq!(tree.get_at_pos(pos).ext_spec(), reference.get_at_pos(pos).ext_spec(), "{:?}", pos);
+                    if reference.get_at_pos(pos).is_surface() {
+                        assert!(tree.get_at_pos(pos).is_surface(), "{:?}", pos);
+                    }
+                }
+            }
+        }
+        // Aligned sub-cubes become single leafs.
+        let num_free_nodes = *tree.num_free_nodes();
+        assert!(tree.fill_region(na::Vector3::new(256, 0, 0), na::Vector3::new(511, 255, 255), Leaf::from_solid_box_spec(true, 5)).is_ok());
+        assert_eq!(*tree.num_free_nodes(), num_free_nodes);
+        assert_eq!(tree.get_at_pos(na::Vector3::new(300, 100, 100)).box_spec(), 5);
+        assert!(tree.get_at_pos(na::Vector3::new(256, 100, 100)).is_surface());
+        // Regions beyond the world are clipped and surface flags wrap around the world.
+        assert!(tree.fill_region(na::Vector3::new(4090, 0, 0), na::Vector3::new(5000, 3, 3), Leaf::from_solid_box_spec(true, 6)).is_ok());
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(2, 2, 2), Leaf::void()).is_ok());
+        assert!(tree.get_at_pos(na::Vector3::new(4095, 1, 1)).is_surface());
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn fill_region_out_of_nodes() {
+        let leaf = Leaf::from_solid_box_spec(true, 7);
+        let mut tree = Tree::new(3, 4, NoCompression);
+        assert!(tree.set_at_pos(na::Vector3::new(0, 0, 0), leaf));
+        assert!(tree.set_at_pos(na::Vector3::new(17, 0, 0), leaf));
+        let before = snapshot(&tree);
+        assert_eq!(
+            tree.fill_region(na::Vector3::new(3, 3, 3), na::Vector3::new(200, 60, 20), leaf),
+            Err(OutOfNodes)
+        );
+        assert!(snapshot(&tree) == before);
+    }
+
+    #[test]
+    fn dirty_regions() {
+        let mut tree = Tree::new(3, 100, NoCompression);
+        let leaf = Leaf::from_solid_box_spec(true, 1);
+        let pos = |x, y, z| na::Vector3::new(x, y, z);
+        let drain = |tree: &mut Tree<NoCompression>| {
+            let mut regions: Vec<_> = tree.drain_dirty_regions().iter().map(|pos| (pos.x, pos.y, pos.z)).collect();
+            regions.sort();
+            regions
+        };
+        // A box, which is only exposed through its neighbor in the node before.
+        assert!(tree.fill_region(pos(15, 19, 19), pos(17, 21, 21), leaf).is_ok());
+        assert!(tree.set_void_at_pos(pos(15, 20, 20)));
+        assert!(tree.get_at_pos(pos(16, 20, 20)).is_surface());
+        tree.track_dirty_regions(1);
+        assert!(tree.set_at_pos(pos(15, 20, 20), leaf));
+        assert!(!tree.get_at_pos(pos(16, 20, 20)).is_surface());
+        assert_eq!(drain(&mut tree), vec![(0, 16, 16), (16, 16, 16)]);
+        // Changing the type of a box without changing its solidity leaves its neighbors alone.
+        assert!(tree.set_at_pos(pos(15, 20, 20), Leaf::from_solid_box_spec(true, 2)));
+        assert_eq!(drain(&mut tree), vec![(0, 16, 16)]);
+        // At a larger depth both boxes are within the same region.
+        tree.track_dirty_regions(2);
+        assert!(tree.set_void_at_pos(pos(15, 20, 20)));
+        assert!(tree.get_at_pos(pos(16, 20, 20)).is_surface());
+        assert_eq!(drain(&mut tree), vec![(0, 0, 0)]);
+        // Filling a region marks the regions overlapping it and the boxes around it.
+        tree.track_dirty_regions(1);
+        assert!(tree.fill_region(pos(16, 16, 16), pos(47, 31, 31), Leaf::from_solid_box_spec(true, 3)).is_ok());
+        let mut expected = Vec::new();
+        for x in 0..4 {
+            for y in 0..3 {
+                for z in 0..3 {
+                    expected.push((x * 16, y * 16, z * 16));
+                }
+            }
+        }
+        assert_eq!(drain(&mut tree), expected);
+        assert!(drain(&mut tree).is_empty());
+        tree.untrack_dirty_regions();
+        assert!(tree.set_at_pos(pos(100, 100, 100), leaf));
+        assert!(drain(&mut tree).is_empty());
+    }
+
+    #[test]
+    fn paged_leaf_rejected() {
+        assert!(Leaf::from_child(Child::paged()).is_none());
+        assert!(Child::paged().as_leaf().is_none());
+        assert!(Leaf::from_child(Child::void()).is_some());
+        let mut tree = Tree::new(2, 16, NoCompression);
+        assert!(tree.set_at_pos(na::Vector3::new(1, 1, 1), Leaf::from_solid_box_spec(true, 1)));
+        let before = snapshot(&tree);
+        let paged = unsafe { Leaf::new(Child::paged()) };
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            tree.set_at_pos(na::Vector3::new(2, 2, 2), paged)
+        })).is_err());
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(255, 255, 255), paged)
+        })).is_err());
+        assert!(snapshot(&tree) == before);
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn far_nodes() {
+        let mut tree = Tree::new(4, 40000, NoCompression);
+        let num_free_nodes = *tree.num_free_nodes();
+        let leaf = Leaf::from_solid_box_spec(true, 1);
+        // Every line of boxes cuts through 64 sub-cubes, which need a node each, so the nodes of the
+        // last layer of lines are far nodes.
+        let side_len = 1024;
+        for y in 0..14 {
+            for z in 0..40 {
+                let min = na::Vector3::new(0, y * 16 + 3, z * 16 + 3);
+                assert!(tree.fill_region(min, na::Vector3::new(side_len - 1, min.y, min.z), leaf).is_ok());
+            }
+        }
+        assert!(tree.num_nodes() > FAR_NODE_INDEX as u32 + 40 * 64);
+        // Only the last layer is kept to save and check fewer nodes.
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(side_len - 1, 13 * 16 - 1, side_len - 1), Leaf::void()).is_ok());
+        let num_far_nodes = tree.far_nodes_.len();
+        assert!(num_far_nodes >= 40 * 64);
+        let mut boxes = Vec::new();
+        for z in 0..40 {
+            for x in 0..side_len / 13 {
+                boxes.push(na::Vector3::new(x * 13, 13 * 16 + 3, z * 16 + 3));
+            }
+        }
+        // Edits within a far node update the surface flags of its boxes.
+        let last = na::Vector3::new(1000, 13 * 16 + 3, 39 * 16 + 3);
+        let around = (0..27).map(|i| na::Vector3::new(last.x + i % 3 - 1, last.y + i / 3 % 3 - 1, last.z + i / 9 - 1));
+        let around: Vec<na::Vector3<u32>> = around.collect();
+        assert!(tree.fill_region(around[0], around[26], Leaf::from_solid_box_spec(true, 3)).is_ok());
+        assert!(tree.set_at_pos(last, leaf));
+        let next = na::Vector3::new(last.x + 1, last.y, last.z);
+        assert!(tree.set_at_pos(next, Leaf::from_solid_box_spec(true, 2)));
+        assert!(!tree.get_at_pos(last).is_surface());
+        assert_eq!(tree.get_at_pos(next).box_spec(), 2);
+        for &pos in around.iter() {
+            assert_eq!(tree.get_at_pos(pos).is_surface(), is_exposed(&tree, pos), "{:?}", pos);
+        }
+        // Filling the sub-cube of a far node collapses it and drops it from the far nodes.
+        let min = na::Vector3::new(last.x & !15, last.y & !15, last.z & !15);
+        let max = na::Vector3::new(min.x + 15, min.y + 15, min.z + 15);
+        let num_free = *tree.num_free_nodes();
+        assert!(tree.fill_region(min, max, leaf).is_ok());
+        assert_eq!(*tree.num_free_nodes(), num_free + 1);
+        assert_eq!(tree.far_nodes_.len(), num_far_nodes - 1);
+        assert_consistent(&tree);
+        // Digging into it takes a node again.
+        assert!(tree.set_void_at_pos(last));
+        assert_eq!(*tree.num_free_nodes(), num_free);
+        for &pos in around.iter() {
+            assert_eq!(tree.get_at_pos(pos).is_surface(), is_exposed(&tree, pos), "{:?}", pos);
+        }
+        assert_consistent(&tree);
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+        let loaded = Tree::read_from(&mut &bytes[..], NoCompression).unwrap();
+        assert_consistent(&loaded);
+        assert_eq!(loaded.num_free_nodes(), tree.num_free_nodes());
+        for &pos in boxes.iter().chain(around.iter()) {
+            assert_eq!(loaded.get_at_pos(pos).value(), tree.get_at_pos(pos).value(), "{:?}", pos);
+        }
+        let mut saved_again = Vec::new();
+        loaded.write_to(&mut saved_again).unwrap();
+        assert!(saved_again == bytes);
+        // Removing all boxes returns every node.
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(side_len - 1, side_len - 1, side_len - 1), Leaf::void()).is_ok());
+        assert_eq!(*tree.num_free_nodes(), num_free_nodes);
+        assert!(tree.far_nodes_.is_empty());
+        assert_consistent(&tree);
+    }
+
+    #[test]
+    fn check_finds_corruption() {
+        let mut tree = Tree::new(2, 16, NoCompression);
+        let leaf = Leaf::from_solid_box_spec(true, 2);
+        assert!(tree.fill_region(na::Vector3::new(2, 2, 2), na::Vector3::new(20, 5, 5), leaf).is_ok());
+        assert!(tree.set_at_pos(na::Vector3::new(3, 3, 3), Leaf::void()));
+        assert!(tree.check().is_empty());
+        let node_index = tree.infos_.iter().rposition(|info| info.num_solid_children > 0).unwrap() as u32;
+        tree.infos_[node_index as usize].num_solid_children -= 1;
+        assert!(tree.check().contains(&Violation::WrongCounters { node_index: node_index }));
+        tree.infos_[node_index as usize].num_solid_children += 1;
+        tree.num_free_nodes_ += 1;
+        let expected = tree.num_free_nodes_ - 1;
+        assert_eq!(tree.check(), vec![Violation::FreeNodeCount { num_free_nodes: expected + 1, expected: expected }]);
+    }
+
+    #[test]
+    fn exposed_faces() {
+        let mut tree = Tree::new(3, 400, NoCompression);
+        for z in 0..12u32 {
+            for x in 0..12u32 {
+                let height = (x * 7 + z * 3) % 30;
+                let leaf = Leaf::from_solid_box_spec(x % 3 != 0, 1 + (x % 5) as u16);
+                assert!(tree.fill_region(na::Vector3::new(x * 5, 0, z * 5), na::Vector3::new(x * 5 + 4, height, z * 5 + 4), leaf).is_ok());
+            }
+        }
+        assert!(tree.fill_region(na::Vector3::new(64, 64, 64), na::Vector3::new(79, 79, 79), Leaf::from_solid_box_spec(false, 3)).is_ok());
+        let origin = na::Point3::new(30.5, 100.5, -20.5);
+        let planes = view_planes(na::Vector3::new(0.3, -0.6, 1.0));
+        for &order in [ViewOrder::Index, ViewOrder::FrontToBack].iter() {
+            let options = ViewOptions { order: order, .. Default::default() };
+            let mut histogram = [0usize; 7];
+            tree.cast_view_with(origin, planes, 1000.0, &options, &mut |pos, depth, child, faces| {
+                assert_eq!(depth, 0);
+                // A face is exposed, unless the box next to it is solid or of the same type.
+                let mut expected = 0;
+                for face in 0..6 {
+                    let mut neighbor = pos;
+                    neighbor[face / 2] = if face % 2 == 1 { neighbor[face / 2] + 1 } else { neighbor[face / 2].wrapping_sub(1) & 4095 };
+                    let leaf = tree.get_at_pos(neighbor);
+                    if !leaf.is_solid() && leaf.ext_spec() != child.ext_spec() {
+                        expected |= 1 << face;
+                    }
+                }
+                assert_eq!(faces, expected, "{:?}", pos);
+                assert_eq!(faces, tree.exposed_faces(pos));
+                histogram[faces.count_ones() as usize] += 1;
+            });
+            assert!(histogram[0] > 0 && histogram[1] > 0 && histogram[3] > 0, "{:?}", histogram);
         }
+        // A transparent cube only shows its outer faces.
+        assert_eq!(tree.exposed_faces(na::Vector3::new(70, 70, 70)), 0);
+        assert_eq!(tree.exposed_faces(na::Vector3::new(64, 70, 70)), FACE_NEG_X_MASK);
     }
 }
@@ -0,0 +1,322 @@
+use nalgebra as na;
+use std::collections::{HashMap, HashSet};
+use boxtree::{Tree, Compression, Leaf, OutOfNodes, CHUNK_SIDE_LEN_LOG2};
+
+/// A change of a single box, recorded with the extended box specifiers before and after the change.
+#[derive(Copy, Clone)]
+struct Edit {
+    pos: na::Vector3<u32>,
+    old_ext_spec: u16,
+    new_ext_spec: u16,
+}
+
+/// Named group of edits, which is undone and redone as a whole.
+struct Transaction {
+    name: String,
+    edits: Vec<Edit>,
+    /// Index of the edit of each changed position, while the transaction is open.
+    edit_indices: HashMap<(u32, u32, u32), usize>,
+}
+impl Transaction {
+    fn new(name: &str) -> Self {
+        Transaction {
+            name: name.to_string(),
+            edits: Vec::new(),
+            edit_indices: HashMap::new(),
+        }
+    }
+}
+
+/// A tree, whose edits are recorded, so that they can be undone and redone. Edits are grouped into
+/// named transactions. An edit outside of a transaction forms a transaction of its own with an
+/// empty name. The surface flags are recalculated when undoing or redoing, just like for any
+/// other edit. Only boxes set through the journaled tree are recorded, so regions are filled
+/// (see Tree::fill_region) or schematics are pasted (see Schematic::paste_into) into the tree,
+/// before it is journaled.
+pub struct JournaledTree<C: Compression> {
+    tree_: Tree<C>,
+    undo_: Vec<Transaction>,
+    redo_: Vec<Transaction>,
+    open_: Option<Transaction>,
+}
+impl<C: Compression> JournaledTree<C> {
+    /// Creates a journaled tree from a tree with an empty journal.
+    pub fn new(tree: Tree<C>) -> Self {
+        JournaledTree {
+            tree_: tree,
+            undo_: Vec::new(),
+            redo_: Vec::new(),
+            open_: None,
+        }
+    }
+    pub fn tree(&self) -> &Tree<C> {
+        &self.tree_
+    }
+    pub fn into_tree(self) -> Tree<C> {
+        self.tree_
+    }
+    /// Get the type of a box at a specific position.
+    pub fn get_at_pos(&self, pos: na::Vector3<u32>) -> Leaf {
+        self.tree_.get_at_pos(pos)
+    }
+    /// Opens a transaction, which collects all following edits until it is ended. An open
+    /// transaction is ended first.
+    pub fn begin_transaction(&mut self, name: &str) {
+        self.end_transaction();
+        self.open_ = Some(Transaction::new(name));
+    }
+    /// Ends the open transaction, if any. Transactions without changes are dropped.
+    pub fn end_transaction(&mut self) {
+        if let Some(mut transaction) = self.open_.take() {
+            if !transaction.edits.is_empty() {
+                transaction.edit_indices = HashMap::new();
+                self.undo_.push(transaction);
+            }
+        }
+    }
+    /// Sets a box at a specific position and records the change. Returns false, if there are not
+    /// enough free nodes available.
+    pub fn set_at_pos(&mut self, pos: na::Vector3<u32>, new_leaf: Leaf) -> bool {
+        self.try_set_at_pos(pos, new_leaf).is_ok()
+    }
+    /// Sets a box at a specific position and records the change. Nothing is recorded, if there are
+    /// not enough free nodes available.
+    pub fn try_set_at_pos(&mut self, pos: na::Vector3<u32>, new_leaf: Leaf) -> Result<(), OutOfNodes> {
+        let old_ext_spec = self.tree_.get_at_pos(pos).ext_spec();
+        try!(self.tree_.try_set_at_pos(pos, new_leaf));
+        if old_ext_spec == new_leaf.ext_spec() {
+            return Ok(());
+        }
+        self.redo_.clear();
+        let single = self.open_.is_none();
+        if single {
+            self.open_ = Some(Transaction::new(""));
+        }
+        {
+            let transaction = self.open_.as_mut().unwrap();
+            let key = (pos.x, pos.y, pos.z);
+            if let Some(&index) = transaction.edit_indices.get(&key) {
+                // Keep the state from before the transaction.
+                transaction.edits[index].new_ext_spec = new_leaf.ext_spec();
+            } else {
+                transaction.edit_indices.insert(key, transaction.edits.len());
+                transaction.edits.push(Edit {
+                    pos: pos,
+                    old_ext_spec: old_ext_spec,
+                    new_ext_spec: new_leaf.ext_spec(),
+                });
+            }
+        }
+        if single {
+            self.end_transaction();
+        }
+        Ok(())
+    }
+    /// Name of the transaction, which would be undone next.
+    pub fn undo_name(&self) -> Option<&str> {
+        self.undo_.last().map(|transaction| &transaction.name[..])
+    }
+    /// Name of the transaction, which would be redone next.
+    pub fn redo_name(&self) -> Option<&str> {
+        self.redo_.last().map(|transaction| &transaction.name[..])
+    }
+    pub fn num_undos(&self) -> usize {
+        self.undo_.len()
+    }
+    pub fn num_redos(&self) -> usize {
+        self.redo_.len()
+    }
+    /// Forgets all recorded transactions.
+    pub fn clear(&mut self) {
+        self.open_ = None;
+        self.undo_.clear();
+        self.redo_.clear();
+    }
+    /// Reverts the last transaction, after ending the open transaction. Returns false, if there
+    /// is nothing to undo. If there may not be enough free nodes available (see
+    /// Tree::max_nodes_needed), nothing is reverted and the transaction stays undoable.
+    pub fn undo(&mut self) -> Result<bool, OutOfNodes> {
+        self.end_transaction();
+        let transaction = match self.undo_.pop() {
+            Some(transaction) => transaction,
+            None => return Ok(false),
+        };
+        let edits = transaction.edits.iter().rev().map(|edit| (edit.pos, edit.old_ext_spec));
+        if let Err(err) = Self::apply(&mut self.tree_, &transaction, edits) {
+            self.undo_.push(transaction);
+            return Err(err);
+        }
+        self.redo_.push(transaction);
+        Ok(true)
+    }
+    /// Applies the last undone transaction again. Returns false, if there is nothing to redo. If
+    /// there may not be enough free nodes available, nothing is applied and the transaction stays
+    /// redoable.
+    pub fn redo(&mut self) -> Result<bool, OutOfNodes> {
+        self.end_transaction();
+        let transaction = match self.redo_.pop() {
+            Some(transaction) => transaction,
+            None => return Ok(false),
+        };
+        let edits = transaction.edits.iter().map(|edit| (edit.pos, edit.new_ext_spec));
+        if let Err(err) = Self::apply(&mut self.tree_, &transaction, edits) {
+            self.redo_.push(transaction);
+            return Err(err);
+        }
+        self.undo_.push(transaction);
+        Ok(true)
+    }
+    /// Sets the boxes at the positions of the edits of a transaction to the extended box
+    /// specifiers. Nothing is set, if the nodes needed for the nodes at depth 0 containing the
+    /// boxes may be more than the free nodes available.
+    fn apply<I: Iterator<Item = (na::Vector3<u32>, u16)>>(tree: &mut Tree<C>, transaction: &Transaction, edits: I) -> Result<(), OutOfNodes> {
+        let shift = CHUNK_SIDE_LEN_LOG2 as u32;
+        let side_len = 1u32 << shift;
+        let nodes: HashSet<(u32, u32, u32)> = transaction.edits.iter()
+            .map(|edit| (edit.pos.x >> shift, edit.pos.y >> shift, edit.pos.z >> shift))
+            .collect();
+        let mut num_nodes = 0u32;
+        for &(x, y, z) in nodes.iter() {
+            let min = na::Vector3::new(x << shift, y << shift, z << shift);
+            let max = na::Vector3::new(min.x + side_len - 1, min.y + side_len - 1, min.z + side_len - 1);
+            num_nodes = num_nodes.saturating_add(tree.max_nodes_needed(min, max));
+        }
+        if num_nodes > *tree.num_free_nodes() {
+            return Err(OutOfNodes);
+        }
+        for (pos, ext_spec) in edits {
+            // The nodes needed have been counted above, so this only fails, if the count is off.
+            try!(tree.try_set_at_pos(pos, Leaf::from_ext_spec(ext_spec)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra as na;
+    use boxtree::{Tree, Leaf, NoCompression, DictionaryCompression, OutOfNodes};
+    use super::JournaledTree;
+
+    #[test]
+    fn undo_redo() {
+        let mut tree = JournaledTree::new(Tree::new(3, 100, NoCompression));
+        let pos = |x, y, z| na::Vector3::new(x, y, z);
+        assert!(tree.set_at_pos(pos(1, 1, 1), Leaf::from_solid_box_spec(true, 1)));
+        tree.begin_transaction("wall");
+        for x in 0..5 {
+            assert!(tree.set_at_pos(pos(x, 2, 1), Leaf::from_solid_box_spec(true, 2)));
+        }
+        assert!(tree.set_at_pos(pos(0, 2, 1), Leaf::from_solid_box_spec(true, 3)));
+        assert!(tree.set_at_pos(pos(1, 1, 1), Leaf::void()));
+        tree.end_transaction();
+        assert_eq!(tree.num_undos(), 2);
+        assert_eq!(tree.undo_name(), Some("wall"));
+        let wall: Vec<u16> = (0..6).map(|x| tree.get_at_pos(pos(x, 2, 1)).ext_spec()).collect();
+        assert_eq!(tree.undo(), Ok(true));
+        assert!(tree.get_at_pos(pos(0, 2, 1)).is_void());
+        assert_eq!(tree.get_at_pos(pos(1, 1, 1)).box_spec(), 1);
+        assert!(tree.tree().check().is_empty());
+        assert_eq!(tree.redo_name(), Some("wall"));
+        assert_eq!(tree.redo(), Ok(true));
+        let again: Vec<u16> = (0..6).map(|x| tree.get_at_pos(pos(x, 2, 1)).ext_spec()).collect();
+        assert_eq!(again, wall);
+        assert_eq!(tree.undo(), Ok(true));
+        assert_eq!(tree.undo(), Ok(true));
+        assert_eq!(tree.undo(), Ok(false));
+        // Nodes, which became entirely void, have been returned to the free nodes.
+        assert!(tree.tree().chunks()[0][0].is_void());
+        assert_eq!(*tree.tree().num_free_nodes(), 99);
+        assert!(tree.tree().check().is_empty());
+        // A new edit drops the transactions, which could be redone.
+        assert_eq!(tree.redo(), Ok(true));
+        assert!(tree.set_at_pos(pos(7, 7, 7), Leaf::from_solid_box_spec(true, 4)));
+        assert_eq!(tree.num_redos(), 0);
+    }
+
+    #[test]
+    fn undo_out_of_nodes() {
+        let leaf = Leaf::from_solid_box_spec(true, 1);
+        let mut tree = Tree::new(3, 3, NoCompression);
+        assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(255, 255, 255), leaf).is_ok());
+        let mut tree = JournaledTree::new(tree);
+        // Digging a hole splits the sub-cube down to depth 0.
+        assert!(tree.set_at_pos(na::Vector3::new(8, 8, 8), Leaf::void()));
+        assert_eq!(*tree.tree().num_free_nodes(), 0);
+        // Closing it again frees the nodes for a box somewhere else.
+        tree.begin_transaction("move");
+        assert!(tree.set_at_pos(na::Vector3::new(8, 8, 8), leaf));
+        assert!(tree.set_at_pos(na::Vector3::new(1000, 0, 0), leaf));
+        tree.end_transaction();
+        assert_eq!(*tree.tree().num_free_nodes(), 0);
+        // Undoing would dig the hole, before the box is removed, so nothing is undone.
+        assert_eq!(tree.undo(), Err(OutOfNodes));
+        assert_eq!(tree.num_undos(), 2);
+        assert_eq!(tree.undo_name(), Some("move"));
+        assert_eq!(tree.get_at_pos(na::Vector3::new(8, 8, 8)).box_spec(), 1);
+        assert_eq!(tree.get_at_pos(na::Vector3::new(1000, 0, 0)).box_spec(), 1);
+        assert!(tree.tree().check().is_empty());
+    }
+
+    #[test]
+    fn undo_redo_random() {
+        let mut seed = 3u64;
+        let mut random = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as u32
+        };
+        for &max_nodes in [6, 10, 16, 30].iter() {
+            let mut tree = Tree::new(3, max_nodes, DictionaryCompression::new());
+            assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(63, 31, 63), Leaf::from_solid_box_spec(true, 1)).is_ok());
+            let mut tree = JournaledTree::new(tree);
+            let positions: Vec<_> = (0..120).map(|_| na::Vector3::new(random() % 64, random() % 40, random() % 64)).collect();
+            let boxes = |tree: &JournaledTree<DictionaryCompression>| -> Vec<u16> {
+                positions.iter().map(|&pos| tree.get_at_pos(pos).ext_spec()).collect()
+            };
+            let mut states = vec![boxes(&tree)];
+            for (i, transaction) in positions.chunks(20).enumerate() {
+                tree.begin_transaction(&i.to_string());
+                for &pos in transaction.iter() {
+                    let spec = random() % 3;
+                    let leaf = if spec == 0 { Leaf::void() } else { Leaf::from_solid_box_spec(true, 1 + spec as u16) };
+                    tree.set_at_pos(pos, leaf);
+                }
+                tree.end_transaction();
+                if tree.num_undos() == states.len() {
+                    states.push(boxes(&tree));
+                }
+            }
+            // Either a transaction is undone or redone as a whole or nothing is changed.
+            while tree.num_undos() > 0 {
+                let num_undos = tree.num_undos();
+                match tree.undo() {
+                    Ok(undone) => {
+                        assert!(undone);
+                        assert!(boxes(&tree) == states[num_undos - 1]);
+                    },
+                    Err(_) => {
+                        assert!(boxes(&tree) == states[num_undos]);
+                        assert_eq!(tree.num_undos(), num_undos);
+                        break;
+                    },
+                }
+                assert!(tree.tree().check().is_empty());
+            }
+            while tree.num_redos() > 0 {
+                let num_undos = tree.num_undos();
+                match tree.redo() {
+                    Ok(redone) => {
+                        assert!(redone);
+                        assert!(boxes(&tree) == states[num_undos + 1]);
+                    },
+                    Err(_) => {
+                        assert!(boxes(&tree) == states[num_undos]);
+                        assert_eq!(tree.num_undos(), num_undos);
+                        break;
+                    },
+                }
+                assert!(tree.tree().check().is_empty());
+            }
+        }
+    }
+}
@@ -11,22 +11,17 @@ use nalgebra as na;
 pub mod boxtree;
 pub mod boxmodel;
 pub mod camera;
+pub mod journal;
+pub mod mesher;
+pub mod paging;
+pub mod schematic;
 
 use std::time::{Duration, Instant, SystemTime};
 
 fn main() {
     let mut max_dist: f32 = 100.0;
 
-    struct Compression;
-    impl boxtree::Compression for Compression {
-        fn compress(&mut self, pos: na::Vector3<u32>, depth: u8, chunk: &boxtree::Chunk<boxtree::HiddenLeaf>) -> Option<boxtree::HiddenLeaf> {
-            panic!("not jet implemented");
-        }
-        fn decompress(&mut self, pos: na::Vector3<u32>, depth: u8, leaf: boxtree::HiddenLeaf, chunk: &mut boxtree::Chunk<boxtree::HiddenLeaf>) {
-            panic!("not jet implemented");
-        }
-    }
-    let mut box_tree = boxtree::Tree::new(7, 1 << boxtree::NODE_INDEX_BITS, Compression);
+    let mut box_tree = boxtree::Tree::new(7, 1 << 20, boxtree::DictionaryCompression::new());
 
     use std::io::Cursor;
     let image = image::load(Cursor::new(&include_bytes!("test.png")[..]), image::PNG).unwrap().to_rgba();
@@ -43,6 +38,7 @@ fn main() {
             }
         }
     }
+    println!("{}", box_tree.stats());
 
     let mut fly_cam = camera::FlyCam::new();
     fly_cam.translate(na::Vector3::new(0.0, 0.0, 10.0));
@@ -92,13 +88,25 @@ fn main() {
         backface_culling: glium::draw_parameters::BackfaceCullingMode::CullCounterClockwise,
         .. Default::default()
     };
+    let transparent_params = glium::DrawParameters {
+        depth: glium::Depth {
+            test: glium::draw_parameters::DepthTest::IfLess,
+            write: false,
+            .. Default::default()
+        },
+        blend: glium::Blend::alpha_blending(),
+        backface_culling: glium::draw_parameters::BackfaceCullingMode::CullCounterClockwise,
+        .. Default::default()
+    };
 
     let mut time = Instant::now();
     let mut elapsed = Duration::new(0, 0);
     let mut frames: usize = 0;
+    let mut num_instances: usize;
     loop {
-        let mut per_instance = {
+        let (per_instance, per_transparent_instance) = {
             let mut data: Vec<boxmodel::Instance> = Vec::new();
+            let mut transparent_data: Vec<boxmodel::Instance> = Vec::new();
             let isometry = fly_cam.isometry64();
             let origin = isometry * na::Point3::new(0.0f64, 0.0, 0.0);
             let d = display.get_framebuffer_dimensions();
@@ -112,15 +120,44 @@ fn main() {
             for mut p in planes.iter_mut() {
                 *p = isometry * p.normalize();
             }
-            box_tree.cast_view(origin, planes, max_dist as f64, &mut |box_pos: na::Vector3<u32>, leaf| {
-                data.push(
-                    boxmodel::Instance {
-                        box_pos: [box_pos.x as f32, box_pos.y as f32, box_pos.z as f32],
-                        box_type: 0/*leaf.box_spec() as u32*/ as f32,
-                    }
-                );
+            let options = boxtree::ViewOptions {
+                // Occlusion culling traverses on the calling thread only.
+                num_threads: 1,
+                // The vertical field of view is 90 degrees.
+                lod: Some(boxtree::Lod {
+                    pixels_per_unit: display.get_framebuffer_dimensions().1 as f64 * 0.5,
+                    min_pixels: 1.0,
+                }),
+                occlusion: Some(boxtree::Occlusion {
+                    resolution: 128,
+                }),
+                order: boxtree::ViewOrder::FrontToBack,
+            };
+            box_tree.cast_view_with(origin, planes, max_dist as f64, &options, &mut |box_pos: na::Vector3<u32>, depth: u8, leaf: boxtree::Child, faces: u8| {
+                // Boxes may be part of the surface only through their edges.
+                if faces == 0 {
+                    return;
+                }
+                let instance = boxmodel::Instance {
+                    box_pos: [box_pos.x as f32, box_pos.y as f32, box_pos.z as f32],
+                    box_type: 0/*leaf.box_spec() as u32*/ as f32,
+                    box_size: (1u32 << (depth * boxtree::CHUNK_SIDE_LEN_LOG2)) as f32,
+                    face_mask: faces as f32,
+                };
+                // Transparent boxes are drawn in a separate blended pass.
+                if leaf.is_solid() {
+                    data.push(instance);
+                } else {
+                    transparent_data.push(instance);
+                }
             });
-            glium::vertex::VertexBuffer::new(&display, &data).unwrap()
+            // Blending needs the transparent boxes back to front.
+            transparent_data.reverse();
+            num_instances = data.len() + transparent_data.len();
+            (
+                glium::vertex::VertexBuffer::new(&display, &data).unwrap(),
+                glium::vertex::VertexBuffer::new(&display, &transparent_data).unwrap()
+            )
         };
 
         let mut target = display.draw();
@@ -132,6 +169,13 @@ fn main() {
             &tiles,
             &params
         );
+        box_model.draw(
+            &mut target,
+            (*persp_mat.as_matrix() * fly_cam.isometry().inverse_transformation().to_homogeneous()).as_ref(),
+            &per_transparent_instance,
+            &tiles,
+            &transparent_params
+        );
         target.finish().unwrap();
 
         for ev in display.poll_events() {
@@ -152,7 +196,7 @@ fn main() {
         frames += 1;
         let mut e = time.elapsed() - elapsed;
         while e.as_secs() >= 1 {
-            println!("MD: {} FPS: {}", max_dist as u32, frames);
+            println!("MD: {} FPS: {} Instances: {}", max_dist as u32, frames, num_instances);
             frames = 0;
             e -= Duration::new(1, 0);
             elapsed += Duration::new(1, 0);
@@ -0,0 +1,303 @@
+use nalgebra as na;
+use std::collections::{HashMap, HashSet};
+use boxtree::{Tree, Compression, CHUNK_SIDE_LEN, CHUNK_SIDE_LEN_LOG2, CHUNK_LEN, SOLID_BIT_MASK, BOX_SPEC_BIT_MASK};
+use schematic::Schematic;
+
+/// Vertex of a mesh.
+#[derive(Copy, Clone)]
+pub struct Vertex {
+    /// Position of the vertex in the world.
+    pub position: [f32; 3],
+    /// Normal of the face.
+    pub normal: [f32; 3],
+    /// The box specifier of the boxes of the face.
+    pub box_type: f32,
+    /// Face index between 0 and 5 in the order of the face masks of the tree.
+    pub face: f32,
+    /// Texture coordinate in units of boxes, so that a texture repeats once per box.
+    pub tex_coord: [f32; 2],
+}
+implement_vertex!(Vertex, position, normal, box_type, face, tex_coord);
+
+/// Triangle mesh of the exposed faces of the boxes of a node at depth 0. Adjacent faces of boxes
+/// of the same type in the same plane are merged into a single quad. The solid and the
+/// transparent quads share the vertices, but are indexed separately, so that the transparent ones
+/// can be drawn in a blended pass.
+#[derive(Clone, Default)]
+pub struct Mesh {
+    /// Four vertices per quad.
+    pub vertices: Vec<Vertex>,
+    /// Indices of the triangles of the solid quads.
+    pub indices: Vec<u32>,
+    /// Indices of the triangles of the transparent quads.
+    pub transparent_indices: Vec<u32>,
+}
+impl Mesh {
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty()
+    }
+    pub fn num_quads(&self) -> usize {
+        self.vertices.len() / 4
+    }
+    /// Adds the quad of a face covering width x height boxes. The face is in the slice of the
+    /// node along the axis of the face and the quad starts at the position u, v within the slice
+    /// along the two other axes in x, y, z order.
+    fn push_quad(
+        &mut self, min: na::Vector3<u32>, face: u8, slice: u32, u: u32, v: u32, width: u32, height: u32,
+        ext_spec: u16
+    ) {
+        let axis = (face / 2) as usize;
+        let u_axis = (axis + 1) % 3;
+        let v_axis = (axis + 2) % 3;
+        let mut normal = [0.0f32; 3];
+        normal[axis] = if (face & 1) != 0 { 1.0 } else { -1.0 };
+        let first = self.vertices.len() as u32;
+        for &(du, dv) in [(0, 0), (width, 0), (width, height), (0, height)].iter() {
+            let mut position = [min.x as f32, min.y as f32, min.z as f32];
+            position[axis] += (slice + (face & 1) as u32) as f32;
+            position[u_axis] += (u + du) as f32;
+            position[v_axis] += (v + dv) as f32;
+            self.vertices.push(Vertex {
+                position: position,
+                normal: normal,
+                box_type: (ext_spec & BOX_SPEC_BIT_MASK) as f32,
+                face: face as f32,
+                tex_coord: [du as f32, dv as f32],
+            });
+        }
+        // Like the faces of boxmodel::Model, the triangles are clockwise seen from outside.
+        let order: [u32; 6] = if (face & 1) != 0 { [0, 3, 2, 2, 1, 0] } else { [0, 1, 2, 2, 3, 0] };
+        let indices = if (ext_spec & SOLID_BIT_MASK) != 0 {
+            &mut self.indices
+        } else {
+            &mut self.transparent_indices
+        };
+        indices.extend(order.iter().map(|&i| first + i));
+    }
+}
+
+/// Meshes the node at depth 0 at a specific position in units of its side length. The faces are
+/// exposed, if the tree reports them as exposed (see Tree::exposed_faces), so faces at the
+/// boundary of the node take the neighbor nodes into account.
+pub fn mesh_node<C: Compression>(tree: &Tree<C>, node_pos: na::Vector3<u32>) -> Mesh {
+    let side_len = CHUNK_SIDE_LEN as u32;
+    let min = node_pos * side_len;
+    let max = na::Vector3::new(min.x + side_len - 1, min.y + side_len - 1, min.z + side_len - 1);
+    let boxes = Schematic::from_tree(tree, min, max);
+    let box_index = |pos: na::Vector3<u32>| {
+        (pos.x | (pos.y << CHUNK_SIDE_LEN_LOG2) | (pos.z << (2 * CHUNK_SIDE_LEN_LOG2))) as usize
+    };
+    // Exposed faces of each box.
+    let mut faces = vec![0u8; CHUNK_LEN as usize];
+    for z in 0..side_len {
+        for y in 0..side_len {
+            for x in 0..side_len {
+                let pos = na::Vector3::new(x, y, z);
+                let leaf = boxes.get(pos);
+                if leaf.is_void() {
+                    continue;
+                }
+                // Solid boxes surrounded by solid boxes of the same node have no exposed faces.
+                let hidden = leaf.is_solid() && (0..6).all(|face| {
+                    let axis = face / 2;
+                    let mut neighbor_pos = pos;
+                    if (face & 1) != 0 {
+                        if neighbor_pos[axis] == side_len - 1 {
+                            return false;
+                        }
+                        neighbor_pos[axis] += 1;
+                    } else {
+                        if neighbor_pos[axis] == 0 {
+                            return false;
+                        }
+                        neighbor_pos[axis] -= 1;
+                    }
+                    boxes.get(neighbor_pos).is_solid()
+                });
+                if !hidden {
+                    faces[box_index(pos)] = tree.exposed_faces(min + pos);
+                }
+            }
+        }
+    }
+    let mut mesh = Mesh::default();
+    // Extended box specifiers plus 1 of the boxes of a slice with an exposed face, 0 elsewhere.
+    let mut keys = vec![0u32; (side_len * side_len) as usize];
+    for face in 0..6u8 {
+        let axis = (face / 2) as usize;
+        let u_axis = (axis + 1) % 3;
+        let v_axis = (axis + 2) % 3;
+        for slice in 0..side_len {
+            for v in 0..side_len {
+                for u in 0..side_len {
+                    let mut pos = na::Vector3::new(0, 0, 0);
+                    pos[axis] = slice;
+                    pos[u_axis] = u;
+                    pos[v_axis] = v;
+                    keys[(u + v * side_len) as usize] = if (faces[box_index(pos)] & (1 << face)) != 0 {
+                        boxes.get(pos).ext_spec() as u32 + 1
+                    } else {
+                        0
+                    };
+                }
+            }
+            // Grow each quad along u first and then along v as long as all faces match.
+            for v in 0..side_len {
+                let mut u = 0;
+                while u < side_len {
+                    let key = keys[(u + v * side_len) as usize];
+                    if key == 0 {
+                        u += 1;
+                        continue;
+                    }
+                    let mut width = 1;
+                    while u + width < side_len && keys[(u + width + v * side_len) as usize] == key {
+                        width += 1;
+                    }
+                    let mut height = 1;
+                    'grow: while v + height < side_len {
+                        for i in 0..width {
+                            if keys[(u + i + (v + height) * side_len) as usize] != key {
+                                break 'grow;
+                            }
+                        }
+                        height += 1;
+                    }
+                    for j in 0..height {
+                        for i in 0..width {
+                            keys[(u + i + (v + j) * side_len) as usize] = 0;
+                        }
+                    }
+                    mesh.push_quad(min, face, slice, u, v, width, height, (key - 1) as u16);
+                    u += width;
+                }
+            }
+        }
+    }
+    mesh
+}
+
+/// Cache of the meshes of nodes at depth 0. Each mesh is kept, or whatever it was turned into
+/// like an uploaded vertex buffer, until the boxes of its node or of the boxes around it change.
+/// The changes are taken from the dirty regions of the tree (see Tree::track_dirty_regions).
+pub struct MeshCache<T> {
+    meshes_: HashMap<(u32, u32, u32), T>,
+}
+impl<T> MeshCache<T> {
+    pub fn new() -> Self {
+        MeshCache {
+            meshes_: HashMap::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.meshes_.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.meshes_.is_empty()
+    }
+    pub fn contains(&self, node_pos: na::Vector3<u32>) -> bool {
+        self.meshes_.contains_key(&(node_pos.x, node_pos.y, node_pos.z))
+    }
+    pub fn clear(&mut self) {
+        self.meshes_.clear();
+    }
+    /// Get the cached value of the node at depth 0 at a specific position in units of its side
+    /// length. If there is none, the node is meshed and the mesh is turned into the value first.
+    pub fn get<C: Compression, F: FnOnce(Mesh) -> T>(&mut self, tree: &Tree<C>, node_pos: na::Vector3<u32>, build: F) -> &T {
+        self.meshes_
+            .entry((node_pos.x, node_pos.y, node_pos.z))
+            .or_insert_with(|| build(mesh_node(tree, node_pos)))
+    }
+    /// Drops the values of the nodes within the dirty regions of the tree and forgets the dirty
+    /// regions. All values are dropped, if the tree doesn't track dirty regions.
+    pub fn invalidate<C: Compression>(&mut self, tree: &mut Tree<C>) {
+        let depth = match *tree.dirty_depth() {
+            Some(depth) => depth,
+            None => {
+                self.meshes_.clear();
+                return;
+            },
+        };
+        let regions = tree.drain_dirty_regions();
+        if depth == 0 {
+            for pos in regions.iter() {
+                self.meshes_.remove(&(
+                    pos.x >> CHUNK_SIDE_LEN_LOG2,
+                    pos.y >> CHUNK_SIDE_LEN_LOG2,
+                    pos.z >> CHUNK_SIDE_LEN_LOG2,
+                ));
+            }
+            return;
+        }
+        let shift = (depth * CHUNK_SIDE_LEN_LOG2) as u32;
+        let regions: HashSet<(u32, u32, u32)> = regions.iter()
+            .map(|pos| (pos.x >> shift, pos.y >> shift, pos.z >> shift))
+            .collect();
+        let shift = shift - CHUNK_SIDE_LEN_LOG2 as u32;
+        self.meshes_.retain(|&(x, y, z), _| !regions.contains(&(x >> shift, y >> shift, z >> shift)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra as na;
+    use boxtree::{Tree, Leaf, NoCompression};
+    use super::{mesh_node, Mesh, MeshCache};
+
+    #[test]
+    fn single_box() {
+        let mut tree = Tree::new(2, 16, NoCompression);
+        assert!(tree.set_at_pos(na::Vector3::new(3, 4, 5), Leaf::from_solid_box_spec(true, 2)));
+        let mesh = mesh_node(&tree, na::Vector3::new(0, 0, 0));
+        assert_eq!(mesh.num_quads(), 6);
+        assert_eq!(mesh.indices.len(), 36);
+        assert!(mesh.transparent_indices.is_empty());
+        assert!(mesh_node(&tree, na::Vector3::new(1, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn transparent_box() {
+        let mut tree = Tree::new(2, 16, NoCompression);
+        assert!(tree.set_at_pos(na::Vector3::new(3, 4, 5), Leaf::from_solid_box_spec(false, 2)));
+        let mesh = mesh_node(&tree, na::Vector3::new(0, 0, 0));
+        assert_eq!(mesh.num_quads(), 6);
+        assert!(mesh.indices.is_empty());
+        assert_eq!(mesh.transparent_indices.len(), 36);
+    }
+
+    #[test]
+    fn merged_faces() {
+        let mut tree = Tree::new(2, 16, NoCompression);
+        let leaf = Leaf::from_solid_box_spec(true, 2);
+        assert!(tree.fill_region(na::Vector3::new(1, 1, 1), na::Vector3::new(4, 3, 2), leaf).is_ok());
+        assert_eq!(mesh_node(&tree, na::Vector3::new(0, 0, 0)).num_quads(), 6);
+        // The faces of the corner box are split off the three faces it is part of, which become
+        // L-shaped and take two quads each.
+        assert!(tree.set_at_pos(na::Vector3::new(1, 1, 1), Leaf::from_solid_box_spec(true, 3)));
+        assert_eq!(mesh_node(&tree, na::Vector3::new(0, 0, 0)).num_quads(), 12);
+    }
+
+    #[test]
+    fn cache_invalidation() {
+        let mut tree = Tree::new(2, 16, NoCompression);
+        let leaf = Leaf::from_solid_box_spec(true, 2);
+        assert!(tree.set_at_pos(na::Vector3::new(3, 3, 3), leaf));
+        assert!(tree.set_at_pos(na::Vector3::new(35, 3, 3), leaf));
+        tree.track_dirty_regions(1);
+        let mut cache: MeshCache<Mesh> = MeshCache::new();
+        for &pos in [na::Vector3::new(0, 0, 0), na::Vector3::new(0, 0, 1), na::Vector3::new(2, 0, 0)].iter() {
+            cache.get(&tree, pos, |mesh| mesh);
+        }
+        assert_eq!(cache.len(), 3);
+        // A box at the boundary of a node changes the surface of the node next to it.
+        assert!(tree.set_at_pos(na::Vector3::new(3, 3, 15), leaf));
+        cache.invalidate(&mut tree);
+        assert!(!cache.contains(na::Vector3::new(0, 0, 0)));
+        assert!(!cache.contains(na::Vector3::new(0, 0, 1)));
+        assert!(cache.contains(na::Vector3::new(2, 0, 0)));
+        assert_eq!(cache.get(&tree, na::Vector3::new(0, 0, 0), |mesh| mesh).num_quads(), 12);
+        tree.untrack_dirty_regions();
+        cache.invalidate(&mut tree);
+        assert!(cache.is_empty());
+    }
+}
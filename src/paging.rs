@@ -0,0 +1,451 @@
+use nalgebra as na;
+use nalgebra::Dot;
+use std::collections::{HashMap, BTreeMap};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Seek, SeekFrom, Read, Write};
+use std::path::Path;
+use boxtree;
+use boxtree::{Tree, Compression, Leaf, Child, CHUNK_LEN, CHUNK_SIDE_LEN_LOG2};
+
+/// Position of the first box of a region, which identifies the region.
+type RegionKey = (u32, u32, u32);
+
+/// Whether any box of a region may be within the view of cast_view.
+fn is_region_visible(key: RegionKey, region_depth: u8, origin: na::Point3<f64>, planes: [na::Vector3<f64>; 4], dist: f64) -> bool {
+    let side_len = (1u64 << (region_depth * CHUNK_SIDE_LEN_LOG2)) as f64;
+    let min = na::Vector3::new(key.0 as f64 - origin.x, key.1 as f64 - origin.y, key.2 as f64 - origin.z);
+    let max = na::Vector3::new(min.x + side_len, min.y + side_len, min.z + side_len);
+    for plane in planes.iter() {
+        // The corner of the region, which is the farthest inside the plane.
+        let corner = na::Vector3::new(
+            if plane.x > 0.0 { max.x } else { min.x },
+            if plane.y > 0.0 { max.y } else { min.y },
+            if plane.z > 0.0 { max.z } else { min.z },
+        );
+        if plane.dot(&corner) < 0.0 {
+            return false;
+        }
+    }
+    region_dist_sq(key, region_depth, origin) <= dist * dist
+}
+
+/// Get the squared distance of the point of a region, which is the closest to the origin.
+fn region_dist_sq(key: RegionKey, region_depth: u8, origin: na::Point3<f64>) -> f64 {
+    let side_len = (1u64 << (region_depth * CHUNK_SIDE_LEN_LOG2)) as f64;
+    let min = na::Vector3::new(key.0 as f64 - origin.x, key.1 as f64 - origin.y, key.2 as f64 - origin.z);
+    let closest = na::Vector3::new(
+        min.x.max(0.0).min(min.x + side_len),
+        min.y.max(0.0).min(min.y + side_len),
+        min.z.max(0.0).min(min.z + side_len),
+    );
+    closest.dot(&closest)
+}
+
+/// A tree, whose regions are moved to a region file, when they haven't been used for a while and
+/// more nodes are in use than the budget allows. A region is the sub-cube of a child at a specific
+/// depth of the tree. A region, which has been moved out of the tree, is replaced by a paged leaf
+/// and is read back transparently, when it is accessed. The space within the region file of
+/// regions, which have been read back, is reused for regions moved out later and the file is
+/// truncated, when its end is not used anymore.
+pub struct PagedTree<C: Compression> {
+    tree_: Tree<C>,
+    region_depth_: u8,
+    max_resident_nodes_: u32,
+    file_: File,
+    file_len_: u64,
+    /// Offset and length within the region file and number of nodes of the regions, which have
+    /// been moved out.
+    paged_regions_: HashMap<RegionKey, (u64, u64, u32)>,
+    /// Length of the unused slots within the region file by their offset. Adjacent slots are
+    /// merged and there is no slot at the end of the file.
+    free_slots_: BTreeMap<u64, u64>,
+    /// Time of the last access of the regions, which may contain nodes.
+    last_accesses_: HashMap<RegionKey, u64>,
+    time_: u64,
+}
+impl<C: Compression> PagedTree<C> {
+    /// Creates a paged tree from a tree and creates or truncates the region file. The region depth
+    /// is the depth of the children, whose sub-cubes form the regions. When more than
+    /// max_resident_nodes nodes are in use after an access, the least recently accessed regions
+    /// are moved to the region file.
+    pub fn new<P: AsRef<Path>>(tree: Tree<C>, region_depth: u8, max_resident_nodes: u32, path: P) -> io::Result<Self> {
+        // Check for valid range of region depth.
+        if region_depth < 1 || region_depth >= *tree.depth() {
+            panic!(
+                "region_depth must be in range {} - {}, but {} was specified",
+                1,
+                *tree.depth() - 1,
+                region_depth
+            );
+        }
+        let file = try!(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path));
+        let mut paged_tree = PagedTree {
+            tree_: tree,
+            region_depth_: region_depth,
+            max_resident_nodes_: max_resident_nodes,
+            file_: file,
+            file_len_: 0,
+            paged_regions_: HashMap::new(),
+            free_slots_: BTreeMap::new(),
+            last_accesses_: HashMap::new(),
+            time_: 0,
+        };
+        paged_tree.collect_regions();
+        Ok(paged_tree)
+    }
+    pub fn tree(&self) -> &Tree<C> {
+        &self.tree_
+    }
+    pub fn region_depth(&self) -> &u8 {
+        &self.region_depth_
+    }
+    pub fn max_resident_nodes(&self) -> &u32 {
+        &self.max_resident_nodes_
+    }
+    pub fn set_max_resident_nodes(&mut self, max_resident_nodes: u32) {
+        self.max_resident_nodes_ = max_resident_nodes;
+    }
+    /// Number of nodes in use by the tree.
+    pub fn num_resident_nodes(&self) -> u32 {
+        *self.tree_.max_nodes() - *self.tree_.num_free_nodes()
+    }
+    /// Number of regions, which have been moved to the region file.
+    pub fn num_paged_regions(&self) -> usize {
+        self.paged_regions_.len()
+    }
+    /// Get the type of a box at a specific position.
+    pub fn get_at_pos(&mut self, pos: na::Vector3<u32>) -> io::Result<Leaf> {
+        self.time_ += 1;
+        let key = self.region_key(pos);
+        try!(self.access_region(key));
+        let leaf = self.tree_.get_at_pos(pos);
+        try!(self.page_out_cold_regions());
+        Ok(leaf)
+    }
+    /// Sets a box at a specific position. All regions, whose boxes may be looked at to update the
+    /// surface flags, are read back first. Returns false, if there are not enough free nodes
+    /// available, even after moving cold regions out of the tree.
+    pub fn set_at_pos(&mut self, pos: na::Vector3<u32>, new_leaf: Leaf) -> io::Result<bool> {
+        self.time_ += 1;
+        let mask = 1u32.wrapping_shl((*self.tree_.depth() * CHUNK_SIDE_LEN_LOG2) as u32).wrapping_sub(1);
+        // Regions are at least CHUNK_SIDE_LEN boxes wide, so the 5x5x5 neighborhood touches at
+        // most the regions of its corners.
+        for &dz in [!1u32, 2].iter() {
+            for &dy in [!1u32, 2].iter() {
+                for &dx in [!1u32, 2].iter() {
+                    let key = self.region_key(na::Vector3::new(
+                        pos.x.wrapping_add(dx) & mask,
+                        pos.y.wrapping_add(dy) & mask,
+                        pos.z.wrapping_add(dz) & mask,
+                    ));
+                    try!(self.access_region(key));
+                }
+            }
+        }
+        let mut set = self.tree_.set_at_pos(pos, new_leaf);
+        if !set {
+            try!(self.page_out_cold_regions());
+            set = self.tree_.set_at_pos(pos, new_leaf);
+        }
+        try!(self.page_out_cold_regions());
+        Ok(set)
+    }
+    /// Like Tree::cast_view, but reads back the regions, which may be visible, first. Visible
+    /// regions already in the tree are kept and the others are read back closest first as long as
+    /// they fit into the budget, while regions not used by the view are moved out to make room.
+    /// Visible regions, which don't fit, stay in the region file and are skipped by the view, so
+    /// the regions in use only change, when the view does.
+    pub fn cast_view<Callback: FnMut(na::Vector3<u32>, Child)>(
+        &mut self,
+        origin: na::Point3<f64>, planes: [na::Vector3<f64>; 4], dist: f64,
+        callback: &mut Callback
+    ) -> io::Result<()> {
+        self.time_ += 1;
+        let time = self.time_;
+        for (&key, last_access) in self.last_accesses_.iter_mut() {
+            if is_region_visible(key, self.region_depth_, origin, planes, dist) {
+                *last_access = time;
+            }
+        }
+        let mut keys: Vec<(f64, RegionKey)> = Vec::new();
+        for &key in self.paged_regions_.keys() {
+            if is_region_visible(key, self.region_depth_, origin, planes, dist) {
+                keys.push((region_dist_sq(key, self.region_depth_, origin), key));
+            }
+        }
+        keys.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for &(_, key) in keys.iter() {
+            let num_nodes = self.paged_regions_[&key].2;
+            if try!(self.make_room(num_nodes)) {
+                try!(self.access_region(key));
+            }
+        }
+        self.tree_.cast_view(origin, planes, dist, callback);
+        self.page_out_cold_regions()
+    }
+    /// Moves all regions, which contain nodes, to the region file.
+    pub fn page_out_all(&mut self) -> io::Result<()> {
+        let keys: Vec<RegionKey> = self.last_accesses_.keys().cloned().collect();
+        for &key in keys.iter() {
+            try!(self.page_out(key));
+        }
+        self.last_accesses_.clear();
+        Ok(())
+    }
+    fn region_key(&self, pos: na::Vector3<u32>) -> RegionKey {
+        let pos = boxtree::sub_cube_pos(pos, self.region_depth_);
+        (pos.x, pos.y, pos.z)
+    }
+    /// Registers the regions of the tree, which contain nodes.
+    fn collect_regions(&mut self) {
+        // Nodes with the position of the first box of their sub-cube and the depth of their
+        // children.
+        let mut nodes = vec![(0u32, na::Vector3::new(0u32, 0, 0), *self.tree_.depth() - 1)];
+        while let Some((node_index, pos, depth)) = nodes.pop() {
+            for index in 0..CHUNK_LEN {
+                if !self.tree_.chunks()[node_index as usize][index as usize].is_node() {
+                    continue;
+                }
+                let child_pos = boxtree::child_pos(pos, depth, index);
+                if depth == self.region_depth_ {
+                    self.last_accesses_.insert((child_pos.x, child_pos.y, child_pos.z), 0);
+                } else {
+                    nodes.push((self.tree_.child_node_index(node_index, index), child_pos, depth - 1));
+                }
+            }
+        }
+    }
+    /// Reads a region back from the region file, if it has been moved out, and marks it as
+    /// accessed by the current operation.
+    fn access_region(&mut self, key: RegionKey) -> io::Result<()> {
+        if let Some(&(offset, len, _)) = self.paged_regions_.get(&key) {
+            let mut bytes = vec![0u8; len as usize];
+            try!(self.file_.seek(SeekFrom::Start(offset)));
+            try!(self.file_.read_exact(&mut bytes));
+            unsafe {
+                try!(self.tree_.read_node_from(
+                    na::Vector3::new(key.0, key.1, key.2), self.region_depth_, &mut &bytes[..]
+                ));
+            }
+            self.paged_regions_.remove(&key);
+            try!(self.free_slot(offset, len));
+        }
+        self.last_accesses_.insert(key, self.time_);
+        Ok(())
+    }
+    /// Moves a region to the region file and replaces it by a paged leaf. Returns false, if the
+    /// region doesn't contain nodes.
+    fn page_out(&mut self, key: RegionKey) -> io::Result<bool> {
+        let pos = na::Vector3::new(key.0, key.1, key.2);
+        let mut bytes = Vec::new();
+        if !try!(self.tree_.write_node_to(pos, self.region_depth_, &mut bytes)) {
+            return Ok(false);
+        }
+        let offset = self.alloc_slot(bytes.len() as u64);
+        try!(self.file_.seek(SeekFrom::Start(offset)));
+        try!(self.file_.write_all(&bytes));
+        let num_resident_nodes = self.num_resident_nodes();
+        unsafe {
+            self.tree_.replace_node_by_leaf(pos, self.region_depth_, Leaf::new(Child::paged()));
+        }
+        let num_nodes = num_resident_nodes - self.num_resident_nodes();
+        self.paged_regions_.insert(key, (offset, bytes.len() as u64, num_nodes));
+        Ok(true)
+    }
+    /// Get the offset of space for a number of bytes within the region file. The smallest unused
+    /// slot, which is large enough, is taken, otherwise the file grows.
+    fn alloc_slot(&mut self, len: u64) -> u64 {
+        let mut best: Option<(u64, u64)> = None;
+        for (&offset, &slot_len) in self.free_slots_.iter() {
+            if slot_len >= len && best.map_or(true, |(_, best_len)| slot_len < best_len) {
+                best = Some((offset, slot_len));
+            }
+        }
+        match best {
+            Some((offset, slot_len)) => {
+                self.free_slots_.remove(&offset);
+                if slot_len > len {
+                    self.free_slots_.insert(offset + len, slot_len - len);
+                }
+                offset
+            },
+            None => {
+                let offset = self.file_len_;
+                self.file_len_ += len;
+                offset
+            },
+        }
+    }
+    /// Marks space within the region file as unused, merges it with adjacent unused slots and
+    /// truncates the file, if the space is at its end.
+    fn free_slot(&mut self, mut offset: u64, mut len: u64) -> io::Result<()> {
+        let prev = self.free_slots_.range(..offset).next_back().map(|(&offset, &len)| (offset, len));
+        if let Some((prev_offset, prev_len)) = prev {
+            if prev_offset + prev_len == offset {
+                self.free_slots_.remove(&prev_offset);
+                offset = prev_offset;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free_slots_.remove(&(offset + len)) {
+            len += next_len;
+        }
+        if offset + len == self.file_len_ {
+            self.file_len_ = offset;
+            try!(self.file_.set_len(offset));
+        } else {
+            self.free_slots_.insert(offset, len);
+        }
+        Ok(())
+    }
+    /// Moves the least recently accessed regions to the region file, until the number of nodes in
+    /// use fits into the budget. Regions accessed by the current operation are kept.
+    fn page_out_cold_regions(&mut self) -> io::Result<()> {
+        try!(self.make_room(0));
+        Ok(())
+    }
+    /// Moves the least recently accessed regions to the region file, until a number of nodes can
+    /// be added to the nodes in use without exceeding the budget. Regions accessed by the current
+    /// operation are kept. Returns false, if there is not enough room left.
+    fn make_room(&mut self, num_nodes: u32) -> io::Result<bool> {
+        while self.num_resident_nodes().saturating_add(num_nodes) > self.max_resident_nodes_ {
+            let mut coldest: Option<(RegionKey, u64)> = None;
+            for (&key, &time) in self.last_accesses_.iter() {
+                if time < self.time_ && coldest.map_or(true, |(_, coldest_time)| time < coldest_time) {
+                    coldest = Some((key, time));
+                }
+            }
+            let key = match coldest {
+                Some((key, _)) => key,
+                None => return Ok(false),
+            };
+            self.last_accesses_.remove(&key);
+            try!(self.page_out(key));
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra as na;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use boxtree::{Tree, Leaf, NoCompression, DictionaryCompression};
+    use super::PagedTree;
+
+    fn region_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("paging-{}-{}.regions", name, ::std::process::id()))
+    }
+
+    /// Boxes at the center of the first eight regions of depth 1 along the z axis.
+    fn column_of_regions() -> Tree<NoCompression> {
+        let mut tree = Tree::new(3, 100, NoCompression);
+        for z in 0..8 {
+            assert!(tree.set_at_pos(na::Vector3::new(8, 8, 16 * z + 8), Leaf::from_solid_box_spec(true, 1 + z as u16)));
+        }
+        tree
+    }
+
+    #[test]
+    fn page_out_and_reload() {
+        let path = region_path("reload");
+        let mut tree = PagedTree::new(Tree::new(3, 1000, DictionaryCompression::new()), 1, 20, &path).unwrap();
+        let mut boxes = HashMap::new();
+        let mut seed = 7u64;
+        let mut random = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as u32
+        };
+        for _ in 0..3000 {
+            let pos = na::Vector3::new(random() % 128, random() % 128, random() % 128);
+            let spec = random() % 4;
+            let leaf = if spec == 0 { Leaf::void() } else { Leaf::from_solid_box_spec(spec != 3, spec as u16) };
+            assert_eq!(tree.set_at_pos(pos, leaf).unwrap(), true);
+            boxes.insert((pos.x, pos.y, pos.z), leaf.ext_spec());
+            assert!(tree.num_resident_nodes() <= 20);
+        }
+        assert!(tree.num_paged_regions() > 0);
+        assert!(tree.tree().check().is_empty());
+        for (&(x, y, z), &ext_spec) in boxes.iter() {
+            assert_eq!(tree.get_at_pos(na::Vector3::new(x, y, z)).unwrap().ext_spec(), ext_spec, "{:?}", (x, y, z));
+            assert!(tree.num_resident_nodes() <= 20);
+        }
+        // Only the root and the node above the regions stay.
+        tree.page_out_all().unwrap();
+        assert_eq!(tree.num_resident_nodes(), 2);
+        assert!(tree.tree().check().is_empty());
+        tree.set_max_resident_nodes(1000);
+        for (&(x, y, z), &ext_spec) in boxes.iter() {
+            assert_eq!(tree.get_at_pos(na::Vector3::new(x, y, z)).unwrap().ext_spec(), ext_spec, "{:?}", (x, y, z));
+        }
+        assert_eq!(tree.num_paged_regions(), 0);
+        assert!(tree.tree().check().is_empty());
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cast_view_closest_first() {
+        let path = region_path("view");
+        let mut tree = PagedTree::new(column_of_regions(), 1, 100, &path).unwrap();
+        tree.page_out_all().unwrap();
+        assert_eq!(tree.num_paged_regions(), 8);
+        // Room for the root, the node above the regions and three regions.
+        tree.set_max_resident_nodes(5);
+        let planes = [
+            na::Vector3::new(1.0, 0.0, 1.0), na::Vector3::new(-1.0, 0.0, 1.0),
+            na::Vector3::new(0.0, 1.0, 1.0), na::Vector3::new(0.0, -1.0, 1.0),
+        ];
+        let mut seen = Vec::new();
+        tree.cast_view(na::Point3::new(8.5, 8.5, 0.5), planes, 1000.0, &mut |pos, _| seen.push(pos.z)).unwrap();
+        seen.sort();
+        assert_eq!(seen, vec![8, 24, 40]);
+        assert_eq!(tree.num_resident_nodes(), 5);
+        assert!(tree.tree().check().is_empty());
+        // Regions behind the view are moved out again to make room for the ones in front of it.
+        let mut seen = Vec::new();
+        tree.cast_view(na::Point3::new(8.5, 8.5, 100.5), planes, 1000.0, &mut |pos, _| seen.push(pos.z)).unwrap();
+        seen.sort();
+        assert_eq!(seen, vec![104, 120]);
+        assert!(tree.paged_regions_.contains_key(&(0, 0, 0)));
+        assert!(!tree.paged_regions_.contains_key(&(0, 0, 112)));
+        assert!(tree.tree().check().is_empty());
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn free_slots_reused() {
+        let path = region_path("slots");
+        let mut tree = PagedTree::new(column_of_regions(), 1, 100, &path).unwrap();
+        tree.page_out_all().unwrap();
+        let file_len = tree.file_len_;
+        assert_eq!(fs::metadata(&path).unwrap().len(), file_len);
+        assert!(tree.free_slots_.is_empty());
+        // Reading back the region at the start of the file leaves a hole.
+        let (&first, _) = tree.paged_regions_.iter().find(|&(_, &(offset, _, _))| offset == 0).unwrap();
+        let first_pos = na::Vector3::new(first.0 + 8, first.1 + 8, first.2 + 8);
+        assert!(tree.get_at_pos(first_pos).unwrap().is_solid());
+        assert_eq!(tree.free_slots_.len(), 1);
+        assert_eq!(tree.file_len_, file_len);
+        // Moving it out again fills the hole instead of growing the file.
+        tree.page_out_all().unwrap();
+        assert!(tree.free_slots_.is_empty());
+        assert_eq!(tree.file_len_, file_len);
+        assert_eq!(fs::metadata(&path).unwrap().len(), file_len);
+        // Reading back the region at the end of the file truncates it.
+        let (&last, &(offset, _, _)) = tree.paged_regions_.iter().max_by_key(|&(_, &(offset, _, _))| offset).unwrap();
+        let last_pos = na::Vector3::new(last.0 + 8, last.1 + 8, last.2 + 8);
+        assert!(tree.get_at_pos(last_pos).unwrap().is_solid());
+        assert!(tree.free_slots_.is_empty());
+        assert_eq!(tree.file_len_, offset);
+        assert_eq!(fs::metadata(&path).unwrap().len(), offset);
+        assert!(tree.tree().check().is_empty());
+        drop(tree);
+        fs::remove_file(&path).unwrap();
+    }
+}
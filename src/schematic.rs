@@ -0,0 +1,303 @@
+use nalgebra as na;
+use boxtree::{Tree, Compression, Leaf, Child, OutOfNodes, CHUNK_SIDE_LEN_LOG2};
+
+/// Axis of the world.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+impl Axis {
+    fn index(&self) -> usize {
+        match *self {
+            Axis::X => 0,
+            Axis::Y => 1,
+            Axis::Z => 2,
+        }
+    }
+}
+
+/// A copy of the boxes within an axis aligned box region of a tree, which is independent of the
+/// tree and may be pasted into any tree. Only the box specifiers and the solidity of the boxes are
+/// kept, the surface flags are recalculated when pasting.
+#[derive(Clone)]
+pub struct Schematic {
+    size_: na::Vector3<u32>,
+    /// Extended box specifiers of the boxes in x, y, z order.
+    ext_specs_: Vec<u16>,
+}
+impl Schematic {
+    /// Creates a schematic of a specific size made of void boxes.
+    pub fn new(size: na::Vector3<u32>) -> Self {
+        Schematic {
+            size_: size,
+            ext_specs_: vec![0; (size.x as usize) * (size.y as usize) * (size.z as usize)],
+        }
+    }
+    /// Copies the boxes within an axis aligned box region from min to max inclusive of a tree.
+    pub fn from_tree<C: Compression>(tree: &Tree<C>, min: na::Vector3<u32>, max: na::Vector3<u32>) -> Self {
+        if min.x > max.x || min.y > max.y || min.z > max.z {
+            return Schematic::new(na::Vector3::new(0, 0, 0));
+        }
+        let mut schematic = Schematic::new(na::Vector3::new(
+            max.x - min.x + 1,
+            max.y - min.y + 1,
+            max.z - min.z + 1,
+        ));
+        for (pos, depth, leaf) in tree.iter_region(min, max, false) {
+            let side_len = 1u32 << (depth * CHUNK_SIDE_LEN_LOG2);
+            for z in 0..side_len {
+                for y in 0..side_len {
+                    for x in 0..side_len {
+                        let index = schematic.index(na::Vector3::new(
+                            pos.x - min.x + x,
+                            pos.y - min.y + y,
+                            pos.z - min.z + z,
+                        ));
+                        schematic.ext_specs_[index] = leaf.ext_spec();
+                    }
+                }
+            }
+        }
+        schematic
+    }
+    pub fn size(&self) -> &na::Vector3<u32> {
+        &self.size_
+    }
+    fn index(&self, pos: na::Vector3<u32>) -> usize {
+        if pos.x >= self.size_.x || pos.y >= self.size_.y || pos.z >= self.size_.z {
+            panic!("position {:?} is outside of the schematic of size {:?}", pos, self.size_);
+        }
+        (pos.x as usize) +
+        (self.size_.x as usize) * ((pos.y as usize) + (self.size_.y as usize) * (pos.z as usize))
+    }
+    /// Get the type of a box at a specific position within the schematic.
+    pub fn get(&self, pos: na::Vector3<u32>) -> Leaf {
+        Leaf::from_ext_spec(self.ext_specs_[self.index(pos)])
+    }
+    /// Sets the type of a box at a specific position within the schematic.
+    pub fn set(&mut self, pos: na::Vector3<u32>, leaf: Leaf) {
+        let index = self.index(pos);
+        self.ext_specs_[index] = leaf.ext_spec();
+    }
+    /// Get the schematic rotated by a number of quarter turns around an axis. A quarter turn around
+    /// the z axis turns the x axis into the y axis, around the x axis it turns the y axis into the
+    /// z axis and around the y axis it turns the z axis into the x axis.
+    pub fn rotated(&self, axis: Axis, quarter_turns: u32) -> Schematic {
+        let (a, b) = match axis {
+            Axis::X => (1, 2),
+            Axis::Y => (2, 0),
+            Axis::Z => (0, 1),
+        };
+        let mut result = self.clone();
+        for _ in 0..(quarter_turns % 4) {
+            let mut size = result.size_;
+            size[a] = result.size_[b];
+            size[b] = result.size_[a];
+            let mut rotated = Schematic::new(size);
+            for z in 0..result.size_.z {
+                for y in 0..result.size_.y {
+                    for x in 0..result.size_.x {
+                        let pos = na::Vector3::new(x, y, z);
+                        let mut rotated_pos = pos;
+                        rotated_pos[a] = result.size_[b] - 1 - pos[b];
+                        rotated_pos[b] = pos[a];
+                        let index = rotated.index(rotated_pos);
+                        rotated.ext_specs_[index] = result.ext_specs_[result.index(pos)];
+                    }
+                }
+            }
+            result = rotated;
+        }
+        result
+    }
+    /// Get the schematic mirrored along an axis.
+    pub fn mirrored(&self, axis: Axis) -> Schematic {
+        let axis = axis.index();
+        let mut mirrored = Schematic::new(self.size_);
+        for z in 0..self.size_.z {
+            for y in 0..self.size_.y {
+                for x in 0..self.size_.x {
+                    let pos = na::Vector3::new(x, y, z);
+                    let mut mirrored_pos = pos;
+                    mirrored_pos[axis] = self.size_[axis] - 1 - pos[axis];
+                    let index = mirrored.index(mirrored_pos);
+                    mirrored.ext_specs_[index] = self.ext_specs_[self.index(pos)];
+                }
+            }
+        }
+        mirrored
+    }
+    /// Pastes the schematic into a tree, so that its first box is at a specific position. Void
+    /// boxes of the schematic only remove the boxes of the tree, if overwrite_void is set. Boxes
+    /// outside of the world are left out. Rows of equal boxes are filled at once. Either the whole
+    /// schematic is pasted or the tree is left unchanged, if there may not be enough free nodes
+    /// available (see Tree::max_nodes_needed).
+    pub fn paste_into<C: Compression>(&self, tree: &mut Tree<C>, offset: na::Vector3<u32>, overwrite_void: bool) -> Result<(), OutOfNodes> {
+        let world_max = (1u64 << (*tree.depth() * CHUNK_SIDE_LEN_LOG2)) - 1;
+        let mut size = self.size_;
+        for axis in 0..3 {
+            if offset[axis] as u64 > world_max {
+                return Ok(());
+            }
+            size[axis] = size[axis].min((world_max - offset[axis] as u64 + 1) as u32);
+        }
+        if size.x == 0 || size.y == 0 || size.z == 0 {
+            return Ok(());
+        }
+        let max = na::Vector3::new(offset.x + size.x - 1, offset.y + size.y - 1, offset.z + size.z - 1);
+        if tree.max_nodes_needed(offset, max) > *tree.num_free_nodes() {
+            return Err(OutOfNodes);
+        }
+        let void = Child::void().ext_spec();
+        for z in 0..size.z {
+            for y in 0..size.y {
+                let mut x = 0;
+                while x < size.x {
+                    let ext_spec = self.ext_specs_[self.index(na::Vector3::new(x, y, z))];
+                    let mut len = 1;
+                    while x + len < size.x && self.ext_specs_[self.index(na::Vector3::new(x + len, y, z))] == ext_spec {
+                        len += 1;
+                    }
+                    if ext_spec != void || overwrite_void {
+                        // The nodes needed have been counted above, so this only fails, if the
+                        // count is off.
+                        try!(tree.fill_region(
+                            na::Vector3::new(offset.x + x, offset.y + y, offset.z + z),
+                            na::Vector3::new(offset.x + x + len - 1, offset.y + y, offset.z + z),
+                            Leaf::from_ext_spec(ext_spec)
+                        ));
+                    }
+                    x += len;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra as na;
+    use boxtree::{Tree, Leaf, NoCompression, DictionaryCompression, OutOfNodes};
+    use super::{Schematic, Axis};
+
+    /// Schematic of an L-shaped structure made of a row of solid boxes along the x axis and a
+    /// column of transparent boxes along the y axis.
+    fn l_shape() -> Schematic {
+        let mut schematic = Schematic::new(na::Vector3::new(5, 3, 1));
+        for x in 0..5 {
+            schematic.set(na::Vector3::new(x, 0, 0), Leaf::from_solid_box_spec(true, 1 + x as u16));
+        }
+        for y in 1..3 {
+            schematic.set(na::Vector3::new(0, y, 0), Leaf::from_solid_box_spec(false, 7));
+        }
+        schematic
+    }
+
+    #[test]
+    fn from_tree() {
+        let mut tree = Tree::new(3, 100, NoCompression);
+        l_shape().paste_into(&mut tree, na::Vector3::new(10, 10, 10), false).unwrap();
+        let schematic = Schematic::from_tree(&tree, na::Vector3::new(10, 10, 10), na::Vector3::new(14, 12, 10));
+        assert_eq!(*schematic.size(), na::Vector3::new(5, 3, 1));
+        assert_eq!(schematic.get(na::Vector3::new(4, 0, 0)).box_spec(), 5);
+        assert!(schematic.get(na::Vector3::new(4, 2, 0)).is_void());
+        assert!(!schematic.get(na::Vector3::new(0, 2, 0)).is_solid());
+        // Sub-cubes made of identical boxes are copied box by box.
+        assert!(tree.fill_region(na::Vector3::new(256, 0, 0), na::Vector3::new(511, 255, 255), Leaf::from_solid_box_spec(true, 3)).is_ok());
+        let schematic = Schematic::from_tree(&tree, na::Vector3::new(250, 0, 0), na::Vector3::new(290, 20, 20));
+        assert!(schematic.get(na::Vector3::new(5, 3, 3)).is_void());
+        assert_eq!(schematic.get(na::Vector3::new(6, 3, 3)).box_spec(), 3);
+        assert_eq!(schematic.get(na::Vector3::new(40, 20, 20)).box_spec(), 3);
+    }
+
+    #[test]
+    fn rotated() {
+        let schematic = l_shape();
+        let rotated = schematic.rotated(Axis::Z, 1);
+        assert_eq!(*rotated.size(), na::Vector3::new(3, 5, 1));
+        assert_eq!(rotated.get(na::Vector3::new(2, 4, 0)).box_spec(), 5);
+        assert_eq!(rotated.get(na::Vector3::new(0, 0, 0)).box_spec(), 7);
+        let rotated = schematic.rotated(Axis::X, 1);
+        assert_eq!(*rotated.size(), na::Vector3::new(5, 1, 3));
+        assert_eq!(rotated.get(na::Vector3::new(4, 0, 0)).box_spec(), 5);
+        assert_eq!(rotated.get(na::Vector3::new(0, 0, 2)).box_spec(), 7);
+        let rotated = schematic.rotated(Axis::Y, 1);
+        assert_eq!(*rotated.size(), na::Vector3::new(1, 3, 5));
+        assert_eq!(rotated.get(na::Vector3::new(0, 0, 0)).box_spec(), 5);
+        // Four quarter turns and two half turns are no turn at all.
+        for &axis in [Axis::X, Axis::Y, Axis::Z].iter() {
+            let turned = schematic.rotated(axis, 4);
+            let half_turned = schematic.rotated(axis, 2).rotated(axis, 2);
+            for y in 0..3 {
+                for x in 0..5 {
+                    let pos = na::Vector3::new(x, y, 0);
+                    assert_eq!(turned.get(pos).ext_spec(), schematic.get(pos).ext_spec());
+                    assert_eq!(half_turned.get(pos).ext_spec(), schematic.get(pos).ext_spec());
+                }
+            }
+        }
+        let mirrored = schematic.mirrored(Axis::X);
+        assert_eq!(mirrored.get(na::Vector3::new(0, 0, 0)).box_spec(), 5);
+        assert_eq!(mirrored.get(na::Vector3::new(4, 2, 0)).box_spec(), 7);
+    }
+
+    #[test]
+    fn paste_into() {
+        let schematic = l_shape();
+        let mut tree = Tree::new(3, 100, NoCompression);
+        assert!(tree.set_at_pos(na::Vector3::new(104, 102, 100), Leaf::from_solid_box_spec(true, 9)));
+        schematic.paste_into(&mut tree, na::Vector3::new(100, 100, 100), false).unwrap();
+        assert_eq!(tree.get_at_pos(na::Vector3::new(104, 102, 100)).box_spec(), 9);
+        assert_eq!(tree.get_at_pos(na::Vector3::new(103, 100, 100)).box_spec(), 4);
+        assert!(tree.get_at_pos(na::Vector3::new(100, 100, 100)).is_surface());
+        schematic.paste_into(&mut tree, na::Vector3::new(100, 100, 100), true).unwrap();
+        assert!(tree.get_at_pos(na::Vector3::new(104, 102, 100)).is_void());
+        // Boxes outside of the world are left out.
+        schematic.paste_into(&mut tree, na::Vector3::new(4093, 0, 0), true).unwrap();
+        assert_eq!(tree.get_at_pos(na::Vector3::new(4095, 0, 0)).box_spec(), 3);
+        assert_eq!(tree.get_at_pos(na::Vector3::new(0, 0, 0)).ext_spec(), 0);
+        assert!(tree.check().is_empty());
+    }
+
+    #[test]
+    fn paste_into_out_of_nodes() {
+        let mut schematic = Schematic::new(na::Vector3::new(40, 20, 20));
+        for z in 0..20 {
+            for y in 0..20 {
+                for x in 0..40 {
+                    let spec = (x * 7 + y * 3 + z) % 4;
+                    let leaf = if spec == 0 { Leaf::void() } else { Leaf::from_solid_box_spec(spec != 1, spec as u16) };
+                    schematic.set(na::Vector3::new(x, y, z), leaf);
+                }
+            }
+        }
+        let mut num_pasted = 0;
+        for &max_nodes in [6, 12, 24, 80].iter() {
+            let mut tree = Tree::new(3, max_nodes, DictionaryCompression::new());
+            assert!(tree.fill_region(na::Vector3::new(0, 0, 0), na::Vector3::new(255, 47, 255), Leaf::from_solid_box_spec(true, 2)).is_ok());
+            let offset = na::Vector3::new(10, 10, 0);
+            let max = na::Vector3::new(49, 29, 19);
+            let num_nodes_needed = tree.max_nodes_needed(offset, max);
+            let before: Vec<u16> = tree.iter_region(offset, max, true).map(|(_, _, leaf)| leaf.ext_spec()).collect();
+            match schematic.paste_into(&mut tree, offset, true) {
+                Ok(()) => {
+                    num_pasted += 1;
+                    for (pos, _, leaf) in tree.iter_region(offset, max, true) {
+                        assert_eq!(leaf.ext_spec(), schematic.get(pos - offset).ext_spec());
+                    }
+                },
+                Err(OutOfNodes) => {
+                    // Either the whole schematic is pasted or nothing at all.
+                    assert!(num_nodes_needed > *tree.num_free_nodes());
+                    let after: Vec<u16> = tree.iter_region(offset, max, true).map(|(_, _, leaf)| leaf.ext_spec()).collect();
+                    assert!(after == before);
+                },
+            }
+            assert!(tree.check().is_empty());
+        }
+        assert!(num_pasted > 0 && num_pasted < 4);
+    }
+}